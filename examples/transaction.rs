@@ -14,61 +14,53 @@
 mod common;
 
 use crate::common::parse_args;
-use futures::{future, Future, Stream};
+use futures::{executor::block_on, future, prelude::*};
 use std::ops::RangeBounds;
 use tikv_client::{
     transaction::{Client, IsolationLevel},
     Config, Key, KvPair, Value,
 };
 
-fn puts(client: &Client, pairs: impl IntoIterator<Item = impl Into<KvPair>>) {
-    let mut txn = client.begin();
-    let _: Vec<()> = future::join_all(
+async fn puts(client: &Client, pairs: impl IntoIterator<Item = impl Into<KvPair>>) {
+    let mut txn = client.begin().await.expect("Could not begin a transaction");
+    let _: Vec<()> = future::try_join_all(
         pairs
             .into_iter()
             .map(Into::into)
             .map(|p| txn.set(p.key().clone(), p.value().clone())),
     )
-    .wait()
+    .await
     .expect("Could not set key value pairs");
-    txn.commit().wait().expect("Could not commit transaction");
+    txn.commit().await.expect("Could not commit transaction");
 }
 
-fn get(client: &Client, key: Key) -> Value {
-    let txn = client.begin();
-    txn.get(key).wait().expect("Could not get value")
+async fn get(client: &Client, key: Key) -> Option<Value> {
+    let txn = client.begin().await.expect("Could not begin a transaction");
+    txn.get(key).await.expect("Could not get value")
 }
 
-fn scan(client: &Client, range: impl RangeBounds<Key>, mut limit: usize) {
+async fn scan(client: &Client, range: impl RangeBounds<Key>, limit: usize) {
     client
         .begin()
+        .await
+        .expect("Could not begin a transaction")
         .scan(range)
-        .take_while(move |_| {
-            Ok(if limit == 0 {
-                false
-            } else {
-                limit -= 1;
-                true
-            })
-        })
-        .for_each(|pair| {
+        .take(limit)
+        .try_for_each(|pair| {
             println!("{:?}", pair);
-            Ok(())
+            future::ok(())
         })
-        .wait()
+        .await
         .expect("Could not scan keys");
 }
 
-fn dels(client: &Client, keys: impl IntoIterator<Item = Key>) {
-    let mut txn = client.begin();
+async fn dels(client: &Client, keys: impl IntoIterator<Item = Key>) {
+    let mut txn = client.begin().await.expect("Could not begin a transaction");
     txn.set_isolation_level(IsolationLevel::ReadCommitted);
-    let _: Vec<()> = keys
-        .into_iter()
-        .map(|p| {
-            txn.delete(p).wait().expect("Could not delete key");
-        })
-        .collect();
-    txn.commit().wait().expect("Could not commit transaction");
+    for key in keys {
+        txn.delete(key).await.expect("Could not delete key");
+    }
+    txn.commit().await.expect("Could not commit transaction");
 }
 
 fn main() {
@@ -84,28 +76,30 @@ fn main() {
         Config::new(args.pd)
     };
 
-    let txn = Client::new(config)
-        .wait()
-        .expect("Could not connect to tikv");
+    block_on(async {
+        let txn = Client::new(config)
+            .await
+            .expect("Could not connect to tikv");
 
-    // set
-    let key1: Key = b"key1".to_vec().into();
-    let value1: Value = b"value1".to_vec().into();
-    let key2: Key = b"key2".to_vec().into();
-    let value2: Value = b"value2".to_vec().into();
-    puts(&txn, vec![(key1, value1), (key2, value2)]);
+        // set
+        let key1: Key = b"key1".to_vec().into();
+        let value1: Value = b"value1".to_vec().into();
+        let key2: Key = b"key2".to_vec().into();
+        let value2: Value = b"value2".to_vec().into();
+        puts(&txn, vec![(key1, value1), (key2, value2)]).await;
 
-    // get
-    let key1: Key = b"key1".to_vec().into();
-    let value1 = get(&txn, key1.clone());
-    println!("{:?}", (key1, value1));
+        // get
+        let key1: Key = b"key1".to_vec().into();
+        let value1 = get(&txn, key1.clone()).await;
+        println!("{:?}", (key1, value1));
 
-    // scan
-    let key1: Key = b"key1".to_vec().into();
-    scan(&txn, key1.., 10);
+        // scan
+        let key1: Key = b"key1".to_vec().into();
+        scan(&txn, key1.., 10).await;
 
-    // delete
-    let key1: Key = b"key1".to_vec().into();
-    let key2: Key = b"key2".to_vec().into();
-    dels(&txn, vec![key1, key2]);
+        // delete
+        let key1: Key = b"key1".to_vec().into();
+        let key2: Key = b"key2".to_vec().into();
+        dels(&txn, vec![key1, key2]).await;
+    });
 }
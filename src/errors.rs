@@ -31,7 +31,7 @@ pub enum ErrorKind {
     Grpc(#[fail(cause)] grpcio::Error),
     /// Represents that a futures oneshot channel was cancelled.
     #[fail(display = "A futures oneshot channel was canceled. {}", _0)]
-    Canceled(#[fail(cause)] futures::sync::oneshot::Canceled),
+    Canceled(#[fail(cause)] futures::channel::oneshot::Canceled),
     /// Feature is not implemented.
     #[fail(display = "Unimplemented feature")]
     Unimplemented,
@@ -40,7 +40,12 @@ pub enum ErrorKind {
     RegionForKeyNotFound { key: Vec<u8> },
     /// The peer is not the leader for the region.
     #[fail(display = "Peer is not leader for region {}. {}", region_id, message)]
-    NotLeader { region_id: u64, message: String },
+    NotLeader {
+        region_id: u64,
+        /// The peer TiKV suggests is the new leader, if it knows one.
+        leader: Option<kvproto::metapb::Peer>,
+        message: String,
+    },
     /// Stale epoch
     #[fail(display = "Stale epoch. {}", message)]
     StaleEpoch { message: String },
@@ -56,9 +61,28 @@ pub enum ErrorKind {
     /// Scan limit exceeds the maximum
     #[fail(display = "Limit {} exceeds max scan limit {}", limit, max_limit)]
     MaxScanLimitExceeded { limit: u32, max_limit: u32 },
+    /// A scan must fetch at least one pair per request.
+    #[fail(display = "Scan batch size must not be zero")]
+    ZeroScanBatchSize,
     /// Wraps `kvproto::kvrpcpb::KeyError`
     #[fail(display = "{:?}", _0)]
     KeyError(kvproto::kvrpcpb::KeyError),
+    /// Waiting for a pessimistic lock would deadlock. The transaction should be rolled back.
+    #[fail(
+        display = "Deadlock waiting for the lock on key {:?} held by transaction {}",
+        lock_key, lock_ts
+    )]
+    Deadlock {
+        lock_ts: u64,
+        lock_key: Vec<u8>,
+        deadlock_key_hash: u64,
+    },
+    /// A pessimistic lock was not released within the lock wait timeout.
+    #[fail(
+        display = "Timed out waiting for the lock on key {:?} held by transaction {}",
+        key, lock_version
+    )]
+    LockWaitTimeout { key: Vec<u8>, lock_version: u64 },
     /// A string error returned by TiKV server
     #[fail(display = "Kv error. {}", message)]
     KvError { message: String },
@@ -132,9 +156,14 @@ impl Error {
         Error::from(ErrorKind::RegionForKeyNotFound { key })
     }
 
-    pub(crate) fn not_leader(region_id: u64, message: Option<String>) -> Self {
+    pub(crate) fn not_leader(
+        region_id: u64,
+        leader: Option<kvproto::metapb::Peer>,
+        message: Option<String>,
+    ) -> Self {
         Error::from(ErrorKind::NotLeader {
             region_id,
+            leader,
             message: message.unwrap_or_default(),
         })
     }
@@ -164,6 +193,25 @@ impl Error {
         Error::from(ErrorKind::MaxScanLimitExceeded { limit, max_limit })
     }
 
+    pub(crate) fn zero_scan_batch_size() -> Self {
+        Error::from(ErrorKind::ZeroScanBatchSize)
+    }
+
+    pub(crate) fn deadlock(mut e: kvproto::kvrpcpb::Deadlock) -> Self {
+        Error::from(ErrorKind::Deadlock {
+            lock_ts: e.get_lock_ts(),
+            lock_key: e.take_lock_key(),
+            deadlock_key_hash: e.get_deadlock_key_hash(),
+        })
+    }
+
+    pub(crate) fn lock_wait_timeout(mut lock: kvproto::kvrpcpb::LockInfo) -> Self {
+        Error::from(ErrorKind::LockWaitTimeout {
+            key: lock.take_key(),
+            lock_version: lock.get_lock_version(),
+        })
+    }
+
     pub(crate) fn kv_error(message: String) -> Self {
         Error::from(ErrorKind::KvError { message })
     }
@@ -210,6 +258,35 @@ impl Error {
     pub(crate) fn internal_error(message: String) -> Self {
         Error::from(ErrorKind::InternalError { message })
     }
+
+    /// Whether the request failed because the server could not be reached in time, rather than
+    /// being answered with an error.
+    pub(crate) fn is_unreachable(&self) -> bool {
+        self.is_transport_failure() || self.is_deadline_exceeded()
+    }
+
+    /// Whether the connection to the server failed. The server may still have received and
+    /// applied the request.
+    pub(crate) fn is_transport_failure(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Grpc(grpcio::Error::RpcFailure(status)) => {
+                status.status == grpcio::RpcStatusCode::Unavailable
+            }
+            ErrorKind::Grpc(grpcio::Error::RemoteStopped) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the request got no answer before its deadline. The server may still have
+    /// received and applied the request.
+    pub(crate) fn is_deadline_exceeded(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Grpc(grpcio::Error::RpcFailure(status)) => {
+                status.status == grpcio::RpcStatusCode::DeadlineExceeded
+            }
+            _ => false,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -238,15 +315,19 @@ impl From<grpcio::Error> for Error {
     }
 }
 
-impl From<futures::sync::oneshot::Canceled> for Error {
-    fn from(err: futures::sync::oneshot::Canceled) -> Self {
+impl From<futures::channel::oneshot::Canceled> for Error {
+    fn from(err: futures::channel::oneshot::Canceled) -> Self {
         Error::from(ErrorKind::Canceled(err))
     }
 }
 
 impl From<kvproto::kvrpcpb::KeyError> for Error {
-    fn from(err: kvproto::kvrpcpb::KeyError) -> Self {
-        Error::from(ErrorKind::KeyError(err))
+    fn from(mut err: kvproto::kvrpcpb::KeyError) -> Self {
+        if err.has_deadlock() {
+            Error::deadlock(err.take_deadlock())
+        } else {
+            Error::from(ErrorKind::KeyError(err))
+        }
     }
 }
 
@@ -42,7 +42,7 @@
 //! [dependencies]
 //! # ...Your other dependencies...
 //! tikv-client = "~0.1"
-//! futures = "0.1" # You'll need this later.
+//! futures = "0.3" # You'll need this later.
 //! ```
 //!
 //! Then run a `cargo build --package tikv-client` command to test building the crate.
@@ -93,7 +93,7 @@
 //!
 //! ```rust
 //! # use tikv_client::{*, raw::*};
-//! use futures::Future;
+//! use futures::executor::block_on;
 //!
 //! // Configure endpoints and optional TLS.
 //! let config = Config::new(vec![ // A list of PD endpoints.
@@ -104,30 +104,39 @@
 //! // Get an unresolved connection.
 //! let connect = Client::new(config);
 //!
-//! // Resolve the connection into a client.
-//! let client = connect.wait();
+//! // Resolve the connection into a client. Any executor works, `.await` it from async code.
+//! let client = block_on(connect);
 //! ```
 //!
+//! Code which does not use an async runtime at all can use the blocking
+//! [raw](raw/struct.SyncClient.html) and [transactional](transaction/struct.SyncClient.html)
+//! clients instead, which drive their requests on a runtime of their own.
+//!
 //! At this point, you should seek the documentation in the related API modules.
 //!
 
 use futures::Future;
+use rand::Rng;
 use serde_derive::*;
 use std::{
-    fmt,
+    cmp, fmt,
     ops::{
         Bound, Deref, DerefMut, Range, RangeFrom, RangeFull, RangeInclusive, RangeTo,
         RangeToInclusive,
     },
     path::PathBuf,
+    pin::Pin,
     str,
     time::Duration,
     u8::{MAX as U8_MAX, MIN as U8_MIN},
 };
 
 mod errors;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod raw;
 mod rpc;
+mod runtime;
 pub mod transaction;
 
 #[doc(inline)]
@@ -476,7 +485,7 @@ impl fmt::Debug for KvPair {
 /// parameters.
 ///
 /// TiKV does not currently offer encrypted storage (or encryption-at-rest).
-#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
@@ -485,9 +494,39 @@ pub struct Config {
     cert_path: Option<PathBuf>,
     key_path: Option<PathBuf>,
     timeout: Duration,
+    backoff: Backoff,
+    batch_concurrency: usize,
+    max_batch_keys: usize,
+    max_batch_bytes: usize,
+    tso_prefetch: u32,
+    lock_wait_timeout: Duration,
 }
 
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_BATCH_CONCURRENCY: usize = 16;
+const DEFAULT_MAX_BATCH_KEYS: usize = 4096;
+const DEFAULT_MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A configuration without PD endpoints, and every other setting at its default. Fields missing
+/// when deserializing take these defaults too.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pd_endpoints: Vec::new(),
+            ca_path: None,
+            cert_path: None,
+            key_path: None,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            backoff: Backoff::default(),
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            max_batch_keys: DEFAULT_MAX_BATCH_KEYS,
+            max_batch_bytes: DEFAULT_MAX_BATCH_BYTES,
+            tso_prefetch: 0,
+            lock_wait_timeout: DEFAULT_LOCK_WAIT_TIMEOUT,
+        }
+    }
+}
 
 impl Config {
     /// Create a new [`Config`](struct.Config.html) which coordinates with the given PD endpoints.
@@ -502,10 +541,7 @@ impl Config {
     pub fn new(pd_endpoints: impl IntoIterator<Item = impl Into<String>>) -> Self {
         Config {
             pd_endpoints: pd_endpoints.into_iter().map(Into::into).collect(),
-            ca_path: None,
-            cert_path: None,
-            key_path: None,
-            timeout: DEFAULT_REQUEST_TIMEOUT,
+            ..Config::default()
         }
     }
 
@@ -536,9 +572,173 @@ impl Config {
         self.timeout = timeout;
         self
     }
+
+    /// Set the [`Backoff`](struct.Backoff.html) policy used to retry requests which failed
+    /// because of a stale region, such as during a leader transfer or a region split.
+    ///
+    /// ```rust
+    /// # use tikv_client::{Backoff, Config};
+    /// # use std::time::Duration;
+    /// let config = Config::new(vec!["192.168.0.100:2379", "192.168.0.101:2379"])
+    ///     .backoff(Backoff::new(Duration::from_millis(2), Duration::from_millis(500)));
+    /// ```
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the maximum number of requests a batch request sends concurrently.
+    ///
+    /// Batch requests like [`raw::Client::batch_get`](raw/struct.Client.html#method.batch_get)
+    /// are split into one request per region, which are sent concurrently up to this limit.
+    ///
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::new(vec!["192.168.0.100:2379", "192.168.0.101:2379"])
+    ///     .batch_concurrency(64);
+    /// ```
+    pub fn batch_concurrency(mut self, batch_concurrency: usize) -> Self {
+        self.batch_concurrency = batch_concurrency;
+        self
+    }
+
+    /// Set the maximum number of keys, and the maximum total size in bytes of the keys and
+    /// values, sent to a region in a single raw batch request.
+    ///
+    /// Larger batches are split into several requests. A request which TiKV still rejects with
+    /// `RaftEntryTooLarge` is split in half and resent.
+    ///
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::new(vec!["192.168.0.100:2379", "192.168.0.101:2379"])
+    ///     .max_batch_size(1024, 1024 * 1024);
+    /// ```
+    pub fn max_batch_size(mut self, max_keys: usize, max_bytes: usize) -> Self {
+        self.max_batch_keys = max_keys;
+        self.max_batch_bytes = max_bytes;
+        self
+    }
+
+    /// Set the number of extra timestamps reserved from PD with each timestamp request.
+    ///
+    /// Reserved timestamps are handed out without asking PD again, which saves a round trip for
+    /// workloads beginning many transactions. They may be slightly older than a timestamp fresh
+    /// from PD, so a transaction could miss another one committed just before it began. Timestamps
+    /// are still strictly increasing across PD leader changes. Disabled by default.
+    ///
+    /// Prefetched timestamps are only ever used as start timestamps. Commit timestamps and the
+    /// `for_update_ts` of pessimistic locks are always requested from PD.
+    ///
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::new(vec!["192.168.0.100:2379", "192.168.0.101:2379"])
+    ///     .tso_prefetch(64);
+    /// ```
+    pub fn tso_prefetch(mut self, tso_prefetch: u32) -> Self {
+        self.tso_prefetch = tso_prefetch;
+        self
+    }
+
+    /// Set how long a pessimistic transaction waits for a lock held by another transaction
+    /// before failing with [`ErrorKind::LockWaitTimeout`](enum.ErrorKind.html).
+    ///
+    /// Pessimistic locks live for 20 seconds after they were acquired, the locks of a crashed
+    /// transaction are only cleaned up by transactions still waiting once that has passed.
+    ///
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// # use std::time::Duration;
+    /// let config = Config::new(vec!["192.168.0.100:2379", "192.168.0.101:2379"])
+    ///     .lock_wait_timeout(Duration::from_secs(10));
+    /// ```
+    pub fn lock_wait_timeout(mut self, lock_wait_timeout: Duration) -> Self {
+        self.lock_wait_timeout = lock_wait_timeout;
+        self
+    }
+}
+
+/// The policy for retrying requests which failed with a region error.
+///
+/// TiKV reports routine events like a leader transfer or a region split as errors on requests
+/// which were sent to the old region. These requests are retried against the new region after an
+/// exponentially growing, jittered delay, until either `max_attempts` requests were sent or the
+/// `deadline` has passed since the first one.
+///
+/// A `ServerIsBusy` error is never retried sooner than the delay suggested by TiKV.
+///
+/// ```rust
+/// # use tikv_client::Backoff;
+/// # use std::time::Duration;
+/// let backoff = Backoff::new(Duration::from_millis(2), Duration::from_millis(500))
+///     .max_attempts(10)
+///     .deadline(Duration::from_secs(20));
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Backoff {
+    base_delay: Duration,
+    max_delay: Duration,
+    max_attempts: u32,
+    deadline: Duration,
+}
+
+const DEFAULT_BACKOFF_BASE_DELAY: Duration = Duration::from_millis(2);
+const DEFAULT_BACKOFF_MAX_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_BACKOFF_MAX_ATTEMPTS: u32 = 10;
+const DEFAULT_BACKOFF_DEADLINE: Duration = Duration::from_secs(20);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(DEFAULT_BACKOFF_BASE_DELAY, DEFAULT_BACKOFF_MAX_DELAY)
+    }
+}
+
+impl Backoff {
+    /// Create an exponential [`Backoff`](struct.Backoff.html) whose delay starts at `base_delay`
+    /// and doubles with every attempt, up to `max_delay`.
+    pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        Backoff {
+            base_delay,
+            max_delay,
+            max_attempts: DEFAULT_BACKOFF_MAX_ATTEMPTS,
+            deadline: DEFAULT_BACKOFF_DEADLINE,
+        }
+    }
+
+    /// A [`Backoff`](struct.Backoff.html) which never retries.
+    pub fn no_retry() -> Self {
+        Backoff::default().max_attempts(1)
+    }
+
+    /// Set the maximum number of requests sent, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the time after the first request past which no more retries are made.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The delay before retrying the request a `attempt + 1`th time.
+    ///
+    /// Half of the exponential delay is fixed, and the other half is random so that clients which
+    /// failed at the same time do not retry in lockstep.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = 1u32
+            .checked_shl(attempt)
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+        let half = delay / 2;
+        let jitter_ms = rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter_ms)
+    }
 }
 
-pub type KvFuture<Resp> = Box<dyn Future<Item = Resp, Error = Error> + Send>;
+pub type KvFuture<Resp> = Pin<Box<dyn Future<Output = Result<Resp>> + Send>>;
 
 /// A convenience trait for expressing ranges.
 ///
@@ -0,0 +1,764 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-process TiKV cluster, for testing code built on this crate without deploying one.
+//!
+//! A [`MockCluster`](struct.MockCluster.html) serves both the PD and the TiKV gRPC services on a
+//! local port and keeps all of its data in memory. The [`raw`](../raw/index.html) and
+//! [`transaction`](../transaction/index.html) clients connect to it like to any other cluster:
+//!
+//! ```rust,no_run
+//! # use tikv_client::{mock::MockCluster, raw::Client};
+//! # use futures::executor::block_on;
+//! let cluster = MockCluster::start().unwrap();
+//! cluster.split("m");
+//! let client = block_on(Client::new(cluster.config())).unwrap();
+//! block_on(client.put("TiKV", "Fast")).unwrap();
+//! ```
+//!
+//! The cluster starts with a single store and a single region covering every key. Regions can be
+//! split to exercise requests which span several of them, and region errors can be injected to
+//! exercise retries. More stores can be added, each holding a peer of every region, to exercise
+//! leader changes and stores going down.
+//!
+//! This module is only available with the `mock` feature.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use grpcio::{EnvBuilder, Environment, Server, ServerBuilder};
+use kvproto::{errorpb, kvrpcpb, metapb, pdpb_grpc, tikvpb_grpc};
+
+use crate::{transaction::Timestamp, Config, Key, Result, Value};
+
+macro_rules! reply {
+    ($ctx:expr, $sink:expr, $resp:expr) => {
+        $ctx.spawn(
+            $sink
+                .success($resp)
+                .map_err(|e| warn!("failed to reply: {:?}", e)),
+        )
+    };
+}
+
+macro_rules! fail_unimplemented {
+    ($ctx:expr, $sink:expr) => {
+        $ctx.spawn(
+            $sink
+                .fail(::grpcio::RpcStatus::new(
+                    ::grpcio::RpcStatusCode::Unimplemented,
+                    None,
+                ))
+                .map_err(|e| warn!("failed to reply: {:?}", e)),
+        )
+    };
+}
+
+macro_rules! fail_unavailable {
+    ($ctx:expr, $sink:expr) => {
+        $ctx.spawn(
+            $sink
+                .fail(::grpcio::RpcStatus::new(
+                    ::grpcio::RpcStatusCode::Unavailable,
+                    Some("down".to_owned()),
+                ))
+                .map_err(|e| warn!("failed to reply: {:?}", e)),
+        )
+    };
+}
+
+/// Implement unary calls the mock does not support by failing them.
+macro_rules! unimplemented_calls {
+    ($($name:ident($req:ty) -> $resp:ty;)*) => {
+        $(
+            fn $name(&mut self, ctx: RpcContext, _: $req, sink: UnarySink<$resp>) {
+                fail_unimplemented!(ctx, sink);
+            }
+        )*
+    };
+}
+
+mod pd;
+mod tikv;
+
+const CLUSTER_ID: u64 = 0x6d6f_636b;
+const STORE_ID: u64 = 1;
+const SERVER_PREFIX: &str = "tikv-mock";
+
+/// A region error which [`MockCluster::inject_error`](struct.MockCluster.html#method.inject_error)
+/// makes the mock TiKV reply with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionError {
+    /// The peer the request was sent to is not the leader of the region. The error suggests the
+    /// actual leader.
+    NotLeader,
+    /// The region is unknown to the store.
+    RegionNotFound,
+    /// The region was split or merged since the client last loaded it.
+    EpochNotMatch,
+    /// The store is too busy to serve the request.
+    ServerIsBusy,
+    /// The request was proposed in a term which is over.
+    StaleCommand,
+    /// The write is too large to be proposed as a single Raft entry.
+    RaftEntryTooLarge,
+}
+
+/// An in-memory PD and TiKV, serving gRPC requests on a local port.
+///
+/// The cluster shuts down when this is dropped.
+pub struct MockCluster {
+    cluster: Arc<RwLock<Cluster>>,
+    addr: String,
+    env: Arc<Environment>,
+    servers: Mutex<Vec<Server>>,
+}
+
+impl MockCluster {
+    /// Start a cluster with a single store and a single region.
+    pub fn start() -> Result<MockCluster> {
+        let env = Arc::new(
+            EnvBuilder::new()
+                .cq_count(1)
+                .name_prefix(SERVER_PREFIX)
+                .build(),
+        );
+        let cluster = Arc::new(RwLock::new(Cluster::new()));
+        let mut server = ServerBuilder::new(Arc::clone(&env))
+            .register_service(pdpb_grpc::create_pd(pd::PdService::new(Arc::clone(
+                &cluster,
+            ))))
+            .register_service(tikvpb_grpc::create_tikv(tikv::TikvService::new(
+                Arc::clone(&cluster),
+                STORE_ID,
+            )))
+            .bind("127.0.0.1", 0)
+            .build()?;
+        server.start();
+        let addr = bound_addr(&server);
+        cluster.write().unwrap().set_addr(STORE_ID, &addr);
+        Ok(MockCluster {
+            cluster,
+            addr,
+            env,
+            servers: Mutex::new(vec![server]),
+        })
+    }
+
+    /// Add a store serving TiKV on a port of its own, with a follower of every region on it.
+    ///
+    /// Returns the ID of the store.
+    pub fn add_store(&self) -> Result<u64> {
+        let id = self.cluster.write().unwrap().add_store();
+        let mut server = ServerBuilder::new(Arc::clone(&self.env))
+            .register_service(tikvpb_grpc::create_tikv(tikv::TikvService::new(
+                Arc::clone(&self.cluster),
+                id,
+            )))
+            .bind("127.0.0.1", 0)
+            .build()?;
+        server.start();
+        self.cluster
+            .write()
+            .unwrap()
+            .set_addr(id, &bound_addr(&server));
+        self.servers.lock().unwrap().push(server);
+        Ok(id)
+    }
+
+    /// The address PD and the first TiKV store are served on.
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// A [`Config`](../struct.Config.html) connecting to this cluster.
+    pub fn config(&self) -> Config {
+        Config::new(vec![self.addr.clone()])
+    }
+
+    /// Split the region containing `key` in two, so that `key` starts the second one.
+    ///
+    /// Returns the ID of the region starting at `key`.
+    pub fn split(&self, key: impl Into<Key>) -> u64 {
+        self.cluster.write().unwrap().split(key.into().as_ref())
+    }
+
+    /// The number of regions the cluster is made of.
+    pub fn region_count(&self) -> usize {
+        self.cluster.read().unwrap().regions.len()
+    }
+
+    /// Make the next request to the region containing `key` fail with `error`.
+    ///
+    /// Errors injected for the same region are replied in the order they were injected, one per
+    /// request.
+    pub fn inject_error(&self, key: impl Into<Key>, error: RegionError) {
+        self.cluster
+            .write()
+            .unwrap()
+            .inject_error(key.into().as_ref(), error);
+    }
+
+    /// The IDs of the stores of the cluster, starting with the one it started with.
+    pub fn store_ids(&self) -> Vec<u64> {
+        self.cluster
+            .read()
+            .unwrap()
+            .stores
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Move the leader of the region containing `key` to its peer on store `store_id`.
+    ///
+    /// Requests sent to the former leader fail with `NotLeader`, which suggests the new one.
+    pub fn transfer_leader(&self, key: impl Into<Key>, store_id: u64) {
+        self.cluster
+            .write()
+            .unwrap()
+            .transfer_leader(key.into().as_ref(), store_id);
+    }
+
+    /// Make store `store_id` fail every request as unavailable while `down` is set, like a
+    /// crashed store.
+    pub fn set_store_down(&self, store_id: u64, down: bool) {
+        self.cluster.write().unwrap().store_mut(store_id).down = down;
+    }
+
+    /// Make store `store_id` reply `delay` after it received a request, to exercise timeouts.
+    pub fn set_store_delay(&self, store_id: u64, delay: Duration) {
+        self.cluster.write().unwrap().store_mut(store_id).delay = delay;
+    }
+
+    /// The number of requests store `store_id` received, including the ones it failed.
+    pub fn request_count(&self, store_id: u64) -> usize {
+        self.cluster.read().unwrap().stores[&store_id].requests
+    }
+
+    /// Elect a new PD leader.
+    ///
+    /// The new leader has another member ID, and timestamp streams opened with the old one fail
+    /// on their next request.
+    pub fn change_pd_leader(&self) {
+        self.cluster.write().unwrap().pd_leader += 1;
+    }
+
+    /// Make PD fail every request as unavailable while `down` is set, like a crashed leader.
+    ///
+    /// Timestamp streams opened before fail on their next request.
+    pub fn set_pd_down(&self, down: bool) {
+        self.cluster.write().unwrap().pd_down = down;
+    }
+
+    /// Prewrite `value` at `key` in a transaction of its own and leave its lock behind, like a
+    /// client which crashed or has not committed yet. The lock expires `ttl` after the transaction
+    /// started.
+    ///
+    /// Returns the start version of the transaction, to
+    /// [`commit`](struct.MockCluster.html#method.commit) it with.
+    pub fn prewrite(&self, key: impl Into<Key>, value: impl Into<Value>, ttl: Duration) -> u64 {
+        let key = key.into().into_inner();
+        let mut mutation = kvrpcpb::Mutation::new();
+        mutation.set_op(kvrpcpb::Op::Put);
+        mutation.set_key(key.clone());
+        mutation.set_value(value.into().into_inner());
+        let ttl = ttl.as_secs() * 1000 + u64::from(ttl.subsec_millis());
+        let mut cluster = self.cluster.write().unwrap();
+        let start_version = cluster.version();
+        let errors = cluster.prewrite(&[mutation], &[], &key, start_version, ttl);
+        assert!(errors.is_empty(), "failed to prewrite: {:?}", errors);
+        start_version
+    }
+
+    /// Commit the transaction [`prewrite`](struct.MockCluster.html#method.prewrite) started at
+    /// `start_version` for `key`.
+    ///
+    /// Returns the commit version, or `None` if the transaction was rolled back in the meantime.
+    pub fn commit(&self, key: impl Into<Key>, start_version: u64) -> Option<u64> {
+        let mut cluster = self.cluster.write().unwrap();
+        let commit_version = cluster.version();
+        match cluster.commit(&[key.into().into_inner()], start_version, commit_version) {
+            Some(_) => None,
+            None => Some(commit_version),
+        }
+    }
+}
+
+fn bound_addr(server: &Server) -> String {
+    let (host, port) = &server.bind_addrs()[0];
+    format!("{}:{}", host, port)
+}
+
+fn region_error(
+    region: &metapb::Region,
+    leader: &metapb::Peer,
+    error: RegionError,
+) -> errorpb::Error {
+    let mut e = errorpb::Error::new();
+    match error {
+        RegionError::NotLeader => {
+            let mut not_leader = errorpb::NotLeader::new();
+            not_leader.set_region_id(region.get_id());
+            not_leader.set_leader(leader.clone());
+            e.set_message("not leader".to_owned());
+            e.set_not_leader(not_leader);
+        }
+        RegionError::RegionNotFound => {
+            let mut not_found = errorpb::RegionNotFound::new();
+            not_found.set_region_id(region.get_id());
+            e.set_message("region not found".to_owned());
+            e.set_region_not_found(not_found);
+        }
+        RegionError::EpochNotMatch => {
+            let mut not_match = errorpb::EpochNotMatch::new();
+            not_match.set_current_regions(vec![region.clone()].into());
+            e.set_message("epoch not match".to_owned());
+            e.set_epoch_not_match(not_match);
+        }
+        RegionError::ServerIsBusy => {
+            let mut busy = errorpb::ServerIsBusy::new();
+            busy.set_reason("injected".to_owned());
+            e.set_message("server is busy".to_owned());
+            e.set_server_is_busy(busy);
+        }
+        RegionError::StaleCommand => {
+            e.set_message("stale command".to_owned());
+            e.set_stale_command(errorpb::StaleCommand::new());
+        }
+        RegionError::RaftEntryTooLarge => {
+            let mut too_large = errorpb::RaftEntryTooLarge::new();
+            too_large.set_region_id(region.get_id());
+            e.set_message("raft entry too large".to_owned());
+            e.set_raft_entry_too_large(too_large);
+        }
+    }
+    e
+}
+
+/// The state of the cluster, shared by the PD and TiKV services.
+struct Cluster {
+    /// The address PD is served on.
+    addr: String,
+    stores: BTreeMap<u64, Store>,
+    /// Regions by their start key.
+    regions: BTreeMap<Vec<u8>, metapb::Region>,
+    /// The store of the leader of each region, by region ID. The first store leads the regions
+    /// missing.
+    leaders: HashMap<u64, u64>,
+    errors: HashMap<u64, VecDeque<errorpb::Error>>,
+    next_id: u64,
+    /// The last timestamp handed out, as physical and logical parts.
+    ts: (i64, i64),
+    /// Bumped whenever the PD leader changes.
+    pd_leader: u64,
+    pd_down: bool,
+    raw: HashMap<String, BTreeMap<Vec<u8>, RawValue>>,
+    locks: BTreeMap<Vec<u8>, Lock>,
+    /// The transaction each waiting pessimistic transaction waits for, by start version.
+    waits: HashMap<u64, u64>,
+    /// Committed and rolled back writes of each key, by start version.
+    writes: BTreeMap<Vec<u8>, BTreeMap<u64, Write>>,
+}
+
+struct Store {
+    meta: metapb::Store,
+    down: bool,
+    delay: Duration,
+    requests: usize,
+}
+
+struct RawValue {
+    value: Vec<u8>,
+    deadline: Option<Instant>,
+}
+
+#[derive(Clone)]
+struct Lock {
+    primary: Vec<u8>,
+    start_version: u64,
+    ttl: u64,
+    op: kvrpcpb::Op,
+    value: Vec<u8>,
+    /// The timestamp a pessimistic lock was acquired at.
+    for_update_ts: u64,
+}
+
+#[derive(Clone)]
+enum Write {
+    Put { commit_version: u64, value: Vec<u8> },
+    Delete { commit_version: u64 },
+    Lock { commit_version: u64 },
+    Rollback,
+}
+
+impl Write {
+    fn commit_version(&self) -> Option<u64> {
+        match self {
+            Write::Put { commit_version, .. }
+            | Write::Delete { commit_version }
+            | Write::Lock { commit_version } => Some(*commit_version),
+            Write::Rollback => None,
+        }
+    }
+}
+
+impl Cluster {
+    fn new() -> Cluster {
+        let mut cluster = Cluster {
+            addr: String::new(),
+            stores: BTreeMap::new(),
+            regions: BTreeMap::new(),
+            leaders: HashMap::new(),
+            errors: HashMap::new(),
+            next_id: STORE_ID + 1,
+            ts: (0, 0),
+            pd_leader: 1,
+            pd_down: false,
+            raw: HashMap::new(),
+            locks: BTreeMap::new(),
+            waits: HashMap::new(),
+            writes: BTreeMap::new(),
+        };
+        cluster.insert_store(STORE_ID);
+        let region = cluster.new_region(Vec::new(), Vec::new());
+        cluster.regions.insert(Vec::new(), region);
+        cluster
+    }
+
+    fn insert_store(&mut self, id: u64) {
+        let mut meta = metapb::Store::new();
+        meta.set_id(id);
+        let store = Store {
+            meta,
+            down: false,
+            delay: Duration::from_millis(0),
+            requests: 0,
+        };
+        self.stores.insert(id, store);
+    }
+
+    /// Add a store and a peer of every region on it. Returns the ID of the store.
+    fn add_store(&mut self) -> u64 {
+        let id = self.alloc_id();
+        self.insert_store(id);
+        let ids: Vec<u64> = (0..self.regions.len()).map(|_| self.alloc_id()).collect();
+        for (region, peer_id) in self.regions.values_mut().zip(ids) {
+            let mut peer = metapb::Peer::new();
+            peer.set_id(peer_id);
+            peer.set_store_id(id);
+            region.mut_peers().push(peer);
+            let conf_ver = region.get_region_epoch().get_conf_ver() + 1;
+            region.mut_region_epoch().set_conf_ver(conf_ver);
+        }
+        id
+    }
+
+    fn set_addr(&mut self, store_id: u64, addr: &str) {
+        if store_id == STORE_ID {
+            self.addr = addr.to_owned();
+        }
+        self.store_mut(store_id).meta.set_address(addr.to_owned());
+    }
+
+    fn store_mut(&mut self, id: u64) -> &mut Store {
+        self.stores
+            .get_mut(&id)
+            .unwrap_or_else(|| panic!("store {} not found", id))
+    }
+
+    /// Count a request store `id` received. Returns how long to wait before replying to it, or
+    /// `None` if the store is down.
+    fn on_request(&mut self, id: u64) -> Option<Duration> {
+        let store = self.store_mut(id);
+        store.requests += 1;
+        if store.down {
+            None
+        } else {
+            Some(store.delay)
+        }
+    }
+
+    /// The peer leading `region`.
+    fn leader(&self, region: &metapb::Region) -> metapb::Peer {
+        let store_id = self
+            .leaders
+            .get(&region.get_id())
+            .cloned()
+            .unwrap_or(STORE_ID);
+        region
+            .get_peers()
+            .iter()
+            .find(|peer| peer.get_store_id() == store_id)
+            .cloned()
+            .unwrap()
+    }
+
+    /// `region` and its leader, as PD describes them.
+    fn with_leader(&self, region: &metapb::Region) -> (metapb::Region, metapb::Peer) {
+        (region.clone(), self.leader(region))
+    }
+
+    fn transfer_leader(&mut self, key: &[u8], store_id: u64) {
+        let region = self.region_by_key(key);
+        assert!(
+            region
+                .get_peers()
+                .iter()
+                .any(|peer| peer.get_store_id() == store_id),
+            "region {} has no peer on store {}",
+            region.get_id(),
+            store_id
+        );
+        let id = region.get_id();
+        self.leaders.insert(id, store_id);
+    }
+
+    fn alloc_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn new_region(&mut self, start_key: Vec<u8>, end_key: Vec<u8>) -> metapb::Region {
+        let store_ids: Vec<u64> = self.stores.keys().cloned().collect();
+        let peers: Vec<metapb::Peer> = store_ids
+            .into_iter()
+            .map(|store_id| {
+                let mut peer = metapb::Peer::new();
+                peer.set_id(self.alloc_id());
+                peer.set_store_id(store_id);
+                peer
+            })
+            .collect();
+        let mut epoch = metapb::RegionEpoch::new();
+        epoch.set_conf_ver(1);
+        epoch.set_version(1);
+        let mut region = metapb::Region::new();
+        region.set_id(self.alloc_id());
+        region.set_start_key(start_key);
+        region.set_end_key(end_key);
+        region.set_region_epoch(epoch);
+        region.set_peers(peers.into());
+        region
+    }
+
+    fn region_by_key(&self, key: &[u8]) -> &metapb::Region {
+        // The first region starts at the empty key, so every key has one.
+        self.regions
+            .range::<[u8], _>(..=key)
+            .next_back()
+            .map(|(_, region)| region)
+            .unwrap()
+    }
+
+    fn prev_region(&self, key: &[u8]) -> Option<&metapb::Region> {
+        let start = self.region_by_key(key).get_start_key();
+        self.regions
+            .range::<[u8], _>(..start)
+            .next_back()
+            .map(|(_, region)| region)
+    }
+
+    fn region_by_id(&self, id: u64) -> Option<&metapb::Region> {
+        self.regions.values().find(|region| region.get_id() == id)
+    }
+
+    fn split(&mut self, key: &[u8]) -> u64 {
+        let mut region = self.region_by_key(key).clone();
+        if region.get_start_key() == key {
+            return region.get_id();
+        }
+        let mut new_region = self.new_region(key.to_vec(), region.take_end_key());
+        region.set_end_key(key.to_vec());
+        let version = region.get_region_epoch().get_version() + 1;
+        region.mut_region_epoch().set_version(version);
+        new_region.mut_region_epoch().set_version(version);
+        let id = new_region.get_id();
+        if let Some(&store_id) = self.leaders.get(&region.get_id()) {
+            self.leaders.insert(id, store_id);
+        }
+        self.regions.insert(region.get_start_key().to_vec(), region);
+        self.regions.insert(key.to_vec(), new_region);
+        id
+    }
+
+    fn inject_error(&mut self, key: &[u8], error: RegionError) {
+        let region = self.region_by_key(key).clone();
+        let leader = self.leader(&region);
+        self.errors
+            .entry(region.get_id())
+            .or_insert_with(VecDeque::new)
+            .push_back(region_error(&region, &leader, error));
+    }
+
+    /// Allocate `count` timestamps, returning the last one as PD does.
+    fn timestamp(&mut self, count: u32) -> (i64, i64) {
+        let now = now_millis();
+        let (physical, logical) = self.ts;
+        self.ts = if now > physical {
+            (now, i64::from(count))
+        } else {
+            (physical, logical + i64::from(count))
+        };
+        self.ts
+    }
+
+    /// Allocate a single timestamp, as a version.
+    fn version(&mut self) -> u64 {
+        let (physical, logical) = self.timestamp(1);
+        Timestamp::compose(physical, logical).timestamp()
+    }
+
+    /// Returns the region a request with `context` to store `store_id` should be served by, if it
+    /// may be, and the error to reply with otherwise.
+    fn check_context(
+        &mut self,
+        store_id: u64,
+        context: &kvrpcpb::Context,
+        keys: &[&[u8]],
+    ) -> ::std::result::Result<metapb::Region, errorpb::Error> {
+        let id = context.get_region_id();
+        if let Some(e) = self.errors.get_mut(&id).and_then(VecDeque::pop_front) {
+            return Err(e);
+        }
+        let region = match self.region_by_id(id) {
+            Some(region) => region.clone(),
+            None => {
+                let mut not_found = errorpb::RegionNotFound::new();
+                not_found.set_region_id(id);
+                let mut e = errorpb::Error::new();
+                e.set_message(format!("region {} not found", id));
+                e.set_region_not_found(not_found);
+                return Err(e);
+            }
+        };
+        let leader = self.leader(&region);
+        if leader.get_store_id() != store_id {
+            return Err(region_error(&region, &leader, RegionError::NotLeader));
+        }
+        if context.get_region_epoch() != region.get_region_epoch() {
+            return Err(region_error(&region, &leader, RegionError::EpochNotMatch));
+        }
+        if let Some(key) = keys.iter().find(|key| !contains(&region, key)) {
+            let mut not_in_region = errorpb::KeyNotInRegion::new();
+            not_in_region.set_key(key.to_vec());
+            not_in_region.set_region_id(id);
+            not_in_region.set_start_key(region.get_start_key().to_vec());
+            not_in_region.set_end_key(region.get_end_key().to_vec());
+            let mut e = errorpb::Error::new();
+            e.set_message("key not in region".to_owned());
+            e.set_key_not_in_region(not_in_region);
+            return Err(e);
+        }
+        Ok(region)
+    }
+}
+
+fn now_millis() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now.as_secs() * 1000 + u64::from(now.subsec_millis())) as i64
+}
+
+fn contains(region: &metapb::Region, key: &[u8]) -> bool {
+    let end_key = region.get_end_key();
+    region.get_start_key() <= key && (end_key.is_empty() || key < end_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let mut cluster = Cluster::new();
+        let first = cluster.region_by_key(b"").get_id();
+        let second = cluster.split(b"m");
+        assert_eq!(cluster.split(b"m"), second);
+        let third = cluster.split(b"t");
+
+        assert_eq!(cluster.regions.len(), 3);
+        assert_eq!(cluster.region_by_key(b"a").get_id(), first);
+        assert_eq!(cluster.region_by_key(b"m").get_id(), second);
+        assert_eq!(cluster.region_by_key(b"s").get_id(), second);
+        assert_eq!(cluster.region_by_key(b"z").get_id(), third);
+        assert_eq!(cluster.region_by_key(b"m").get_end_key(), b"t");
+        assert_eq!(cluster.prev_region(b"s").unwrap().get_id(), first);
+        assert!(cluster.prev_region(b"a").is_none());
+    }
+
+    #[test]
+    fn test_check_context() {
+        let mut cluster = Cluster::new();
+        let old = cluster.region_by_key(b"").clone();
+        cluster.split(b"m");
+        let region = cluster.region_by_key(b"").clone();
+
+        let mut context = kvrpcpb::Context::new();
+        context.set_region_id(region.get_id());
+        context.set_region_epoch(old.get_region_epoch().clone());
+        assert!(cluster
+            .check_context(STORE_ID, &context, &[b"a"])
+            .unwrap_err()
+            .has_epoch_not_match());
+
+        context.set_region_epoch(region.get_region_epoch().clone());
+        assert_eq!(
+            cluster.check_context(STORE_ID, &context, &[b"a"]),
+            Ok(region)
+        );
+        assert!(cluster
+            .check_context(STORE_ID, &context, &[b"a", b"n"])
+            .unwrap_err()
+            .has_key_not_in_region());
+
+        cluster.inject_error(b"a", RegionError::ServerIsBusy);
+        assert!(cluster
+            .check_context(STORE_ID, &context, &[b"a"])
+            .unwrap_err()
+            .has_server_is_busy());
+        assert!(cluster.check_context(STORE_ID, &context, &[b"a"]).is_ok());
+    }
+
+    #[test]
+    fn test_transfer_leader() {
+        let mut cluster = Cluster::new();
+        let store = cluster.add_store();
+        let region = cluster.region_by_key(b"").clone();
+        assert_eq!(region.get_peers().len(), 2);
+
+        let mut context = kvrpcpb::Context::new();
+        context.set_region_id(region.get_id());
+        context.set_region_epoch(region.get_region_epoch().clone());
+        assert!(cluster.check_context(STORE_ID, &context, &[b"a"]).is_ok());
+        assert!(cluster
+            .check_context(store, &context, &[b"a"])
+            .unwrap_err()
+            .has_not_leader());
+
+        cluster.transfer_leader(b"a", store);
+        let e = cluster
+            .check_context(STORE_ID, &context, &[b"a"])
+            .unwrap_err();
+        assert_eq!(e.get_not_leader().get_leader().get_store_id(), store);
+        assert!(cluster.check_context(store, &context, &[b"a"]).is_ok());
+
+        // Regions split off keep the leader.
+        let id = cluster.split(b"m");
+        let split = cluster.region_by_id(id).unwrap();
+        assert_eq!(split.get_peers().len(), 2);
+        assert_eq!(cluster.leader(split).get_store_id(), store);
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, RwLock};
+
+use futures01::{future::Either, Future, Sink, Stream};
+use grpcio::{
+    DuplexSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode, UnarySink, WriteFlags,
+};
+use kvproto::{metapb, pdpb, pdpb_grpc::Pd};
+use log::*;
+
+use crate::mock::{Cluster, CLUSTER_ID};
+
+/// The PD service of the mock cluster, which is its own only member. A change of leader gives
+/// the member a new ID.
+#[derive(Clone)]
+pub(super) struct PdService {
+    cluster: Arc<RwLock<Cluster>>,
+}
+
+impl PdService {
+    pub(super) fn new(cluster: Arc<RwLock<Cluster>>) -> PdService {
+        PdService { cluster }
+    }
+
+    fn is_down(&self) -> bool {
+        self.cluster.read().unwrap().pd_down
+    }
+}
+
+fn header() -> pdpb::ResponseHeader {
+    let mut header = pdpb::ResponseHeader::new();
+    header.set_cluster_id(CLUSTER_ID);
+    header
+}
+
+fn error_header(message: String) -> pdpb::ResponseHeader {
+    let mut error = pdpb::Error::new();
+    error.set_field_type(pdpb::ErrorType::UNKNOWN);
+    error.set_message(message);
+    let mut header = header();
+    header.set_error(error);
+    header
+}
+
+impl Pd for PdService {
+    fn get_members(
+        &mut self,
+        ctx: RpcContext,
+        _: pdpb::GetMembersRequest,
+        sink: UnarySink<pdpb::GetMembersResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let (address, leader) = {
+            let cluster = self.cluster.read().unwrap();
+            (cluster.addr.clone(), cluster.pd_leader)
+        };
+        let mut member = pdpb::Member::new();
+        member.set_name(format!("mock-{}", leader));
+        member.set_member_id(CLUSTER_ID + leader);
+        member.set_client_urls(vec![format!("http://{}", address)].into());
+        let mut resp = pdpb::GetMembersResponse::new();
+        resp.set_header(header());
+        resp.set_members(vec![member.clone()].into());
+        resp.set_leader(member);
+        reply!(ctx, sink, resp);
+    }
+
+    fn tso(
+        &mut self,
+        ctx: RpcContext,
+        stream: RequestStream<pdpb::TsoRequest>,
+        sink: DuplexSink<pdpb::TsoResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let leader = self.cluster.read().unwrap().pd_leader;
+        let current = Arc::clone(&self.cluster);
+        let is_leader = move || {
+            let current = current.read().unwrap();
+            current.pd_leader == leader && !current.pd_down
+        };
+        let cluster = Arc::clone(&self.cluster);
+        let still_leader = is_leader.clone();
+        let responses = stream
+            .take_while(move |_| Ok(still_leader()))
+            .map(move |req| {
+                let (physical, logical) = cluster.write().unwrap().timestamp(req.get_count());
+                let mut timestamp = pdpb::Timestamp::new();
+                timestamp.set_physical(physical);
+                timestamp.set_logical(logical);
+                let mut resp = pdpb::TsoResponse::new();
+                resp.set_header(header());
+                resp.set_count(req.get_count());
+                resp.set_timestamp(timestamp);
+                (resp, WriteFlags::default())
+            });
+        ctx.spawn(
+            sink.send_all(responses)
+                .and_then(move |(sink, _)| {
+                    // A former or crashed leader refuses to allocate more timestamps.
+                    if is_leader() {
+                        Either::A(futures01::future::ok(()))
+                    } else {
+                        let status = RpcStatus::new(
+                            RpcStatusCode::Unavailable,
+                            Some("not leader".to_owned()),
+                        );
+                        Either::B(sink.fail(status))
+                    }
+                })
+                .map_err(|e| warn!("tso stream failed: {:?}", e)),
+        );
+    }
+
+    fn get_store(
+        &mut self,
+        ctx: RpcContext,
+        req: pdpb::GetStoreRequest,
+        sink: UnarySink<pdpb::GetStoreResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let store = self
+            .cluster
+            .read()
+            .unwrap()
+            .stores
+            .get(&req.get_store_id())
+            .map(|store| store.meta.clone());
+        let mut resp = pdpb::GetStoreResponse::new();
+        if let Some(store) = store {
+            resp.set_header(header());
+            resp.set_store(store);
+        } else {
+            resp.set_header(error_header(format!(
+                "invalid store ID {}, not found",
+                req.get_store_id()
+            )));
+        }
+        reply!(ctx, sink, resp);
+    }
+
+    fn get_all_stores(
+        &mut self,
+        ctx: RpcContext,
+        _: pdpb::GetAllStoresRequest,
+        sink: UnarySink<pdpb::GetAllStoresResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let stores: Vec<metapb::Store> = self
+            .cluster
+            .read()
+            .unwrap()
+            .stores
+            .values()
+            .map(|store| store.meta.clone())
+            .collect();
+        let mut resp = pdpb::GetAllStoresResponse::new();
+        resp.set_header(header());
+        resp.set_stores(stores.into());
+        reply!(ctx, sink, resp);
+    }
+
+    fn get_region(
+        &mut self,
+        ctx: RpcContext,
+        req: pdpb::GetRegionRequest,
+        sink: UnarySink<pdpb::GetRegionResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let (region, leader) = {
+            let cluster = self.cluster.read().unwrap();
+            cluster.with_leader(cluster.region_by_key(req.get_region_key()))
+        };
+        let mut resp = pdpb::GetRegionResponse::new();
+        resp.set_header(header());
+        resp.set_region(region);
+        resp.set_leader(leader);
+        reply!(ctx, sink, resp);
+    }
+
+    fn get_prev_region(
+        &mut self,
+        ctx: RpcContext,
+        req: pdpb::GetRegionRequest,
+        sink: UnarySink<pdpb::GetRegionResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let found = {
+            let cluster = self.cluster.read().unwrap();
+            cluster
+                .prev_region(req.get_region_key())
+                .map(|region| cluster.with_leader(region))
+        };
+        let mut resp = pdpb::GetRegionResponse::new();
+        resp.set_header(header());
+        if let Some((region, leader)) = found {
+            resp.set_region(region);
+            resp.set_leader(leader);
+        }
+        reply!(ctx, sink, resp);
+    }
+
+    fn get_region_by_id(
+        &mut self,
+        ctx: RpcContext,
+        req: pdpb::GetRegionByIDRequest,
+        sink: UnarySink<pdpb::GetRegionResponse>,
+    ) {
+        if self.is_down() {
+            fail_unavailable!(ctx, sink);
+            return;
+        }
+        let found = {
+            let cluster = self.cluster.read().unwrap();
+            cluster
+                .region_by_id(req.get_region_id())
+                .map(|region| cluster.with_leader(region))
+        };
+        let mut resp = pdpb::GetRegionResponse::new();
+        resp.set_header(header());
+        if let Some((region, leader)) = found {
+            resp.set_region(region);
+            resp.set_leader(leader);
+        }
+        reply!(ctx, sink, resp);
+    }
+
+    fn region_heartbeat(
+        &mut self,
+        ctx: RpcContext,
+        _: RequestStream<pdpb::RegionHeartbeatRequest>,
+        sink: DuplexSink<pdpb::RegionHeartbeatResponse>,
+    ) {
+        fail_unimplemented!(ctx, sink);
+    }
+
+    fn sync_regions(
+        &mut self,
+        ctx: RpcContext,
+        _: RequestStream<pdpb::SyncRegionRequest>,
+        sink: DuplexSink<pdpb::SyncRegionResponse>,
+    ) {
+        fail_unimplemented!(ctx, sink);
+    }
+
+    unimplemented_calls! {
+        bootstrap(pdpb::BootstrapRequest) -> pdpb::BootstrapResponse;
+        is_bootstrapped(pdpb::IsBootstrappedRequest) -> pdpb::IsBootstrappedResponse;
+        alloc_id(pdpb::AllocIDRequest) -> pdpb::AllocIDResponse;
+        put_store(pdpb::PutStoreRequest) -> pdpb::PutStoreResponse;
+        store_heartbeat(pdpb::StoreHeartbeatRequest) -> pdpb::StoreHeartbeatResponse;
+        ask_split(pdpb::AskSplitRequest) -> pdpb::AskSplitResponse;
+        report_split(pdpb::ReportSplitRequest) -> pdpb::ReportSplitResponse;
+        ask_batch_split(pdpb::AskBatchSplitRequest) -> pdpb::AskBatchSplitResponse;
+        report_batch_split(pdpb::ReportBatchSplitRequest) -> pdpb::ReportBatchSplitResponse;
+        get_cluster_config(pdpb::GetClusterConfigRequest) -> pdpb::GetClusterConfigResponse;
+        put_cluster_config(pdpb::PutClusterConfigRequest) -> pdpb::PutClusterConfigResponse;
+        scatter_region(pdpb::ScatterRegionRequest) -> pdpb::ScatterRegionResponse;
+        get_gc_safe_point(pdpb::GetGCSafePointRequest) -> pdpb::GetGCSafePointResponse;
+        update_gc_safe_point(pdpb::UpdateGCSafePointRequest) -> pdpb::UpdateGCSafePointResponse;
+    }
+}
@@ -0,0 +1,1154 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use futures::{FutureExt, TryFutureExt};
+use futures01::Future;
+use futures_timer::Delay;
+use grpcio::{
+    ClientStreamingSink, DuplexSink, RequestStream, RpcContext, ServerStreamingSink, UnarySink,
+};
+use kvproto::{coprocessor, errorpb, kvrpcpb, metapb, raft_serverpb, tikvpb, tikvpb_grpc::Tikv};
+use log::*;
+
+use crate::mock::{Cluster, Lock, RawValue, Write};
+
+/// The TiKV service of a store of the mock cluster, serving the requests of the regions it leads.
+#[derive(Clone)]
+pub(super) struct TikvService {
+    cluster: Arc<RwLock<Cluster>>,
+    store_id: u64,
+}
+
+impl TikvService {
+    pub(super) fn new(cluster: Arc<RwLock<Cluster>>, store_id: u64) -> TikvService {
+        TikvService { cluster, store_id }
+    }
+
+    /// Reply to a request sent with `context` for `keys`. If the request reached the leader of the
+    /// right region, the response is filled in by `f`, otherwise it carries the region error. A
+    /// store which is down fails the request instead.
+    fn handle<Resp, F>(
+        &self,
+        ctx: RpcContext,
+        sink: UnarySink<Resp>,
+        context: &kvrpcpb::Context,
+        keys: &[&[u8]],
+        f: F,
+    ) where
+        Resp: RegionResponse + Send + 'static,
+        F: FnOnce(&mut Cluster, &metapb::Region, &mut Resp),
+    {
+        let mut resp = Resp::default();
+        let delay = {
+            let mut cluster = self.cluster.write().unwrap();
+            let delay = match cluster.on_request(self.store_id) {
+                Some(delay) => delay,
+                None => {
+                    fail_unavailable!(ctx, sink);
+                    return;
+                }
+            };
+            match cluster.check_context(self.store_id, context, keys) {
+                Ok(region) => f(&mut *cluster, &region, &mut resp),
+                Err(e) => resp.set_region_err(e),
+            }
+            delay
+        };
+        if delay == Duration::from_millis(0) {
+            reply!(ctx, sink, resp);
+        } else {
+            let timer = Delay::new(delay).unit_error().boxed().compat();
+            ctx.spawn(
+                timer
+                    .then(move |_| sink.success(resp))
+                    .map_err(|e| warn!("failed to reply: {:?}", e)),
+            );
+        }
+    }
+}
+
+trait RegionResponse: Default {
+    fn set_region_err(&mut self, e: errorpb::Error);
+}
+
+macro_rules! region_response {
+    ($($type:ty),*) => {
+        $(
+            impl RegionResponse for $type {
+                fn set_region_err(&mut self, e: errorpb::Error) {
+                    self.set_region_error(e);
+                }
+            }
+        )*
+    };
+}
+
+region_response!(
+    kvrpcpb::GetResponse,
+    kvrpcpb::BatchGetResponse,
+    kvrpcpb::ScanResponse,
+    kvrpcpb::PrewriteResponse,
+    kvrpcpb::PessimisticLockResponse,
+    kvrpcpb::PessimisticRollbackResponse,
+    kvrpcpb::CommitResponse,
+    kvrpcpb::CleanupResponse,
+    kvrpcpb::BatchRollbackResponse,
+    kvrpcpb::ResolveLockResponse,
+    kvrpcpb::RawGetResponse,
+    kvrpcpb::RawBatchGetResponse,
+    kvrpcpb::RawPutResponse,
+    kvrpcpb::RawBatchPutResponse,
+    kvrpcpb::RawDeleteResponse,
+    kvrpcpb::RawBatchDeleteResponse,
+    kvrpcpb::RawScanResponse,
+    kvrpcpb::RawBatchScanResponse,
+    kvrpcpb::RawDeleteRangeResponse,
+    kvrpcpb::RawCASResponse,
+    kvrpcpb::RawGetKeyTTLResponse
+);
+
+/// Returns `[lower, upper)` narrowed down to the range of `region`, or `None` if they do not
+/// overlap. An empty upper bound is unbounded.
+fn clamp<'a>(
+    region: &'a metapb::Region,
+    lower: &'a [u8],
+    upper: &'a [u8],
+) -> Option<(Bound<&'a [u8]>, Bound<&'a [u8]>)> {
+    let lower = cmp::max(lower, region.get_start_key());
+    let upper = match (upper, region.get_end_key()) {
+        (upper, end) if upper.is_empty() => end,
+        (upper, end) if end.is_empty() => upper,
+        (upper, end) => cmp::min(upper, end),
+    };
+    if upper.is_empty() {
+        Some((Bound::Included(lower), Bound::Unbounded))
+    } else if lower < upper {
+        Some((Bound::Included(lower), Bound::Excluded(upper)))
+    } else {
+        None
+    }
+}
+
+/// Returns the keys of `map` in `range`, in descending order if `reverse` is set.
+fn keys_in<'a, V>(
+    map: &'a BTreeMap<Vec<u8>, V>,
+    range: Option<(Bound<&[u8]>, Bound<&[u8]>)>,
+    reverse: bool,
+) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a V)> + 'a> {
+    match range {
+        Some(range) if reverse => Box::new(map.range::<[u8], _>(range).rev()),
+        Some(range) => Box::new(map.range::<[u8], _>(range)),
+        None => Box::new(::std::iter::empty()),
+    }
+}
+
+fn kv_pair(key: Vec<u8>, value: Vec<u8>) -> kvrpcpb::KvPair {
+    let mut pair = kvrpcpb::KvPair::new();
+    pair.set_key(key);
+    pair.set_value(value);
+    pair
+}
+
+fn key_error_pair(key: Vec<u8>, error: kvrpcpb::KeyError) -> kvrpcpb::KvPair {
+    let mut pair = kvrpcpb::KvPair::new();
+    pair.set_key(key);
+    pair.set_error(error);
+    pair
+}
+
+fn locked(key: &[u8], lock: &Lock) -> kvrpcpb::KeyError {
+    let mut info = kvrpcpb::LockInfo::new();
+    info.set_primary_lock(lock.primary.clone());
+    info.set_lock_version(lock.start_version);
+    info.set_key(key.to_vec());
+    info.set_lock_ttl(lock.ttl);
+    let mut error = kvrpcpb::KeyError::new();
+    error.set_locked(info);
+    error
+}
+
+fn deadlock(key: &[u8], lock: &Lock) -> kvrpcpb::KeyError {
+    let mut info = kvrpcpb::Deadlock::new();
+    info.set_lock_ts(lock.start_version);
+    info.set_lock_key(key.to_vec());
+    let mut error = kvrpcpb::KeyError::new();
+    error.set_deadlock(info);
+    error
+}
+
+fn abort(message: &str) -> kvrpcpb::KeyError {
+    let mut error = kvrpcpb::KeyError::new();
+    error.set_abort(message.to_owned());
+    error
+}
+
+fn retryable(message: &str) -> kvrpcpb::KeyError {
+    let mut error = kvrpcpb::KeyError::new();
+    error.set_retryable(message.to_owned());
+    error
+}
+
+impl RawValue {
+    fn is_expired(&self) -> bool {
+        self.deadline
+            .map_or(false, |deadline| deadline <= Instant::now())
+    }
+}
+
+impl Cluster {
+    fn raw_cf(&mut self, cf: &str) -> &mut BTreeMap<Vec<u8>, RawValue> {
+        self.raw.entry(cf.to_owned()).or_insert_with(BTreeMap::new)
+    }
+
+    fn raw_get(&mut self, cf: &str, key: &[u8]) -> Option<&RawValue> {
+        let data = self.raw_cf(cf);
+        if data.get(key).map_or(false, RawValue::is_expired) {
+            data.remove(key);
+        }
+        data.get(key)
+    }
+
+    fn raw_put(&mut self, cf: &str, key: Vec<u8>, value: Vec<u8>, ttl: u64) {
+        let deadline = if ttl == 0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_secs(ttl))
+        };
+        self.raw_cf(cf).insert(key, RawValue { value, deadline });
+    }
+
+    fn raw_scan(
+        &mut self,
+        cf: &str,
+        range: Option<(Bound<&[u8]>, Bound<&[u8]>)>,
+        limit: u32,
+        key_only: bool,
+        reverse: bool,
+    ) -> Vec<kvrpcpb::KvPair> {
+        keys_in(self.raw_cf(cf), range, reverse)
+            .filter(|(_, value)| !value.is_expired())
+            .take(limit as usize)
+            .map(|(key, value)| {
+                let value = if key_only {
+                    Vec::new()
+                } else {
+                    value.value.clone()
+                };
+                kv_pair(key.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Read `key` at `version`, unless a transaction which started before is writing it.
+    fn mvcc_get(
+        &self,
+        key: &[u8],
+        version: u64,
+    ) -> ::std::result::Result<Option<Vec<u8>>, kvrpcpb::KeyError> {
+        if let Some(lock) = self.locks.get(key) {
+            let blocks_reads =
+                lock.op != kvrpcpb::Op::Lock && lock.op != kvrpcpb::Op::PessimisticLock;
+            if blocks_reads && lock.start_version <= version {
+                return Err(locked(key, lock));
+            }
+        }
+        let latest = self.writes.get(key).and_then(|writes| {
+            writes
+                .values()
+                .filter(|write| match write {
+                    Write::Put { commit_version, .. } | Write::Delete { commit_version } => {
+                        *commit_version <= version
+                    }
+                    _ => false,
+                })
+                .max_by_key(|write| write.commit_version())
+        });
+        Ok(match latest {
+            Some(Write::Put { value, .. }) => Some(value.clone()),
+            _ => None,
+        })
+    }
+
+    fn mvcc_scan(
+        &self,
+        range: Option<(Bound<&[u8]>, Bound<&[u8]>)>,
+        version: u64,
+        limit: u32,
+        key_only: bool,
+        reverse: bool,
+    ) -> Vec<kvrpcpb::KvPair> {
+        let keys: BTreeSet<&Vec<u8>> = keys_in(&self.locks, range, false)
+            .map(|(key, _)| key)
+            .chain(keys_in(&self.writes, range, false).map(|(key, _)| key))
+            .collect();
+        let keys: Box<dyn Iterator<Item = &Vec<u8>> + '_> = if reverse {
+            Box::new(keys.into_iter().rev())
+        } else {
+            Box::new(keys.into_iter())
+        };
+        keys.filter_map(|key| match self.mvcc_get(key, version) {
+            Ok(Some(_)) if key_only => Some(kv_pair(key.clone(), Vec::new())),
+            Ok(Some(value)) => Some(kv_pair(key.clone(), value)),
+            Ok(None) => None,
+            Err(e) => Some(key_error_pair(key.clone(), e)),
+        })
+        .take(limit as usize)
+        .collect()
+    }
+
+    /// Prewrite `mutations`. The keys flagged in `is_pessimistic_lock` must hold a pessimistic
+    /// lock of the transaction, which is turned into a regular one.
+    pub(super) fn prewrite(
+        &mut self,
+        mutations: &[kvrpcpb::Mutation],
+        is_pessimistic_lock: &[bool],
+        primary: &[u8],
+        start_version: u64,
+        ttl: u64,
+    ) -> Vec<kvrpcpb::KeyError> {
+        let mut errors = Vec::new();
+        let mut locks = Vec::new();
+        for (i, mutation) in mutations.iter().enumerate() {
+            let key = mutation.get_key();
+            let pessimistic = is_pessimistic_lock.get(i).cloned().unwrap_or(false);
+            match self.locks.get(key) {
+                Some(lock) if lock.start_version != start_version => {
+                    errors.push(locked(key, lock));
+                    continue;
+                }
+                // Prewritten already.
+                Some(lock) if lock.op != kvrpcpb::Op::PessimisticLock => continue,
+                Some(_) => {}
+                None if pessimistic => {
+                    errors.push(abort("pessimistic lock not found"));
+                    continue;
+                }
+                None => {}
+            }
+            let writes = self.writes.get(key);
+            match writes.and_then(|writes| writes.get(&start_version)) {
+                Some(Write::Rollback) => {
+                    errors.push(abort("transaction is already rolled back"));
+                    continue;
+                }
+                // Prewritten and committed already.
+                Some(_) => continue,
+                None => {}
+            }
+            // Writes to a pessimistically locked key were checked when it was locked.
+            let conflict = !pessimistic
+                && writes.map_or(false, |writes| {
+                    writes
+                        .values()
+                        .any(|write| write.commit_version().map_or(false, |v| v >= start_version))
+                });
+            if conflict {
+                errors.push(retryable("write conflict"));
+                continue;
+            }
+            if mutation.get_op() == kvrpcpb::Op::Rollback {
+                continue;
+            }
+            locks.push((
+                key.to_vec(),
+                Lock {
+                    primary: primary.to_vec(),
+                    start_version,
+                    ttl,
+                    op: mutation.get_op(),
+                    value: mutation.get_value().to_vec(),
+                    for_update_ts: 0,
+                },
+            ));
+        }
+        if errors.is_empty() {
+            self.locks.extend(locks);
+        }
+        errors
+    }
+
+    /// Acquire pessimistic locks on `keys` at `for_update_ts`, unless another transaction holds
+    /// one of them or wrote it since.
+    fn pessimistic_lock(
+        &mut self,
+        keys: &[&[u8]],
+        primary: &[u8],
+        start_version: u64,
+        for_update_ts: u64,
+        ttl: u64,
+    ) -> Vec<kvrpcpb::KeyError> {
+        let mut errors = Vec::new();
+        let mut locks = Vec::new();
+        for key in keys {
+            if let Some(lock) = self.locks.get(*key).cloned() {
+                if lock.start_version != start_version {
+                    errors.push(self.wait_for(key, &lock, start_version));
+                } else if lock.op == kvrpcpb::Op::PessimisticLock {
+                    let for_update_ts = cmp::max(lock.for_update_ts, for_update_ts);
+                    locks.push((
+                        key.to_vec(),
+                        Lock {
+                            for_update_ts,
+                            ..lock
+                        },
+                    ));
+                }
+                continue;
+            }
+            let writes = self.writes.get(*key);
+            if let Some(Write::Rollback) = writes.and_then(|writes| writes.get(&start_version)) {
+                errors.push(abort("transaction is already rolled back"));
+                continue;
+            }
+            let conflict = writes.map_or(false, |writes| {
+                writes
+                    .values()
+                    .any(|write| write.commit_version().map_or(false, |v| v > for_update_ts))
+            });
+            if conflict {
+                errors.push(retryable("write conflict"));
+                continue;
+            }
+            locks.push((
+                key.to_vec(),
+                Lock {
+                    primary: primary.to_vec(),
+                    start_version,
+                    ttl,
+                    op: kvrpcpb::Op::PessimisticLock,
+                    value: Vec::new(),
+                    for_update_ts,
+                },
+            ));
+        }
+        if errors.is_empty() {
+            self.waits.remove(&start_version);
+            self.locks.extend(locks);
+        }
+        errors
+    }
+
+    /// The error for the transaction started at `start_version` running into `lock`: a deadlock
+    /// if the transaction holding it waits for this one, even through others, and the lock
+    /// otherwise, which the transaction is then recorded to wait for.
+    fn wait_for(&mut self, key: &[u8], lock: &Lock, start_version: u64) -> kvrpcpb::KeyError {
+        let mut holder = lock.start_version;
+        for _ in 0..self.waits.len() {
+            match self.waits.get(&holder) {
+                Some(&next) if next == start_version => return deadlock(key, lock),
+                Some(&next) => holder = next,
+                None => break,
+            }
+        }
+        self.waits.insert(start_version, lock.start_version);
+        locked(key, lock)
+    }
+
+    /// Release the pessimistic locks on `keys` the transaction acquired up to `for_update_ts`.
+    fn pessimistic_rollback(&mut self, keys: &[Vec<u8>], start_version: u64, for_update_ts: u64) {
+        for key in keys {
+            let release = self.locks.get(key).map_or(false, |lock| {
+                lock.start_version == start_version
+                    && lock.op == kvrpcpb::Op::PessimisticLock
+                    && lock.for_update_ts <= for_update_ts
+            });
+            if release {
+                self.locks.remove(key);
+            }
+        }
+        self.waits.remove(&start_version);
+    }
+
+    pub(super) fn commit(
+        &mut self,
+        keys: &[Vec<u8>],
+        start_version: u64,
+        commit_version: u64,
+    ) -> Option<kvrpcpb::KeyError> {
+        for key in keys {
+            if self
+                .locks
+                .get(key)
+                .map_or(false, |lock| lock.start_version == start_version)
+            {
+                continue;
+            }
+            match self
+                .writes
+                .get(key)
+                .and_then(|writes| writes.get(&start_version))
+            {
+                Some(Write::Rollback) => return Some(abort("transaction is already rolled back")),
+                Some(_) => continue,
+                None => return Some(abort("transaction lock not found")),
+            }
+        }
+        for key in keys {
+            self.commit_key(key, start_version, commit_version);
+        }
+        None
+    }
+
+    fn commit_key(&mut self, key: &[u8], start_version: u64, commit_version: u64) {
+        let lock = match self.locks.get(key) {
+            Some(lock) if lock.start_version == start_version => self.locks.remove(key).unwrap(),
+            _ => return,
+        };
+        let write = match lock.op {
+            kvrpcpb::Op::Put => Write::Put {
+                commit_version,
+                value: lock.value,
+            },
+            kvrpcpb::Op::Del => Write::Delete { commit_version },
+            _ => Write::Lock { commit_version },
+        };
+        self.writes
+            .entry(key.to_vec())
+            .or_insert_with(BTreeMap::new)
+            .insert(start_version, write);
+    }
+
+    fn rollback(&mut self, key: &[u8], start_version: u64) -> Option<kvrpcpb::KeyError> {
+        let writes = self
+            .writes
+            .entry(key.to_vec())
+            .or_insert_with(BTreeMap::new);
+        match writes.get(&start_version) {
+            Some(Write::Rollback) => return None,
+            Some(_) => return Some(abort("transaction is already committed")),
+            None => {}
+        }
+        writes.insert(start_version, Write::Rollback);
+        self.waits.remove(&start_version);
+        if self
+            .locks
+            .get(key)
+            .map_or(false, |lock| lock.start_version == start_version)
+        {
+            self.locks.remove(key);
+        }
+        None
+    }
+
+    /// Returns the commit version of the transaction started at `start_version`, rolling its
+    /// primary lock at `key` back unless it is committed. Zero means the transaction is rolled
+    /// back. Like TiKV, the lock TTL is not checked here: that is up to the client.
+    fn cleanup(&mut self, key: &[u8], start_version: u64) -> u64 {
+        let committed = self
+            .writes
+            .get(key)
+            .and_then(|writes| writes.get(&start_version))
+            .and_then(Write::commit_version);
+        match committed {
+            Some(commit_version) => commit_version,
+            None => {
+                self.rollback(key, start_version);
+                0
+            }
+        }
+    }
+
+    fn resolve_lock(&mut self, region: &metapb::Region, start_version: u64, commit_version: u64) {
+        let keys: Vec<Vec<u8>> = keys_in(&self.locks, clamp(region, &[], &[]), false)
+            .filter(|(_, lock)| lock.start_version == start_version)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            if commit_version == 0 {
+                self.rollback(&key, start_version);
+            } else {
+                self.commit_key(&key, start_version, commit_version);
+            }
+        }
+    }
+}
+
+impl Tikv for TikvService {
+    fn kv_get(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::GetRequest,
+        sink: UnarySink<kvrpcpb::GetResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(
+            ctx,
+            sink,
+            req.get_context(),
+            &[key],
+            |cluster, _, resp| match cluster.mvcc_get(key, req.get_version()) {
+                Ok(value) => resp.set_value(value.unwrap_or_default()),
+                Err(e) => resp.set_error(e),
+            },
+        );
+    }
+
+    fn kv_batch_get(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::BatchGetRequest,
+        sink: UnarySink<kvrpcpb::BatchGetResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_keys().iter().map(Vec::as_slice).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, resp| {
+            let pairs = keys
+                .iter()
+                .filter_map(|key| match cluster.mvcc_get(key, req.get_version()) {
+                    Ok(value) => value.map(|value| kv_pair(key.to_vec(), value)),
+                    Err(e) => Some(key_error_pair(key.to_vec(), e)),
+                })
+                .collect::<Vec<_>>();
+            resp.set_pairs(pairs.into());
+        });
+    }
+
+    fn kv_scan(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::ScanRequest,
+        sink: UnarySink<kvrpcpb::ScanResponse>,
+    ) {
+        // A reverse scan starts from its upper bound.
+        let (lower, upper) = if req.get_reverse() {
+            (req.get_end_key(), req.get_start_key())
+        } else {
+            (req.get_start_key(), req.get_end_key())
+        };
+        let start_keys = [lower];
+        let keys: &[&[u8]] = if req.get_reverse() { &[] } else { &start_keys };
+        self.handle(
+            ctx,
+            sink,
+            req.get_context(),
+            keys,
+            |cluster, region, resp| {
+                let pairs = cluster.mvcc_scan(
+                    clamp(region, lower, upper),
+                    req.get_version(),
+                    req.get_limit(),
+                    req.get_key_only(),
+                    req.get_reverse(),
+                );
+                resp.set_pairs(pairs.into());
+            },
+        );
+    }
+
+    fn kv_prewrite(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::PrewriteRequest,
+        sink: UnarySink<kvrpcpb::PrewriteResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_mutations().iter().map(|m| m.get_key()).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, resp| {
+            let errors = cluster.prewrite(
+                req.get_mutations(),
+                req.get_is_pessimistic_lock(),
+                req.get_primary_lock(),
+                req.get_start_version(),
+                req.get_lock_ttl(),
+            );
+            resp.set_errors(errors.into());
+        });
+    }
+
+    fn kv_pessimistic_lock(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::PessimisticLockRequest,
+        sink: UnarySink<kvrpcpb::PessimisticLockResponse>,
+    ) {
+        // Locks are reported right away rather than waited for, the client retries until its
+        // wait timeout.
+        let keys: Vec<&[u8]> = req.get_mutations().iter().map(|m| m.get_key()).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, resp| {
+            let errors = cluster.pessimistic_lock(
+                &keys,
+                req.get_primary_lock(),
+                req.get_start_version(),
+                req.get_for_update_ts(),
+                req.get_lock_ttl(),
+            );
+            resp.set_errors(errors.into());
+        });
+    }
+
+    fn kv_pessimistic_rollback(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::PessimisticRollbackRequest,
+        sink: UnarySink<kvrpcpb::PessimisticRollbackResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_keys().iter().map(Vec::as_slice).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, _| {
+            cluster.pessimistic_rollback(
+                req.get_keys(),
+                req.get_start_version(),
+                req.get_for_update_ts(),
+            );
+        });
+    }
+
+    fn kv_commit(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::CommitRequest,
+        sink: UnarySink<kvrpcpb::CommitResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_keys().iter().map(Vec::as_slice).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, resp| {
+            if let Some(e) = cluster.commit(
+                req.get_keys(),
+                req.get_start_version(),
+                req.get_commit_version(),
+            ) {
+                resp.set_error(e);
+            }
+        });
+    }
+
+    fn kv_cleanup(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::CleanupRequest,
+        sink: UnarySink<kvrpcpb::CleanupResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(ctx, sink, req.get_context(), &[key], |cluster, _, resp| {
+            resp.set_commit_version(cluster.cleanup(key, req.get_start_version()))
+        });
+    }
+
+    fn kv_batch_rollback(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::BatchRollbackRequest,
+        sink: UnarySink<kvrpcpb::BatchRollbackResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_keys().iter().map(Vec::as_slice).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, resp| {
+            if let Some(e) = keys
+                .iter()
+                .filter_map(|key| cluster.rollback(key, req.get_start_version()))
+                .next()
+            {
+                resp.set_error(e);
+            }
+        });
+    }
+
+    fn kv_resolve_lock(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::ResolveLockRequest,
+        sink: UnarySink<kvrpcpb::ResolveLockResponse>,
+    ) {
+        self.handle(ctx, sink, req.get_context(), &[], |cluster, region, _| {
+            if req.get_txn_infos().is_empty() {
+                cluster.resolve_lock(region, req.get_start_version(), req.get_commit_version());
+            }
+            for info in req.get_txn_infos() {
+                cluster.resolve_lock(region, info.get_txn(), info.get_status());
+            }
+        });
+    }
+
+    fn raw_get(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawGetRequest,
+        sink: UnarySink<kvrpcpb::RawGetResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(ctx, sink, req.get_context(), &[key], |cluster, _, resp| {
+            if let Some(value) = cluster.raw_get(req.get_cf(), key) {
+                resp.set_value(value.value.clone());
+            }
+        });
+    }
+
+    fn raw_batch_get(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawBatchGetRequest,
+        sink: UnarySink<kvrpcpb::RawBatchGetResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_keys().iter().map(Vec::as_slice).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, resp| {
+            let pairs = keys
+                .iter()
+                .filter_map(|key| {
+                    cluster
+                        .raw_get(req.get_cf(), key)
+                        .map(|value| kv_pair(key.to_vec(), value.value.clone()))
+                })
+                .collect::<Vec<_>>();
+            resp.set_pairs(pairs.into());
+        });
+    }
+
+    fn raw_put(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawPutRequest,
+        sink: UnarySink<kvrpcpb::RawPutResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(ctx, sink, req.get_context(), &[key], |cluster, _, _| {
+            cluster.raw_put(
+                req.get_cf(),
+                key.to_vec(),
+                req.get_value().to_vec(),
+                req.get_ttl(),
+            );
+        });
+    }
+
+    fn raw_batch_put(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawBatchPutRequest,
+        sink: UnarySink<kvrpcpb::RawBatchPutResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_pairs().iter().map(|p| p.get_key()).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, _| {
+            for pair in req.get_pairs() {
+                cluster.raw_put(
+                    req.get_cf(),
+                    pair.get_key().to_vec(),
+                    pair.get_value().to_vec(),
+                    req.get_ttl(),
+                );
+            }
+        });
+    }
+
+    fn raw_delete(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawDeleteRequest,
+        sink: UnarySink<kvrpcpb::RawDeleteResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(ctx, sink, req.get_context(), &[key], |cluster, _, _| {
+            cluster.raw_cf(req.get_cf()).remove(key);
+        });
+    }
+
+    fn raw_batch_delete(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawBatchDeleteRequest,
+        sink: UnarySink<kvrpcpb::RawBatchDeleteResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_keys().iter().map(Vec::as_slice).collect();
+        self.handle(ctx, sink, req.get_context(), &keys, |cluster, _, _| {
+            let data = cluster.raw_cf(req.get_cf());
+            for key in &keys {
+                data.remove(*key);
+            }
+        });
+    }
+
+    fn raw_scan(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawScanRequest,
+        sink: UnarySink<kvrpcpb::RawScanResponse>,
+    ) {
+        // A reverse scan starts from its upper bound.
+        let (lower, upper) = if req.get_reverse() {
+            (req.get_end_key(), req.get_start_key())
+        } else {
+            (req.get_start_key(), req.get_end_key())
+        };
+        let start_keys = [lower];
+        let keys: &[&[u8]] = if req.get_reverse() { &[] } else { &start_keys };
+        self.handle(
+            ctx,
+            sink,
+            req.get_context(),
+            keys,
+            |cluster, region, resp| {
+                let kvs = cluster.raw_scan(
+                    req.get_cf(),
+                    clamp(region, lower, upper),
+                    req.get_limit(),
+                    req.get_key_only(),
+                    req.get_reverse(),
+                );
+                resp.set_kvs(kvs.into());
+            },
+        );
+    }
+
+    fn raw_batch_scan(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawBatchScanRequest,
+        sink: UnarySink<kvrpcpb::RawBatchScanResponse>,
+    ) {
+        let keys: Vec<&[u8]> = req.get_ranges().iter().map(|r| r.get_start_key()).collect();
+        self.handle(
+            ctx,
+            sink,
+            req.get_context(),
+            &keys,
+            |cluster, region, resp| {
+                let mut kvs = Vec::new();
+                for range in req.get_ranges() {
+                    kvs.extend(cluster.raw_scan(
+                        req.get_cf(),
+                        clamp(region, range.get_start_key(), range.get_end_key()),
+                        req.get_each_limit(),
+                        req.get_key_only(),
+                        false,
+                    ));
+                }
+                resp.set_kvs(kvs.into());
+            },
+        );
+    }
+
+    fn raw_delete_range(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawDeleteRangeRequest,
+        sink: UnarySink<kvrpcpb::RawDeleteRangeResponse>,
+    ) {
+        let key = req.get_start_key();
+        self.handle(
+            ctx,
+            sink,
+            req.get_context(),
+            &[key],
+            |cluster, region, _| {
+                let range = clamp(region, key, req.get_end_key());
+                let data = cluster.raw_cf(req.get_cf());
+                let keys: Vec<Vec<u8>> = keys_in(data, range, false)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in keys {
+                    data.remove(&key);
+                }
+            },
+        );
+    }
+
+    fn raw_compare_and_swap(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawCASRequest,
+        sink: UnarySink<kvrpcpb::RawCASResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(ctx, sink, req.get_context(), &[key], |cluster, _, resp| {
+            let previous = cluster
+                .raw_get(req.get_cf(), key)
+                .map(|value| value.value.clone());
+            let succeed = match previous {
+                Some(ref value) => {
+                    !req.get_previous_not_exist() && value.as_slice() == req.get_previous_value()
+                }
+                None => req.get_previous_not_exist(),
+            };
+            if succeed {
+                cluster.raw_put(
+                    req.get_cf(),
+                    key.to_vec(),
+                    req.get_value().to_vec(),
+                    req.get_ttl(),
+                );
+            }
+            match previous {
+                Some(value) => resp.set_previous_value(value),
+                None => resp.set_previous_not_exist(true),
+            }
+            resp.set_succeed(succeed);
+        });
+    }
+
+    fn raw_get_key_ttl(
+        &mut self,
+        ctx: RpcContext,
+        req: kvrpcpb::RawGetKeyTTLRequest,
+        sink: UnarySink<kvrpcpb::RawGetKeyTTLResponse>,
+    ) {
+        let key = req.get_key();
+        self.handle(ctx, sink, req.get_context(), &[key], |cluster, _, resp| {
+            match cluster.raw_get(req.get_cf(), key) {
+                Some(value) => {
+                    let now = Instant::now();
+                    let ttl = match value.deadline {
+                        // Round up, so keys which have not expired yet have some time left.
+                        Some(deadline) if deadline > now => {
+                            let left = deadline - now;
+                            left.as_secs() + u64::from(left.subsec_nanos() > 0)
+                        }
+                        _ => 0,
+                    };
+                    resp.set_ttl(ttl);
+                }
+                None => resp.set_not_found(true),
+            }
+        });
+    }
+
+    fn coprocessor_stream(
+        &mut self,
+        ctx: RpcContext,
+        _: coprocessor::Request,
+        sink: ServerStreamingSink<coprocessor::Response>,
+    ) {
+        fail_unimplemented!(ctx, sink);
+    }
+
+    fn raft(
+        &mut self,
+        ctx: RpcContext,
+        _: RequestStream<raft_serverpb::RaftMessage>,
+        sink: ClientStreamingSink<raft_serverpb::Done>,
+    ) {
+        fail_unimplemented!(ctx, sink);
+    }
+
+    fn snapshot(
+        &mut self,
+        ctx: RpcContext,
+        _: RequestStream<raft_serverpb::SnapshotChunk>,
+        sink: ClientStreamingSink<raft_serverpb::Done>,
+    ) {
+        fail_unimplemented!(ctx, sink);
+    }
+
+    fn batch_commands(
+        &mut self,
+        ctx: RpcContext,
+        _: RequestStream<tikvpb::BatchCommandsRequest>,
+        sink: DuplexSink<tikvpb::BatchCommandsResponse>,
+    ) {
+        fail_unimplemented!(ctx, sink);
+    }
+
+    unimplemented_calls! {
+        kv_import(kvrpcpb::ImportRequest) -> kvrpcpb::ImportResponse;
+        kv_scan_lock(kvrpcpb::ScanLockRequest) -> kvrpcpb::ScanLockResponse;
+        kv_gc(kvrpcpb::GCRequest) -> kvrpcpb::GCResponse;
+        kv_delete_range(kvrpcpb::DeleteRangeRequest) -> kvrpcpb::DeleteRangeResponse;
+        unsafe_destroy_range(kvrpcpb::UnsafeDestroyRangeRequest) -> kvrpcpb::UnsafeDestroyRangeResponse;
+        coprocessor(coprocessor::Request) -> coprocessor::Response;
+        split_region(kvrpcpb::SplitRegionRequest) -> kvrpcpb::SplitRegionResponse;
+        mvcc_get_by_key(kvrpcpb::MvccGetByKeyRequest) -> kvrpcpb::MvccGetByKeyResponse;
+        mvcc_get_by_start_ts(kvrpcpb::MvccGetByStartTsRequest) -> kvrpcpb::MvccGetByStartTsResponse;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp() {
+        let mut region = metapb::Region::new();
+        region.set_start_key(b"b".to_vec());
+        region.set_end_key(b"d".to_vec());
+        assert_eq!(
+            clamp(&region, b"a", b""),
+            Some((Bound::Included(&b"b"[..]), Bound::Excluded(&b"d"[..])))
+        );
+        assert_eq!(
+            clamp(&region, b"c", b"cc"),
+            Some((Bound::Included(&b"c"[..]), Bound::Excluded(&b"cc"[..])))
+        );
+        assert_eq!(clamp(&region, b"d", b""), None);
+
+        region.set_end_key(Vec::new());
+        assert_eq!(
+            clamp(&region, b"c", b""),
+            Some((Bound::Included(&b"c"[..]), Bound::Unbounded))
+        );
+    }
+
+    #[test]
+    fn test_mvcc() {
+        let mut cluster = Cluster::new();
+        let mut put = kvrpcpb::Mutation::new();
+        put.set_op(kvrpcpb::Op::Put);
+        put.set_key(b"k".to_vec());
+        put.set_value(b"v".to_vec());
+
+        assert!(cluster
+            .prewrite(&[put.clone()], &[], b"k", 10, 3000)
+            .is_empty());
+        assert!(cluster.mvcc_get(b"k", 5).unwrap().is_none());
+        assert!(cluster.mvcc_get(b"k", 15).unwrap_err().has_locked());
+        assert!(cluster.prewrite(&[put.clone()], &[], b"k", 12, 3000)[0].has_locked());
+
+        assert!(cluster.commit(&[b"k".to_vec()], 10, 20).is_none());
+        assert!(cluster.mvcc_get(b"k", 15).unwrap().is_none());
+        assert_eq!(cluster.mvcc_get(b"k", 20).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(cluster.cleanup(b"k", 10), 20);
+
+        assert!(cluster.prewrite(&[put.clone()], &[], b"k", 15, 3000)[0].has_retryable());
+        assert!(cluster
+            .prewrite(&[put.clone()], &[], b"k", 30, 3000)
+            .is_empty());
+        assert!(cluster.rollback(b"k", 30).is_none());
+        assert!(cluster
+            .commit(&[b"k".to_vec()], 30, 40)
+            .unwrap()
+            .has_abort());
+        assert_eq!(cluster.mvcc_get(b"k", 50).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_pessimistic_lock() {
+        let mut cluster = Cluster::new();
+        let mut put = kvrpcpb::Mutation::new();
+        put.set_op(kvrpcpb::Op::Put);
+        put.set_key(b"a".to_vec());
+        put.set_value(b"v".to_vec());
+
+        // Pessimistic locks do not block reads, but other transactions' locks.
+        assert!(cluster
+            .pessimistic_lock(&[&b"a"[..]], b"a", 10, 11, 3000)
+            .is_empty());
+        assert!(cluster
+            .pessimistic_lock(&[&b"b"[..]], b"b", 20, 21, 3000)
+            .is_empty());
+        assert!(cluster.mvcc_get(b"a", 30).unwrap().is_none());
+        assert!(cluster.pessimistic_lock(&[&b"b"[..]], b"a", 10, 31, 3000)[0].has_locked());
+        assert!(cluster.pessimistic_lock(&[&b"a"[..]], b"b", 20, 32, 3000)[0].has_deadlock());
+
+        // Prewriting turns the pessimistic lock into a regular one.
+        cluster.pessimistic_rollback(&[b"b".to_vec()], 20, 32);
+        assert!(cluster
+            .prewrite(&[put.clone()], &[true], b"a", 10, 3000)
+            .is_empty());
+        assert!(cluster.mvcc_get(b"a", 30).unwrap_err().has_locked());
+        assert!(cluster.commit(&[b"a".to_vec()], 10, 40).is_none());
+        assert!(cluster.pessimistic_lock(&[&b"a"[..]], b"a", 20, 33, 3000)[0].has_retryable());
+
+        put.set_key(b"b".to_vec());
+        assert!(cluster.prewrite(&[put.clone()], &[true], b"b", 20, 3000)[0].has_abort());
+    }
+}
@@ -20,15 +20,26 @@
 //!
 //! **Warning:** It is not advisable to use both raw and transactional functionality in the same keyspace.
 //!
-use crate::{rpc::RpcClient, Config, Error, Key, KeyRange, KvFuture, KvPair, Result, Value};
-use futures::{future, Async, Future, Poll};
+use crate::{
+    rpc::RpcClient, runtime::Runtime, Config, Error, Key, KeyRange, KvFuture, KvPair, Result, Value,
+};
+use futures::{
+    future,
+    prelude::*,
+    task::{Context, Poll},
+};
 use std::{
+    cmp,
     ops::{Bound, Deref},
+    pin::Pin,
     sync::Arc,
-    u32,
+    time::Duration,
+    u32, vec,
 };
 
 const MAX_RAW_KV_SCAN_LIMIT: u32 = 10240;
+/// The number of pairs a [`ScanStream`](struct.ScanStream.html) fetches per request by default.
+const DEFAULT_SCAN_BATCH_SIZE: u32 = 256;
 
 /// The TiKV raw [`Client`](struct.Client.html) is used to issue requests to the TiKV server and PD cluster.
 pub struct Client {
@@ -40,9 +51,9 @@ impl Client {
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, raw::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait();
+    /// let client = block_on(connect);
     /// ```
     #[cfg_attr(feature = "cargo-clippy", allow(clippy::new_ret_no_self))]
     pub fn new(config: Config) -> Connect {
@@ -61,12 +72,12 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Value, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let key = "TiKV";
     /// let req = connected_client.get(key);
-    /// let result: Option<Value> = req.wait().unwrap();
+    /// let result: Option<Value> = block_on(req).unwrap();
     /// ```
     pub fn get(&self, key: impl Into<Key>) -> Get {
         Get::new(self.rpc(), GetInner::new(key.into()))
@@ -79,12 +90,12 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{KvPair, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let keys = vec!["TiKV", "TiDB"];
     /// let req = connected_client.batch_get(keys);
-    /// let result: Vec<KvPair> = req.wait().unwrap();
+    /// let result: Vec<KvPair> = block_on(req).unwrap();
     /// ```
     pub fn batch_get(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> BatchGet {
         BatchGet::new(
@@ -99,13 +110,13 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Key, Value, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let key = "TiKV";
     /// let val = "TiKV";
     /// let req = connected_client.put(key, val);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn put(&self, key: impl Into<Key>, value: impl Into<Value>) -> Put {
         Put::new(self.rpc(), PutInner::new(key.into(), value.into()))
@@ -117,14 +128,14 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Error, Result, KvPair, Key, Value, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let kvpair1 = ("PD", "Go");
     /// let kvpair2 = ("TiKV", "Rust");
     /// let iterable = vec![kvpair1, kvpair2];
     /// let req = connected_client.batch_put(iterable);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn batch_put(&self, pairs: impl IntoIterator<Item = impl Into<KvPair>>) -> BatchPut {
         BatchPut::new(
@@ -133,18 +144,65 @@ impl Client {
         )
     }
 
+    /// Create a new [`GetKeyTtl`](struct.GetKeyTtl.html) request.
+    ///
+    /// Once resolved this request will result in the time the given key has left to live, or
+    /// `None` if it does not exist. Keys put without a TTL never expire, and report a zero duration.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, raw::Client};
+    /// # use futures::executor::block_on;
+    /// # use std::time::Duration;
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let key = "TiKV";
+    /// let req = connected_client.get_key_ttl(key);
+    /// let result: Option<Duration> = block_on(req).unwrap();
+    /// ```
+    pub fn get_key_ttl(&self, key: impl Into<Key>) -> GetKeyTtl {
+        GetKeyTtl::new(self.rpc(), GetKeyTtlInner::new(key.into()))
+    }
+
+    /// Create a new [`CompareAndSwap`](struct.CompareAndSwap.html) request.
+    ///
+    /// Once resolved this request will result in the setting of the value associated with the given
+    /// key to `new`, only if its current value is `expected`. `None` expects the key not to exist.
+    ///
+    /// The result is the previous value of the key, and whether the swap happened.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, Config, raw::Client};
+    /// # use futures::executor::block_on;
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let key = "TiKV";
+    /// let req = connected_client.compare_and_swap(key, None, "leader");
+    /// let (previous, swapped): (Option<Value>, bool) = block_on(req).unwrap();
+    /// ```
+    pub fn compare_and_swap(
+        &self,
+        key: impl Into<Key>,
+        expected: impl Into<Option<Value>>,
+        new: impl Into<Value>,
+    ) -> CompareAndSwap {
+        CompareAndSwap::new(
+            self.rpc(),
+            CompareAndSwapInner::new(key.into(), expected.into(), new.into()),
+        )
+    }
+
     /// Create a new [`Delete`](struct.Delete.html) request.
     ///
     /// Once resolved this request will result in the deletion of the given key.
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Key, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let key = "TiKV";
     /// let req = connected_client.delete(key);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn delete(&self, key: impl Into<Key>) -> Delete {
         Delete::new(self.rpc(), DeleteInner::new(key.into()))
@@ -156,12 +214,12 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let keys = vec!["TiKV", "TiDB"];
     /// let req = connected_client.batch_delete(keys);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn batch_delete(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> BatchDelete {
         BatchDelete::new(
@@ -176,31 +234,48 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{KvPair, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let inclusive_range = "TiKV"..="TiDB";
     /// let req = connected_client.scan(inclusive_range, 2);
-    /// let result: Vec<KvPair> = req.wait().unwrap();
+    /// let result: Vec<KvPair> = block_on(req).unwrap();
     /// ```
     pub fn scan(&self, range: impl KeyRange, limit: u32) -> Scan {
         Scan::new(self.rpc(), ScanInner::new(range.into_bounds(), limit))
     }
 
+    /// Create a new [`ScanStream`](struct.ScanStream.html) over the given range.
+    ///
+    /// Unlike [`scan`](struct.Client.html#method.scan), there is no limit: the pairs are fetched
+    /// lazily, in batches, as the stream is polled.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, raw::Client};
+    /// # use futures::{executor::block_on, future, prelude::*};
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let stream = connected_client.scan_stream("TiKV"..).batch_size(1024);
+    /// let count = block_on(stream.try_fold(0, |count, _| future::ok(count + 1))).unwrap();
+    /// ```
+    pub fn scan_stream(&self, range: impl KeyRange) -> ScanStream {
+        ScanStream::new(self.rpc(), range.into_keys())
+    }
+
     /// Create a new [`BatchScan`](struct.BatchScan.html) request.
     ///
     /// Once resolved this request will result in a set of scanners over the given keys.
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Key, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let inclusive_range1 = "TiDB"..="TiKV";
     /// let inclusive_range2 = "TiKV"..="TiSpark";
     /// let iterable = vec![inclusive_range1, inclusive_range2];
     /// let req = connected_client.batch_scan(iterable, 2);
-    /// let result = req.wait();
+    /// let result = block_on(req);
     /// ```
     pub fn batch_scan(
         &self,
@@ -222,12 +297,12 @@ impl Client {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Key, Config, raw::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
+    /// # let connected_client = block_on(connecting_client).unwrap();
     /// let inclusive_range = "TiKV"..="TiDB";
     /// let req = connected_client.delete_range(inclusive_range);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn delete_range(&self, range: impl KeyRange) -> DeleteRange {
         DeleteRange::new(self.rpc(), DeleteRangeInner::new(range.into_keys()))
@@ -240,10 +315,10 @@ impl Client {
 ///
 /// ```rust,no_run
 /// use tikv_client::{Config, raw::{Client, Connect}};
-/// use futures::Future;
+/// use futures::executor::block_on;
 ///
 /// let connect: Connect = Client::new(Config::default());
-/// let client: Client = connect.wait().unwrap();
+/// let client: Client = block_on(connect).unwrap();
 /// ```
 pub struct Connect {
     config: Config,
@@ -256,13 +331,95 @@ impl Connect {
 }
 
 impl Future for Connect {
-    type Item = Client;
-    type Error = Error;
+    type Output = Result<Client>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
         let config = &self.config;
-        let rpc = Arc::new(RpcClient::connect(config)?);
-        Ok(Async::Ready(Client { rpc }))
+        let rpc = RpcClient::connect(config).map(Arc::new);
+        Poll::Ready(rpc.map(|rpc| Client { rpc }))
+    }
+}
+
+/// A blocking wrapper around the raw [`Client`](struct.Client.html).
+///
+/// The [`SyncClient`](struct.SyncClient.html) owns the runtime its requests are driven on, so it
+/// can be used from code which has no executor of its own. Each method blocks the calling thread
+/// until the request resolves.
+///
+/// ```rust,no_run
+/// use tikv_client::{Config, Value, raw::SyncClient};
+///
+/// let client = SyncClient::new(Config::default()).unwrap();
+/// client.put("TiKV", "Rust").unwrap();
+/// let value: Option<Value> = client.get("TiKV").unwrap();
+/// ```
+pub struct SyncClient {
+    client: Client,
+    runtime: Runtime,
+}
+
+impl SyncClient {
+    /// Create a new [`SyncClient`](struct.SyncClient.html), blocking until it is connected.
+    pub fn new(config: Config) -> Result<SyncClient> {
+        let runtime = Runtime::new()?;
+        let client = runtime.block_on(Client::new(config))?;
+        Ok(SyncClient { client, runtime })
+    }
+
+    /// Fetch the value associated with the given key.
+    ///
+    /// See [`Client::get`](struct.Client.html#method.get).
+    pub fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.runtime.block_on(self.client.get(key))
+    }
+
+    /// Fetch the values associated with the given keys.
+    ///
+    /// See [`Client::batch_get`](struct.Client.html#method.batch_get).
+    pub fn batch_get(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Result<Vec<KvPair>> {
+        self.runtime.block_on(self.client.batch_get(keys))
+    }
+
+    /// Set the value associated with the given key.
+    ///
+    /// See [`Client::put`](struct.Client.html#method.put).
+    pub fn put(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.runtime.block_on(self.client.put(key, value))
+    }
+
+    /// Set the values associated with the given keys.
+    ///
+    /// See [`Client::batch_put`](struct.Client.html#method.batch_put).
+    pub fn batch_put(&self, pairs: impl IntoIterator<Item = impl Into<KvPair>>) -> Result<()> {
+        self.runtime.block_on(self.client.batch_put(pairs))
+    }
+
+    /// Delete the given key.
+    ///
+    /// See [`Client::delete`](struct.Client.html#method.delete).
+    pub fn delete(&self, key: impl Into<Key>) -> Result<()> {
+        self.runtime.block_on(self.client.delete(key))
+    }
+
+    /// Delete the given keys.
+    ///
+    /// See [`Client::batch_delete`](struct.Client.html#method.batch_delete).
+    pub fn batch_delete(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Result<()> {
+        self.runtime.block_on(self.client.batch_delete(keys))
+    }
+
+    /// Fetch up to `limit` pairs in the given range.
+    ///
+    /// See [`Client::scan`](struct.Client.html#method.scan).
+    pub fn scan(&self, range: impl KeyRange, limit: u32) -> Result<Vec<KvPair>> {
+        self.runtime.block_on(self.client.scan(range, limit))
+    }
+
+    /// Delete all keys in the given range.
+    ///
+    /// See [`Client::delete_range`](struct.Client.html#method.delete_range).
+    pub fn delete_range(&self, range: impl KeyRange) -> Result<()> {
+        self.runtime.block_on(self.client.delete_range(range))
     }
 }
 
@@ -318,6 +475,45 @@ impl Deref for ColumnFamily {
     }
 }
 
+/// A [`ReplicaRead`](struct.ReplicaRead.html) lets a read be served by a follower or learner of
+/// a region, rather than its leader.
+///
+/// Replica reads spread the load of read-heavy workloads over all the peers of a region. Followers
+/// still check with the leader that they have caught up before serving a read. Reads are spread
+/// randomly over the followers and learners, preferring the ones on stores with all the given
+/// labels. A region without any follower or learner is read from its leader.
+///
+/// ```rust,no_run
+/// # use tikv_client::{Value, Config, raw::{Client, ReplicaRead}};
+/// # use futures::executor::block_on;
+/// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+/// # let connected_client = block_on(connecting_client).unwrap();
+/// let replica_read = ReplicaRead::new().prefer_label("zone", "us-west-1a");
+/// let req = connected_client.get("TiKV").replica_read(replica_read);
+/// let result: Option<Value> = block_on(req).unwrap();
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplicaRead {
+    labels: Vec<(String, String)>,
+}
+
+impl ReplicaRead {
+    /// Read from any follower or learner.
+    pub fn new() -> Self {
+        ReplicaRead::default()
+    }
+
+    /// Prefer peers on stores with the given label, such as the zone the client runs in.
+    pub fn prefer_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    pub(crate) fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+}
+
 trait RequestInner: Sized {
     type Resp;
 
@@ -353,13 +549,13 @@ where
         }
     }
 
-    fn poll(&mut self) -> Poll<Inner::Resp, Error> {
+    fn poll(&mut self, cx: &mut Context) -> Poll<Result<Inner::Resp>> {
         if let RequestState::Uninitiated(state) = self {
             let (client, inner, cf) = state.take().unwrap();
             *self = RequestState::Initiated(inner.execute(client, cf));
         }
         match self {
-            RequestState::Initiated(ref mut future) => future.poll(),
+            RequestState::Initiated(ref mut future) => future.as_mut().poll(cx),
             _ => unreachable!(),
         }
     }
@@ -385,24 +581,36 @@ impl Get {
         self.state.cf(cf);
         self
     }
+
+    /// Allow the request to be served by a follower or learner, see
+    /// [`ReplicaRead`](struct.ReplicaRead.html).
+    pub fn replica_read(mut self, replica_read: ReplicaRead) -> Self {
+        if let Some(x) = self.state.inner_mut() {
+            x.replica_read = Some(replica_read);
+        };
+        self
+    }
 }
 
 impl Future for Get {
-    type Item = Option<Value>;
-    type Error = Error;
+    type Output = Result<Option<Value>>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
 pub(crate) struct GetInner {
     key: Key,
+    replica_read: Option<ReplicaRead>,
 }
 
 impl GetInner {
     fn new(key: Key) -> Self {
-        GetInner { key }
+        GetInner {
+            key,
+            replica_read: None,
+        }
     }
 }
 
@@ -410,7 +618,7 @@ impl RequestInner for GetInner {
     type Resp = Option<Value>;
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
-        Box::new(client.raw_get(self.key, cf))
+        Box::pin(async move { client.raw_get(self.key, cf, self.replica_read).await })
     }
 }
 
@@ -434,32 +642,44 @@ impl BatchGet {
         self.state.cf(cf);
         self
     }
+
+    /// Allow the request to be served by a follower or learner, see
+    /// [`ReplicaRead`](struct.ReplicaRead.html).
+    pub fn replica_read(mut self, replica_read: ReplicaRead) -> Self {
+        if let Some(x) = self.state.inner_mut() {
+            x.replica_read = Some(replica_read);
+        };
+        self
+    }
 }
 
 impl Future for BatchGet {
-    type Item = Vec<KvPair>;
-    type Error = Error;
+    type Output = Result<Vec<KvPair>>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
 pub(crate) struct BatchGetInner {
     keys: Vec<Key>,
+    replica_read: Option<ReplicaRead>,
 }
 
 impl RequestInner for BatchGetInner {
     type Resp = Vec<KvPair>;
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
-        Box::new(client.raw_batch_get(self.keys, cf))
+        Box::pin(async move { client.raw_batch_get(self.keys, cf, self.replica_read).await })
     }
 }
 
 impl BatchGetInner {
     fn new(keys: Vec<Key>) -> Self {
-        BatchGetInner { keys }
+        BatchGetInner {
+            keys,
+            replica_read: None,
+        }
     }
 }
 
@@ -483,25 +703,44 @@ impl Put {
         self.state.cf(cf);
         self
     }
+
+    /// Set the time after which the key expires. TiKV tracks it with a precision of seconds, so it
+    /// is rounded up to whole seconds, and to at least one.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, raw::Client};
+    /// # use futures::executor::block_on;
+    /// # use std::time::Duration;
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let req = connected_client.put("TiKV", "Fast").ttl(Duration::from_secs(60));
+    /// let result: () = block_on(req).unwrap();
+    /// ```
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        if let Some(x) = self.state.inner_mut() {
+            x.ttl = ttl_secs(ttl);
+        };
+        self
+    }
 }
 
 impl Future for Put {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
 pub(crate) struct PutInner {
     key: Key,
     value: Value,
+    ttl: u64,
 }
 
 impl PutInner {
     fn new(key: Key, value: Value) -> Self {
-        PutInner { key, value }
+        PutInner { key, value, ttl: 0 }
     }
 }
 
@@ -509,11 +748,16 @@ impl RequestInner for PutInner {
     type Resp = ();
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
-        let (key, value) = (self.key, self.value);
-        Box::new(client.raw_put(key, value, cf))
+        Box::pin(async move { client.raw_put(self.key, self.value, self.ttl, cf).await })
     }
 }
 
+/// The TTL to send TiKV for `ttl`. A zero TTL means the key never expires, so no duration may be
+/// rounded down to it.
+fn ttl_secs(ttl: Duration) -> u64 {
+    cmp::max(ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0), 1)
+}
+
 /// An unresolved [`Client::batch_put`](struct.Client.html#method.batch_put) request.
 ///
 /// Once resolved this request will result in the setting of the value associated with the given key.
@@ -533,24 +777,33 @@ impl BatchPut {
         self.state.cf(cf);
         self
     }
+
+    /// Set the time after which the keys expire. TiKV tracks it with a precision of seconds, so it
+    /// is rounded up to whole seconds, and to at least one.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        if let Some(x) = self.state.inner_mut() {
+            x.ttl = ttl_secs(ttl);
+        };
+        self
+    }
 }
 
 impl Future for BatchPut {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
 pub(crate) struct BatchPutInner {
     pairs: Vec<KvPair>,
+    ttl: u64,
 }
 
 impl BatchPutInner {
     fn new(pairs: Vec<KvPair>) -> Self {
-        BatchPutInner { pairs }
+        BatchPutInner { pairs, ttl: 0 }
     }
 }
 
@@ -558,7 +811,111 @@ impl RequestInner for BatchPutInner {
     type Resp = ();
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
-        Box::new(client.raw_batch_put(self.pairs, cf))
+        Box::pin(async move { client.raw_batch_put(self.pairs, self.ttl, cf).await })
+    }
+}
+
+/// An unresolved [`Client::get_key_ttl`](struct.Client.html#method.get_key_ttl) request.
+///
+/// Once resolved this request will result in the time the given key has left to live.
+pub struct GetKeyTtl {
+    state: RequestState<GetKeyTtlInner>,
+}
+
+impl GetKeyTtl {
+    fn new(client: Arc<RpcClient>, inner: GetKeyTtlInner) -> Self {
+        Self {
+            state: RequestState::new(client, inner),
+        }
+    }
+
+    /// Set the (optional) [`ColumnFamily`](struct.ColumnFamily.html).
+    pub fn cf(mut self, cf: impl Into<ColumnFamily>) -> Self {
+        self.state.cf(cf);
+        self
+    }
+}
+
+impl Future for GetKeyTtl {
+    type Output = Result<Option<Duration>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+pub(crate) struct GetKeyTtlInner {
+    key: Key,
+}
+
+impl GetKeyTtlInner {
+    fn new(key: Key) -> Self {
+        GetKeyTtlInner { key }
+    }
+}
+
+impl RequestInner for GetKeyTtlInner {
+    type Resp = Option<Duration>;
+
+    fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
+        Box::pin(async move {
+            let ttl = client.raw_get_key_ttl(self.key, cf).await?;
+            Ok(ttl.map(Duration::from_secs))
+        })
+    }
+}
+
+/// An unresolved [`Client::compare_and_swap`](struct.Client.html#method.compare_and_swap) request.
+///
+/// Once resolved this request will result in the previous value associated with the given key,
+/// and whether it was swapped for the new one.
+pub struct CompareAndSwap {
+    state: RequestState<CompareAndSwapInner>,
+}
+
+impl CompareAndSwap {
+    fn new(client: Arc<RpcClient>, inner: CompareAndSwapInner) -> Self {
+        Self {
+            state: RequestState::new(client, inner),
+        }
+    }
+
+    /// Set the (optional) [`ColumnFamily`](struct.ColumnFamily.html).
+    pub fn cf(mut self, cf: impl Into<ColumnFamily>) -> Self {
+        self.state.cf(cf);
+        self
+    }
+}
+
+impl Future for CompareAndSwap {
+    type Output = Result<(Option<Value>, bool)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+pub(crate) struct CompareAndSwapInner {
+    key: Key,
+    expected: Option<Value>,
+    new: Value,
+}
+
+impl CompareAndSwapInner {
+    fn new(key: Key, expected: Option<Value>, new: Value) -> Self {
+        CompareAndSwapInner { key, expected, new }
+    }
+}
+
+impl RequestInner for CompareAndSwapInner {
+    type Resp = (Option<Value>, bool);
+
+    fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
+        Box::pin(async move {
+            client
+                .raw_compare_and_swap(self.key, self.expected, self.new, cf)
+                .await
+        })
     }
 }
 
@@ -584,11 +941,10 @@ impl Delete {
 }
 
 impl Future for Delete {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
@@ -606,7 +962,7 @@ impl RequestInner for DeleteInner {
     type Resp = ();
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
-        Box::new(client.raw_delete(self.key, cf))
+        Box::pin(async move { client.raw_delete(self.key, cf).await })
     }
 }
 
@@ -632,11 +988,10 @@ impl BatchDelete {
 }
 
 impl Future for BatchDelete {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
@@ -654,7 +1009,7 @@ impl RequestInner for BatchDeleteInner {
     type Resp = ();
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
-        Box::new(client.raw_batch_delete(self.keys, cf))
+        Box::pin(async move { client.raw_batch_delete(self.keys, cf).await })
     }
 }
 
@@ -662,6 +1017,8 @@ pub(crate) struct ScanInner {
     range: (Bound<Key>, Bound<Key>),
     limit: u32,
     key_only: bool,
+    reverse: bool,
+    replica_read: Option<ReplicaRead>,
 }
 
 impl ScanInner {
@@ -670,6 +1027,8 @@ impl ScanInner {
             range,
             limit,
             key_only: false,
+            reverse: false,
+            replica_read: None,
         }
     }
 }
@@ -679,16 +1038,27 @@ impl RequestInner for ScanInner {
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
         if self.limit > MAX_RAW_KV_SCAN_LIMIT {
-            Box::new(future::err(Error::max_scan_limit_exceeded(
+            Box::pin(future::err(Error::max_scan_limit_exceeded(
                 self.limit,
                 MAX_RAW_KV_SCAN_LIMIT,
             )))
         } else {
             let keys = match self.range.into_keys() {
-                Err(e) => return Box::new(future::err(e)),
+                Err(e) => return Box::pin(future::err(e)),
                 Ok(v) => v,
             };
-            Box::new(client.raw_scan(keys, self.limit, self.key_only, cf))
+            Box::pin(async move {
+                client
+                    .raw_scan(
+                        keys,
+                        self.limit,
+                        self.key_only,
+                        self.reverse,
+                        cf,
+                        self.replica_read,
+                    )
+                    .await
+            })
         }
     }
 }
@@ -719,14 +1089,147 @@ impl Scan {
         };
         self
     }
+
+    /// Scan the range backwards, from its end, so the result is in descending order of keys.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, Config, raw::Client};
+    /// # use futures::executor::block_on;
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// // The last 10 pairs before "TiKV".
+    /// let req = connected_client.scan("TiDB".."TiKV", 10).reverse();
+    /// let result: Vec<KvPair> = block_on(req).unwrap();
+    /// ```
+    pub fn reverse(mut self) -> Self {
+        if let Some(x) = self.state.inner_mut() {
+            x.reverse = true;
+        };
+        self
+    }
+
+    /// Allow the request to be served by a follower or learner, see
+    /// [`ReplicaRead`](struct.ReplicaRead.html).
+    pub fn replica_read(mut self, replica_read: ReplicaRead) -> Self {
+        if let Some(x) = self.state.inner_mut() {
+            x.replica_read = Some(replica_read);
+        };
+        self
+    }
 }
 
 impl Future for Scan {
-    type Item = Vec<KvPair>;
-    type Error = Error;
+    type Output = Result<Vec<KvPair>>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+type ScanBatch = (Vec<KvPair>, Option<(Key, Option<Key>)>);
+
+/// A stream over the pairs of a range, created by
+/// [`Client::scan_stream`](struct.Client.html#method.scan_stream).
+///
+/// Pairs are fetched from TiKV in batches, one region at a time, as the stream is polled.
+pub struct ScanStream {
+    client: Arc<RpcClient>,
+    /// The part of the range which has not been fetched yet, if any.
+    range: Option<(Key, Option<Key>)>,
+    error: Option<Error>,
+    batch_size: u32,
+    key_only: bool,
+    cf: Option<ColumnFamily>,
+    batch: vec::IntoIter<KvPair>,
+    pending: Option<KvFuture<ScanBatch>>,
+}
+
+impl ScanStream {
+    fn new(client: Arc<RpcClient>, range: Result<(Key, Option<Key>)>) -> Self {
+        let (range, error) = match range {
+            Ok(range) => (Some(range), None),
+            Err(e) => (None, Some(e)),
+        };
+        ScanStream {
+            client,
+            range,
+            error,
+            batch_size: DEFAULT_SCAN_BATCH_SIZE,
+            key_only: false,
+            cf: None,
+            batch: Vec::new().into_iter(),
+            pending: None,
+        }
+    }
+
+    /// Set the (optional) [`ColumnFamily`](struct.ColumnFamily.html).
+    pub fn cf(mut self, cf: impl Into<ColumnFamily>) -> Self {
+        self.cf = Some(cf.into());
+        self
+    }
+
+    pub fn key_only(mut self) -> Self {
+        self.key_only = true;
+        self
+    }
+
+    /// Set the number of pairs fetched from TiKV per request.
+    ///
+    /// A batch size of zero, or above the maximum scan limit, makes the stream fail.
+    pub fn batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    fn next_batch(&self, range: (Key, Option<Key>)) -> KvFuture<ScanBatch> {
+        if self.batch_size == 0 {
+            return Box::pin(future::err(Error::zero_scan_batch_size()));
+        }
+        if self.batch_size > MAX_RAW_KV_SCAN_LIMIT {
+            return Box::pin(future::err(Error::max_scan_limit_exceeded(
+                self.batch_size,
+                MAX_RAW_KV_SCAN_LIMIT,
+            )));
+        }
+        let client = Arc::clone(&self.client);
+        let (batch_size, key_only, cf) = (self.batch_size, self.key_only, self.cf.clone());
+        Box::pin(async move {
+            client
+                .raw_scan_batch(range, batch_size, key_only, false, cf, None)
+                .await
+        })
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = Result<KvPair>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(e) = self.error.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        loop {
+            if let Some(pair) = self.batch.next() {
+                return Poll::Ready(Some(Ok(pair)));
+            }
+            if self.pending.is_none() {
+                match self.range.take() {
+                    Some(range) => self.pending = Some(self.next_batch(range)),
+                    None => return Poll::Ready(None),
+                }
+            }
+            let (pairs, rest) = match self.pending.as_mut().unwrap().as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(batch)) => batch,
+                Poll::Ready(Err(e)) => {
+                    self.pending = None;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            self.pending = None;
+            self.range = rest;
+            self.batch = pairs.into_iter();
+        }
     }
 }
 
@@ -751,20 +1254,24 @@ impl RequestInner for BatchScanInner {
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
         if self.each_limit > MAX_RAW_KV_SCAN_LIMIT {
-            Box::new(future::err(Error::max_scan_limit_exceeded(
+            Box::pin(future::err(Error::max_scan_limit_exceeded(
                 self.each_limit,
                 MAX_RAW_KV_SCAN_LIMIT,
             )))
         } else if self.ranges.iter().any(Result::is_err) {
             // All errors must be InvalidKeyRange so we can simply return a new InvalidKeyRange
-            Box::new(future::err(Error::invalid_key_range()))
+            Box::pin(future::err(Error::invalid_key_range()))
         } else {
-            Box::new(client.raw_batch_scan(
-                self.ranges.into_iter().map(Result::unwrap).collect(),
-                self.each_limit,
-                self.key_only,
-                cf,
-            ))
+            Box::pin(async move {
+                client
+                    .raw_batch_scan(
+                        self.ranges.into_iter().map(Result::unwrap).collect(),
+                        self.each_limit,
+                        self.key_only,
+                        cf,
+                    )
+                    .await
+            })
         }
     }
 }
@@ -798,11 +1305,10 @@ impl BatchScan {
 }
 
 impl Future for BatchScan {
-    type Item = Vec<KvPair>;
-    type Error = Error;
+    type Output = Result<Vec<KvPair>>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
@@ -829,11 +1335,10 @@ impl DeleteRange {
 }
 
 impl Future for DeleteRange {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.state.poll()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
     }
 }
 
@@ -852,8 +1357,8 @@ impl RequestInner for DeleteRangeInner {
 
     fn execute(self, client: Arc<RpcClient>, cf: Option<ColumnFamily>) -> KvFuture<Self::Resp> {
         match self.range {
-            Ok(range) => Box::new(client.raw_delete_range(range, cf)),
-            Err(e) => Box::new(future::err(e)),
+            Ok(range) => Box::pin(async move { client.raw_delete_range(range, cf).await }),
+            Err(e) => Box::pin(future::err(e)),
         }
     }
 }
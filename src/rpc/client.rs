@@ -11,34 +11,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-// TODO: Remove this when txn is done.
-#![allow(dead_code)]
-
 use std::{
+    cmp,
     collections::HashMap,
-    fmt,
+    fmt, iter, mem,
     ops::Deref,
     sync::{Arc, RwLock},
     time::Duration,
 };
 
-use futures::{
-    future::{self, loop_fn, Either, Loop},
-    Future,
-};
+use futures::{future, prelude::*, stream};
 use grpcio::{EnvBuilder, Environment};
 use kvproto::kvrpcpb;
 use log::*;
+use rand::Rng;
 
 use crate::{
-    raw::ColumnFamily,
+    raw::{ColumnFamily, ReplicaRead},
     rpc::{
-        pd::{PdClient, PdTimestamp, Region, RegionId, RegionVerId, Store, StoreId},
+        lock::{retry_on_lock, retry_on_lock_wait},
+        pd::{PdClient, PdTimestamp, Peer, Region, RegionId, RegionVerId, Store, StoreId},
+        region_cache::RegionCache,
+        retry::retry_on_region_error,
         security::SecurityManager,
-        tikv::KvClient,
+        tikv::{KvClient, StoreHealth},
         util::HandyRwLock,
     },
-    Config, Error, Key, KvPair, Result, Value,
+    transaction::{Mutation, Timestamp, TxnInfo},
+    Backoff, Config, Error, ErrorKind, Key, KvPair, Result, Value,
 };
 
 const CQ_COUNT: usize = 1;
@@ -47,9 +47,16 @@ const CLIENT_PREFIX: &str = "tikv-client";
 struct RpcClientInner {
     pd: Arc<PdClient>,
     tikv: Arc<RwLock<HashMap<String, Arc<KvClient>>>>,
+    health: Arc<RwLock<HashMap<StoreId, Arc<StoreHealth>>>>,
+    cache: Arc<RwLock<RegionCache>>,
     env: Arc<Environment>,
     security_mgr: Arc<SecurityManager>,
     timeout: Duration,
+    backoff: Backoff,
+    batch_concurrency: usize,
+    max_batch_keys: usize,
+    max_batch_bytes: usize,
+    lock_wait_timeout: Duration,
 }
 
 impl RpcClientInner {
@@ -75,57 +82,245 @@ impl RpcClientInner {
             &config.pd_endpoints,
             Arc::clone(&security_mgr),
             config.timeout,
+            config.tso_prefetch,
         )?);
         let tikv = Default::default();
+        let health = Default::default();
+        let cache = Default::default();
         Ok(RpcClientInner {
             pd,
             tikv,
+            health,
+            cache,
             env,
             security_mgr,
             timeout: config.timeout,
+            backoff: config.backoff.clone(),
+            batch_concurrency: cmp::max(config.batch_concurrency, 1),
+            max_batch_keys: cmp::max(config.max_batch_keys, 1),
+            max_batch_bytes: cmp::max(config.max_batch_bytes, 1),
+            lock_wait_timeout: config.lock_wait_timeout,
         })
     }
 
-    fn get_all_stores(&self) -> impl Future<Item = Vec<Store>, Error = Error> {
-        self.pd.get_all_stores()
+    async fn get_ts(&self) -> Result<PdTimestamp> {
+        self.pd.get_ts().await
     }
 
-    fn get_store_by_id(&self, id: StoreId) -> impl Future<Item = Store, Error = Error> {
-        self.pd.get_store(id)
+    async fn get_fresh_ts(&self) -> Result<PdTimestamp> {
+        self.pd.get_fresh_ts().await
     }
 
-    fn get_region(&self, key: &[u8]) -> impl Future<Item = Region, Error = Error> {
-        self.pd.get_region(key)
+    async fn load_store(&self, id: StoreId) -> Result<Store> {
+        info!("reload info for store {}", id);
+        self.pd.get_store(id).await
     }
 
-    fn get_region_by_id(&self, id: RegionId) -> impl Future<Item = Region, Error = Error> {
-        self.pd.get_region_by_id(id)
+    async fn load_region(&self, key: &Key) -> Result<Region> {
+        self.pd.get_region(key.as_ref()).await
     }
 
-    fn get_ts(&self) -> impl Future<Item = PdTimestamp, Error = Error> {
-        self.pd.get_ts()
+    async fn load_region_by_id(&self, id: RegionId) -> Result<Region> {
+        self.pd.get_region_by_id(id).await
     }
 
-    fn load_store(&self, id: StoreId) -> impl Future<Item = Store, Error = Error> {
-        info!("reload info for store {}", id);
-        self.pd.get_store(id).map(Into::into)
+    async fn locate_key(&self, key: &Key) -> Result<KeyLocation> {
+        let cached = self.cache.rl().get_region_by_key(key);
+        if let Some(region) = cached {
+            return Ok(KeyLocation::new(region));
+        }
+        let region = self.load_region(key).await?;
+        if region.leader.is_some() {
+            self.cache.wl().add_region(region.clone());
+        }
+        Ok(KeyLocation::new(region))
+    }
+
+    /// Locate the regions containing `keys`, which must be sorted.
+    ///
+    /// Returns the regions in key order, each with the number of consecutive keys it contains.
+    /// Regions missing from the cache are loaded from PD in rounds of up to `batch_concurrency`
+    /// concurrent requests, spread over the keys which are still missing.
+    async fn locate_keys(&self, keys: &[&Key]) -> Result<Vec<(KeyLocation, usize)>> {
+        // Regions without a leader are not cached, but they can still be used to group keys.
+        let mut loaded = RegionCache::default();
+        loop {
+            let mut located: Vec<(KeyLocation, usize)> = Vec::new();
+            let mut missing = Vec::new();
+            {
+                let cache = self.cache.rl();
+                for key in keys {
+                    if let Some((location, count)) = located.last_mut() {
+                        if location.contains(key) {
+                            *count += 1;
+                            continue;
+                        }
+                    }
+                    let region = cache
+                        .get_region_by_key(key)
+                        .or_else(|| loaded.get_region_by_key(key));
+                    match region {
+                        Some(region) => located.push((KeyLocation::new(region), 1)),
+                        None => missing.push(*key),
+                    }
+                }
+            }
+            if missing.is_empty() {
+                return Ok(located);
+            }
+
+            let step = (missing.len() + self.batch_concurrency - 1) / self.batch_concurrency;
+            let regions = future::try_join_all(
+                missing
+                    .into_iter()
+                    .step_by(step)
+                    .map(|key| self.load_region(key)),
+            )
+            .await?;
+            let mut cache = self.cache.wl();
+            for region in regions {
+                if region.leader.is_some() {
+                    cache.add_region(region.clone());
+                }
+                loaded.add_region(region);
+            }
+        }
+    }
+
+    /// Locate the region containing the keys right before `key`, which is exclusive as the end
+    /// of a range. An empty `key` stands for the end of the key space.
+    async fn locate_end_key(&self, key: &Key) -> Result<KeyLocation> {
+        let cached = self.cache.rl().get_region_by_end_key(key);
+        if let Some(region) = cached {
+            return Ok(KeyLocation::new(region));
+        }
+        let region = if key.is_empty() {
+            // PD can only look regions up by the keys they contain, so walk to the last one.
+            let mut start = Key::default();
+            loop {
+                let region = self.pd.get_region(&start).await?;
+                if region.end_key().is_empty() {
+                    break region;
+                }
+                start = Key::from(region.end_key().to_vec());
+            }
+        } else {
+            let region = self.load_region(key).await?;
+            if region.start_key() == &**key {
+                self.pd.get_prev_region(key).await?
+            } else {
+                region
+            }
+        };
+        if region.leader.is_some() {
+            self.cache.wl().add_region(region.clone());
+        }
+        Ok(KeyLocation::new(region))
+    }
+
+    async fn locate_region_by_id(&self, id: RegionId) -> Result<Region> {
+        let cached = self.cache.rl().get_region_by_id(id);
+        if let Some(region) = cached {
+            return Ok(region);
+        }
+        let region = self.load_region_by_id(id).await?;
+        if region.leader.is_some() {
+            self.cache.wl().add_region(region.clone());
+        }
+        Ok(region)
+    }
+
+    async fn locate_store(&self, id: StoreId) -> Result<Store> {
+        let cached = self.cache.rl().get_store(id);
+        if let Some(store) = cached {
+            return Ok(store);
+        }
+        let store = self.load_store(id).await?;
+        self.cache.wl().add_store(store.clone());
+        Ok(store)
     }
 
-    fn load_region(&self, key: &Key) -> impl Future<Item = Region, Error = Error> {
-        self.pd.get_region(key.as_ref())
+    /// Drop the cached metadata a region error from TiKV has shown to be stale.
+    ///
+    /// When TiKV names the new leader of the region, the cached region is switched to it in place
+    /// instead, which saves a round trip to PD.
+    fn on_region_error(&self, region_id: RegionId, e: Error) -> Error {
+        match e.kind() {
+            ErrorKind::NotLeader {
+                leader: Some(leader),
+                ..
+            } if self
+                .cache
+                .wl()
+                .switch_leader(region_id, leader.get_store_id()) =>
+            {
+                info!(
+                    "switch cached region {} to leader on store {}: {}",
+                    region_id,
+                    leader.get_store_id(),
+                    e
+                );
+            }
+            ErrorKind::NotLeader { .. }
+            | ErrorKind::StaleEpoch { .. }
+            | ErrorKind::RegionNotFound { .. }
+            | ErrorKind::KeyNotInRegion { .. } => {
+                info!("invalidate cached region {}: {}", region_id, e);
+                self.cache.wl().invalidate_region(region_id);
+            }
+            _ => {}
+        }
+        e
     }
 
-    fn load_region_by_id(&self, id: RegionId) -> impl Future<Item = Region, Error = Error> {
-        self.pd.get_region_by_id(id)
+    fn store_health(&self, id: StoreId) -> Arc<StoreHealth> {
+        if let Some(health) = self.health.rl().get(&id) {
+            return Arc::clone(health);
+        }
+        Arc::clone(self.health.wl().entry(id).or_insert_with(Default::default))
     }
 
-    fn locate_key(&self, key: &Key) -> impl Future<Item = KeyLocation, Error = Error> {
-        self.load_region(key).map(KeyLocation::new)
+    /// Point `region` at another voter if the store of its leader is down.
+    ///
+    /// The new peer either serves the request once it is elected, or replies with `NotLeader`.
+    fn avoid_down_leader(&self, region: &mut Region) {
+        let leader_store = match region.leader {
+            Some(ref leader) => leader.get_store_id(),
+            None => return,
+        };
+        if !self.store_health(leader_store).is_down() {
+            return;
+        }
+        let target = region
+            .get_peers()
+            .iter()
+            .filter(|peer| !peer.get_is_learner() && peer.get_store_id() != leader_store)
+            .map(|peer| peer.get_store_id())
+            .find(|&store_id| !self.store_health(store_id).is_down());
+        if let Some(store_id) = target {
+            info!(
+                "store {} of the leader of region {} is down, switch to store {}",
+                leader_store,
+                region.id(),
+                store_id
+            );
+            if region.switch_peer(store_id).is_ok() {
+                self.cache.wl().add_region(region.clone());
+            }
+        }
     }
 
     fn kv_client(&self, context: RegionContext) -> Result<(RegionContext, Arc<KvClient>)> {
-        if let Some(conn) = self.tikv.rl().get(context.address()) {
-            return Ok((context, Arc::clone(conn)));
+        let cached = self.tikv.rl().get(context.address()).cloned();
+        if let Some(conn) = cached {
+            if !conn.is_broken() {
+                return Ok((context, conn));
+            }
+            info!(
+                "evict broken connection to tikv endpoint: {:?}",
+                context.address()
+            );
+            self.tikv.wl().remove(context.address());
         };
         info!("connect to tikv endpoint: {:?}", context.address());
         let tikv = Arc::clone(&self.tikv);
@@ -134,6 +329,7 @@ impl RpcClientInner {
             context.address(),
             &self.security_mgr,
             self.timeout,
+            self.store_health(context.store.get_id()),
         )
         .map(Arc::new)
         .map(|c| {
@@ -144,6 +340,7 @@ impl RpcClientInner {
     }
 }
 
+#[derive(Clone)]
 pub struct RpcClient {
     inner: Arc<RpcClientInner>,
 }
@@ -155,90 +352,213 @@ impl RpcClient {
         })
     }
 
-    fn group_tasks_by_region<Task>(
-        &self,
-        tasks: Vec<Task>,
-    ) -> impl Future<Item = GroupedTasks<Task>, Error = Error>
+    async fn group_tasks_by_region<Task>(
+        inner: &RpcClientInner,
+        mut tasks: Vec<Task>,
+    ) -> Result<GroupedTasks<Task>>
     where
         Task: GroupingTask,
     {
-        let result: Option<GroupedTasks<Task>> = None;
-        let inner = self.inner();
-        loop_fn((0, tasks, result), move |(mut index, tasks, mut result)| {
-            if index == tasks.len() {
-                Either::A(future::ok(Loop::Break(result)))
-            } else {
-                let inner = Arc::clone(&inner);
-                Either::B(inner.locate_key(tasks[index].key()).map(move |location| {
-                    while let Some(item) = tasks.get(index) {
-                        if !location.contains(item.key()) {
-                            break;
-                        }
-                        let ver_id = location.ver_id();
-                        let item = item.clone();
-                        if let Some(ref mut grouped) = result {
-                            grouped.add(ver_id, item);
-                        } else {
-                            result = Some(GroupedTasks::new(ver_id, item));
-                        }
-                        index += 1;
-                    }
-                    if index == tasks.len() {
-                        Loop::Break(result)
-                    } else {
-                        Loop::Continue((index, tasks, result))
-                    }
-                }))
+        tasks.sort_by(|a, b| a.key().cmp(b.key()));
+        let locations = {
+            let keys = tasks.iter().map(GroupingTask::key).collect::<Vec<_>>();
+            inner.locate_keys(&keys).await?
+        };
+        let mut result: Option<GroupedTasks<Task>> = None;
+        let mut tasks = tasks.into_iter();
+        for (location, count) in locations {
+            let ver_id = location.ver_id();
+            for item in tasks.by_ref().take(count) {
+                if let Some(ref mut grouped) = result {
+                    grouped.add(ver_id.clone(), item);
+                } else {
+                    result = Some(GroupedTasks::new(ver_id.clone(), item));
+                }
             }
-        })
-        .map(|r| r.unwrap_or_default())
+        }
+        Ok(result.unwrap_or_default())
     }
 
-    fn region_context(
-        inner: Arc<RpcClientInner>,
-        key: &Key,
-    ) -> impl Future<Item = (RegionContext, Arc<KvClient>), Error = Error> {
-        let inner2 = Arc::clone(&inner);
-        inner
-            .locate_key(key)
-            .and_then(move |location| {
-                let peer = location.peer().expect("leader must exist");
-                let store_id = peer.get_store_id();
-                inner.load_store(store_id).map(|store| RegionContext {
-                    region: location.into_inner(),
-                    store,
-                })
+    /// Send the per-region requests of a batch, at most `batch_concurrency` of them at a time.
+    ///
+    /// The results are in no particular order.
+    async fn fan_out<Fut, T>(
+        inner: &RpcClientInner,
+        requests: impl IntoIterator<Item = Fut>,
+    ) -> Result<Vec<T>>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        stream::iter(requests)
+            .buffer_unordered(inner.batch_concurrency)
+            .try_collect()
+            .await
+    }
+
+    /// Split the tasks of each region into batches within the `max_batch_keys` and
+    /// `max_batch_bytes` limits.
+    fn split_batches<Task>(
+        inner: &RpcClientInner,
+        task_groups: HashMap<RegionVerId, Vec<Task>>,
+    ) -> Vec<(RegionId, Vec<Task>)>
+    where
+        Task: GroupingTask,
+    {
+        task_groups
+            .into_iter()
+            .flat_map(|(region, tasks)| {
+                split_batch(tasks, inner.max_batch_keys, inner.max_batch_bytes)
+                    .into_iter()
+                    .map(move |batch| (region.id, batch))
             })
-            .and_then(move |region| inner2.kv_client(region))
+            .collect()
+    }
+
+    /// Send `batch` with `send`. If TiKV rejects it with `RaftEntryTooLarge`, split it in half
+    /// and send each half instead, down to a single task.
+    async fn send_batch<Task, F, Fut, T>(batch: Vec<Task>, mut send: F) -> Result<Vec<T>>
+    where
+        Task: Clone,
+        F: FnMut(Vec<Task>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut results = Vec::new();
+        let mut pending = vec![batch];
+        while let Some(mut batch) = pending.pop() {
+            match send(batch.clone()).await {
+                Ok(result) => results.push(result),
+                Err(ref e) if is_raft_entry_too_large(e) && batch.len() > 1 => {
+                    warn!(
+                        "batch of {} keys is too large, splitting: {}",
+                        batch.len(),
+                        e
+                    );
+                    let rest = batch.split_off(batch.len() / 2);
+                    pending.push(rest);
+                    pending.push(batch);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(results)
+    }
+
+    async fn group_ranges_by_region(
+        inner: &RpcClientInner,
+        ranges: Vec<(Key, Option<Key>)>,
+    ) -> Result<HashMap<RegionId, Vec<ScanRange>>> {
+        let mut pending = ranges
+            .into_iter()
+            .enumerate()
+            .map(|(index, (start, end))| ScanRange { index, start, end })
+            .collect::<Vec<_>>();
+        pending.reverse();
+        let mut result: HashMap<RegionId, Vec<ScanRange>> = HashMap::new();
+        while let Some(range) = pending.pop() {
+            if range.is_empty() {
+                continue;
+            }
+            let location = inner.locate_key(&range.start).await?;
+            let (range, rest) = range.split_at_region_end(&location);
+            if let Some(rest) = rest {
+                pending.push(rest);
+            }
+            result
+                .entry(location.id())
+                .or_insert_with(Vec::new)
+                .push(range);
+        }
+        Ok(result)
+    }
+
+    async fn region_context(
+        inner: &RpcClientInner,
+        key: &Key,
+    ) -> Result<(RegionContext, Arc<KvClient>)> {
+        let mut region = inner.locate_key(key).await?.into_inner();
+        inner.avoid_down_leader(&mut region);
+        let peer = region.peer().expect("leader must exist");
+        let store = inner.locate_store(peer.get_store_id()).await?;
+        inner.kv_client(RegionContext {
+            region,
+            store,
+            peer,
+            replica_read: false,
+        })
     }
 
-    fn region_context_by_id(
-        inner: Arc<RpcClientInner>,
+    async fn region_context_by_id(
+        inner: &RpcClientInner,
         id: RegionId,
-    ) -> impl Future<Item = (RegionContext, Arc<KvClient>), Error = Error> {
-        let inner2 = Arc::clone(&inner);
-        inner
-            .load_region_by_id(id)
-            .and_then(move |region| {
-                let peer = region.peer().expect("leader must exist");
-                let store_id = peer.get_store_id();
-                inner
-                    .load_store(store_id)
-                    .map(|store| RegionContext { region, store })
-            })
-            .and_then(move |region| inner2.kv_client(region))
+    ) -> Result<(RegionContext, Arc<KvClient>)> {
+        let mut region = inner.locate_region_by_id(id).await?;
+        inner.avoid_down_leader(&mut region);
+        let peer = region.peer().expect("leader must exist");
+        let store = inner.locate_store(peer.get_store_id()).await?;
+        inner.kv_client(RegionContext {
+            region,
+            store,
+            peer,
+            replica_read: false,
+        })
+    }
+
+    /// Like `region_context_by_id`, but targets a follower or learner of the region picked
+    /// according to `replica_read`, if it has any.
+    async fn replica_context_by_id(
+        inner: &RpcClientInner,
+        id: RegionId,
+        replica_read: &ReplicaRead,
+    ) -> Result<(RegionContext, Arc<KvClient>)> {
+        let region = inner.locate_region_by_id(id).await?;
+        let replicas = region.replicas();
+        if replicas.is_empty() {
+            return Self::region_context_by_id(inner, id).await;
+        }
+        let stores = future::try_join_all(
+            replicas
+                .iter()
+                .map(|peer| inner.locate_store(peer.get_store_id())),
+        )
+        .await?;
+        let (peer, store) = select_replica(
+            replicas.into_iter().zip(stores).collect(),
+            replica_read.labels(),
+        );
+        inner.kv_client(RegionContext {
+            region,
+            store,
+            peer,
+            replica_read: true,
+        })
     }
 
-    fn raw(
-        inner: Arc<RpcClientInner>,
+    /// The context of a raw read from the region `id`, sent to a replica if `replica_read` is set.
+    async fn raw_read(
+        inner: &RpcClientInner,
+        id: RegionId,
+        cf: Option<ColumnFamily>,
+        replica_read: Option<&ReplicaRead>,
+    ) -> Result<RawContext> {
+        let (region, client) = match replica_read {
+            Some(replica_read) => Self::replica_context_by_id(inner, id, replica_read).await?,
+            None => Self::region_context_by_id(inner, id).await?,
+        };
+        Ok(RawContext::new(region, client, cf))
+    }
+
+    async fn raw(
+        inner: &RpcClientInner,
         key: &Key,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = RawContext, Error = Error> {
-        Self::region_context(inner, key).map(|(region, client)| RawContext::new(region, client, cf))
+    ) -> Result<RawContext> {
+        let (region, client) = Self::region_context(inner, key).await?;
+        Ok(RawContext::new(region, client, cf))
     }
 
-    fn txn(inner: Arc<RpcClientInner>, key: &Key) -> impl Future<Item = TxnContext, Error = Error> {
-        Self::region_context(inner, key).map(|(region, _client)| TxnContext::new(region))
+    async fn txn(inner: &RpcClientInner, key: &Key) -> Result<TxnContext> {
+        let (region, client) = Self::region_context(inner, key).await?;
+        Ok(TxnContext::new(region, client))
     }
 
     #[inline]
@@ -246,212 +566,790 @@ impl RpcClient {
         Arc::clone(&self.inner)
     }
 
-    pub fn raw_get(
+    async fn with_retry<F, Fut, T>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(Arc<RpcClientInner>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        retry_on_region_error(self.inner.backoff.clone(), || f(self.inner())).await
+    }
+
+    /// Like `with_retry`, but also resolves the locks the request runs into and resends it.
+    async fn with_lock_retry<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(Arc<RpcClientInner>) -> Fut + Clone,
+        Fut: Future<Output = Result<T>>,
+    {
+        retry_on_lock(self.clone(), self.inner.backoff.clone(), move || {
+            self.with_retry(f.clone())
+        })
+        .await
+    }
+
+    pub async fn get_timestamp(&self) -> Result<Timestamp> {
+        let ts = self.inner.get_ts().await?;
+        Ok(Timestamp::compose(ts.physical, ts.logical))
+    }
+
+    /// Get a timestamp allocated by PD after this call, never one from the prefetch window.
+    ///
+    /// Use this wherever the timestamp must be newer than everything committed so far, like
+    /// commit and `for_update_ts` timestamps.
+    pub async fn get_fresh_timestamp(&self) -> Result<Timestamp> {
+        let ts = self.inner.get_fresh_ts().await?;
+        Ok(Timestamp::compose(ts.physical, ts.logical))
+    }
+
+    pub async fn raw_get(
         &self,
         key: Key,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = Option<Value>, Error = Error> {
-        Self::raw(self.inner(), &key, cf)
-            .and_then(|context| context.client().raw_get(context, key))
-            .map(|value| if value.is_empty() { None } else { Some(value) })
+        replica_read: Option<ReplicaRead>,
+    ) -> Result<Option<Value>> {
+        let value = self
+            .with_retry(|inner| {
+                let (key, cf, replica_read) = (key.clone(), cf.clone(), replica_read.clone());
+                async move {
+                    let location = inner.locate_key(&key).await?;
+                    let region_id = location.id();
+                    let context =
+                        Self::raw_read(&inner, region_id, cf, replica_read.as_ref()).await?;
+                    context
+                        .client()
+                        .raw_get(context, key)
+                        .await
+                        .map_err(|e| inner.on_region_error(region_id, e))
+                }
+            })
+            .await?;
+        Ok(if value.is_empty() { None } else { Some(value) })
     }
 
-    pub fn raw_batch_get(
+    pub async fn raw_batch_get(
         &self,
         keys: Vec<Key>,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = Vec<KvPair>, Error = Error> {
-        let inner = self.inner();
-        self.group_tasks_by_region(keys)
-            .and_then(move |task_groups| {
-                let task_groups = task_groups.into_inner();
-                let mut tasks = Vec::with_capacity(task_groups.len());
-                for (region, keys) in task_groups.into_iter() {
-                    let inner = Arc::clone(&inner);
-                    let cf = cf.clone();
-                    let task = Self::region_context_by_id(inner, region.id)
-                        .map(|(region, client)| RawContext::new(region, client, cf))
-                        .and_then(|context| {
-                            context.client().raw_batch_get(context, keys.into_iter())
-                        });
-                    tasks.push(task);
+        replica_read: Option<ReplicaRead>,
+    ) -> Result<Vec<KvPair>> {
+        let results = self
+            .with_retry(|inner| {
+                let (keys, cf) = (keys.clone(), cf.clone());
+                let replica_read = replica_read.clone();
+                async move {
+                    let task_groups = Self::group_tasks_by_region(&inner, keys)
+                        .await?
+                        .into_inner();
+                    let batches = Self::split_batches(&inner, task_groups);
+                    let tasks = batches.into_iter().map(|(region_id, keys)| {
+                        let inner = Arc::clone(&inner);
+                        let (cf, replica_read) = (cf.clone(), replica_read.clone());
+                        async move {
+                            let context =
+                                Self::raw_read(&inner, region_id, cf, replica_read.as_ref())
+                                    .await?;
+                            context
+                                .client()
+                                .raw_batch_get(context, keys.into_iter())
+                                .await
+                                .map_err(|e| inner.on_region_error(region_id, e))
+                        }
+                    });
+                    Self::fan_out(&inner, tasks).await
                 }
-                future::join_all(tasks)
             })
-            .map(|r| r.into_iter().flat_map(|a| a.into_iter()).collect())
+            .await?;
+        // Regions were fetched in no particular order, so return the pairs in key order.
+        let mut pairs: Vec<KvPair> = results.into_iter().flatten().collect();
+        pairs.sort_by(|a, b| a.key().cmp(b.key()));
+        Ok(pairs)
     }
 
-    pub fn raw_put(
+    pub async fn raw_put(
         &self,
         key: Key,
         value: Value,
+        ttl: u64,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> Result<()> {
         if value.is_empty() {
-            Either::A(future::err(Error::empty_value()))
-        } else {
-            Either::B(
-                Self::raw(self.inner(), &key, cf)
-                    .and_then(|context| context.client().raw_put(context, key, value)),
-            )
+            return Err(Error::empty_value());
         }
+        self.with_retry(|inner| {
+            let (key, value, cf) = (key.clone(), value.clone(), cf.clone());
+            async move {
+                let context = Self::raw(&inner, &key, cf).await?;
+                let region_id = context.region_id();
+                context
+                    .client()
+                    .raw_put(context, key, value, ttl)
+                    .await
+                    .map_err(|e| inner.on_region_error(region_id, e))
+            }
+        })
+        .await
     }
 
-    pub fn raw_batch_put(
+    pub async fn raw_batch_put(
         &self,
         pairs: Vec<KvPair>,
+        ttl: u64,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> Result<()> {
         if pairs.iter().any(|p| p.value().is_empty()) {
-            Either::A(future::err(Error::empty_value()))
-        } else {
-            let inner = self.inner();
-            Either::B(
-                self.group_tasks_by_region(pairs)
-                    .and_then(move |task_groups| {
-                        let task_groups = task_groups.into_inner();
-                        let mut tasks = Vec::with_capacity(task_groups.len());
-                        for (region, pairs) in task_groups.into_iter() {
-                            let inner = Arc::clone(&inner);
-                            let cf = cf.clone();
-                            let task = Self::region_context_by_id(inner, region.id)
-                                .map(|(region, client)| RawContext::new(region, client, cf))
-                                .and_then(|context| context.client().raw_batch_put(context, pairs));
-                            tasks.push(task);
+            return Err(Error::empty_value());
+        }
+        self.with_retry(|inner| {
+            let (pairs, cf) = (pairs.clone(), cf.clone());
+            async move {
+                let task_groups = Self::group_tasks_by_region(&inner, pairs)
+                    .await?
+                    .into_inner();
+                let batches = Self::split_batches(&inner, task_groups);
+                let tasks = batches.into_iter().map(|(region_id, pairs)| {
+                    let inner = Arc::clone(&inner);
+                    let cf = cf.clone();
+                    Self::send_batch(pairs, move |pairs| {
+                        let inner = Arc::clone(&inner);
+                        let cf = cf.clone();
+                        async move {
+                            let (region, client) =
+                                Self::region_context_by_id(&inner, region_id).await?;
+                            let context = RawContext::new(region, client, cf);
+                            context
+                                .client()
+                                .raw_batch_put(context, pairs, ttl)
+                                .await
+                                .map_err(|e| inner.on_region_error(region_id, e))
                         }
-                        future::join_all(tasks)
                     })
-                    .map(|_| ()),
-            )
-        }
+                });
+                Self::fan_out(&inner, tasks).await
+            }
+        })
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn raw_get_key_ttl(&self, key: Key, cf: Option<ColumnFamily>) -> Result<Option<u64>> {
+        self.with_retry(|inner| {
+            let (key, cf) = (key.clone(), cf.clone());
+            async move {
+                let context = Self::raw(&inner, &key, cf).await?;
+                let region_id = context.region_id();
+                context
+                    .client()
+                    .raw_get_key_ttl(context, key)
+                    .await
+                    .map_err(|e| inner.on_region_error(region_id, e))
+            }
+        })
+        .await
     }
 
-    pub fn raw_delete(
+    pub async fn raw_compare_and_swap(
         &self,
         key: Key,
+        expected: Option<Value>,
+        new: Value,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = (), Error = Error> {
-        Self::raw(self.inner(), &key, cf)
-            .and_then(|context| context.client().raw_delete(context, key))
+    ) -> Result<(Option<Value>, bool)> {
+        if new.is_empty() {
+            return Err(Error::empty_value());
+        }
+        self.with_retry(|inner| {
+            let (key, expected, new, cf) = (key.clone(), expected.clone(), new.clone(), cf.clone());
+            async move {
+                let context = Self::raw(&inner, &key, cf).await?;
+                let region_id = context.region_id();
+                context
+                    .client()
+                    .raw_compare_and_swap(context, key, expected, new)
+                    .await
+                    .map_err(|e| inner.on_region_error(region_id, e))
+            }
+        })
+        .await
     }
 
-    pub fn raw_batch_delete(
-        &self,
-        keys: Vec<Key>,
-        cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = (), Error = Error> {
-        let inner = self.inner();
-        self.group_tasks_by_region(keys)
-            .and_then(move |task_groups| {
-                let task_groups = task_groups.into_inner();
-                let mut tasks = Vec::with_capacity(task_groups.len());
-                for (region, keys) in task_groups.into_iter() {
+    pub async fn raw_delete(&self, key: Key, cf: Option<ColumnFamily>) -> Result<()> {
+        self.with_retry(|inner| {
+            let (key, cf) = (key.clone(), cf.clone());
+            async move {
+                let context = Self::raw(&inner, &key, cf).await?;
+                let region_id = context.region_id();
+                context
+                    .client()
+                    .raw_delete(context, key)
+                    .await
+                    .map_err(|e| inner.on_region_error(region_id, e))
+            }
+        })
+        .await
+    }
+
+    pub async fn raw_batch_delete(&self, keys: Vec<Key>, cf: Option<ColumnFamily>) -> Result<()> {
+        self.with_retry(|inner| {
+            let (keys, cf) = (keys.clone(), cf.clone());
+            async move {
+                let task_groups = Self::group_tasks_by_region(&inner, keys)
+                    .await?
+                    .into_inner();
+                let batches = Self::split_batches(&inner, task_groups);
+                let tasks = batches.into_iter().map(|(region_id, keys)| {
                     let inner = Arc::clone(&inner);
                     let cf = cf.clone();
-                    let task = Self::region_context_by_id(inner, region.id)
-                        .map(|(region, client)| RawContext::new(region, client, cf))
-                        .and_then(|context| context.client().raw_batch_delete(context, keys));
-                    tasks.push(task);
-                }
-                future::join_all(tasks)
-            })
-            .map(|_| ())
+                    Self::send_batch(keys, move |keys| {
+                        let inner = Arc::clone(&inner);
+                        let cf = cf.clone();
+                        async move {
+                            let (region, client) =
+                                Self::region_context_by_id(&inner, region_id).await?;
+                            let context = RawContext::new(region, client, cf);
+                            context
+                                .client()
+                                .raw_batch_delete(context, keys)
+                                .await
+                                .map_err(|e| inner.on_region_error(region_id, e))
+                        }
+                    })
+                });
+                Self::fan_out(&inner, tasks).await
+            }
+        })
+        .await
+        .map(|_| ())
     }
 
-    pub fn raw_scan(
+    pub async fn raw_scan(
         &self,
         range: (Key, Option<Key>),
         limit: u32,
         key_only: bool,
+        reverse: bool,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = Vec<KvPair>, Error = Error> {
-        struct State {
-            limit: u32,
-            key_only: bool,
-            cf: Option<ColumnFamily>,
-        };
-        let scan: ScanRegionsContext<Vec<KvPair>, State> = ScanRegionsContext::new(
-            range,
-            State {
-                limit,
-                key_only,
-                cf,
-            },
-        );
-        let inner = Arc::clone(&self.inner);
-        loop_fn((inner, scan), |(inner, scan)| {
-            inner.locate_key(scan.start_key()).and_then(|location| {
-                let region = location.into_inner();
-                let cf = scan.cf.clone();
-                Self::region_context_by_id(Arc::clone(&inner), region.id)
-                    .map(|(region, client)| {
-                        (scan, region.range(), RawContext::new(region, client, cf))
-                    })
-                    .and_then(|(mut scan, region_range, context)| {
-                        let (start_key, end_key) = scan.range();
-                        context
-                            .client()
-                            .raw_scan(context, start_key, end_key, scan.limit, scan.key_only)
-                            .map(|pairs| (scan, region_range, pairs))
-                    })
-                    .map(|(mut scan, region_range, mut pairs)| {
-                        let limit = scan.limit;
-                        scan.result_mut().append(&mut pairs);
-                        if scan.result().len() as u32 >= limit {
-                            Loop::Break(scan.into_inner())
-                        } else {
-                            match scan.next(region_range) {
-                                ScanRegionsStatus::Continue => Loop::Continue((inner, scan)),
-                                ScanRegionsStatus::Break => Loop::Break(scan.into_inner()),
-                            }
-                        }
-                    })
+        replica_read: Option<ReplicaRead>,
+    ) -> Result<Vec<KvPair>> {
+        let mut result = Vec::new();
+        let mut range = Some(range);
+        while let Some(batch_range) = range.take() {
+            if result.len() as u32 >= limit {
+                break;
+            }
+            let batch_limit = limit - result.len() as u32;
+            let (mut pairs, rest) = self
+                .raw_scan_batch(
+                    batch_range,
+                    batch_limit,
+                    key_only,
+                    reverse,
+                    cf.clone(),
+                    replica_read.clone(),
+                )
+                .await?;
+            result.append(&mut pairs);
+            range = rest;
+        }
+        Ok(result)
+    }
+
+    /// Scan one batch of at most `limit` pairs of `range` from a single region, backwards if
+    /// `reverse` is set. Returns the pairs along with the part of `range` left to scan, if any.
+    pub async fn raw_scan_batch(
+        &self,
+        range: (Key, Option<Key>),
+        limit: u32,
+        key_only: bool,
+        reverse: bool,
+        cf: Option<ColumnFamily>,
+        replica_read: Option<ReplicaRead>,
+    ) -> Result<(Vec<KvPair>, Option<(Key, Option<Key>)>)> {
+        let (pairs, region_range) = self
+            .with_retry(|inner| {
+                let (start, end) = range.clone();
+                let (cf, replica_read) = (cf.clone(), replica_read.clone());
+                async move {
+                    let location = if reverse {
+                        inner
+                            .locate_end_key(&end.clone().unwrap_or_default())
+                            .await?
+                    } else {
+                        inner.locate_key(&start).await?
+                    };
+                    let context =
+                        Self::raw_read(&inner, location.id(), cf, replica_read.as_ref()).await?;
+                    let region_range = context.region.range();
+                    let region_id = context.region_id();
+                    // A reverse scan starts from the upper bound of the range.
+                    let (start_key, end_key) = if reverse {
+                        (end, Some(start))
+                    } else {
+                        (Some(start), end)
+                    };
+                    let pairs = context
+                        .client()
+                        .raw_scan(context, start_key, end_key, limit, key_only, reverse)
+                        .await
+                        .map_err(|e| inner.on_region_error(region_id, e))?;
+                    Ok((pairs, region_range))
+                }
             })
-        })
+            .await?;
+        let rest = next_scan_range(range, region_range, &pairs, limit, reverse);
+        Ok((pairs, rest))
     }
 
-    pub fn raw_batch_scan(
+    pub async fn raw_batch_scan(
         &self,
         ranges: Vec<(Key, Option<Key>)>,
-        _each_limit: u32,
-        _key_only: bool,
+        each_limit: u32,
+        key_only: bool,
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = Vec<KvPair>, Error = Error> {
-        drop(ranges);
-        drop(cf);
-        future::err(Error::unimplemented())
+    ) -> Result<Vec<KvPair>> {
+        let range_count = ranges.len();
+        let results = self
+            .with_retry(|inner| {
+                let (ranges, cf) = (ranges.clone(), cf.clone());
+                async move {
+                    let range_groups = Self::group_ranges_by_region(&inner, ranges).await?;
+                    let tasks = range_groups.into_iter().map(|(region_id, ranges)| {
+                        let inner = Arc::clone(&inner);
+                        let cf = cf.clone();
+                        async move {
+                            let (region, client) =
+                                Self::region_context_by_id(&inner, region_id).await?;
+                            let context = RawContext::new(region, client, cf);
+                            let grpc_ranges = ranges
+                                .iter()
+                                .map(|range| (Some(range.start.clone()), range.end.clone()))
+                                .collect::<Vec<_>>();
+                            let pairs = context
+                                .client()
+                                .raw_batch_scan(
+                                    context,
+                                    grpc_ranges.into_iter(),
+                                    each_limit,
+                                    key_only,
+                                )
+                                .await
+                                .map_err(|e| inner.on_region_error(region_id, e))?;
+                            let pairs = split_pairs_by_range(&ranges, pairs, each_limit);
+                            Ok(ranges.into_iter().zip(pairs).collect::<Vec<_>>())
+                        }
+                    });
+                    Self::fan_out(&inner, tasks).await
+                }
+            })
+            .await?;
+        // Regions were scanned in no particular order, so put each range back together from its
+        // pieces before truncating it to the limit.
+        let mut pieces = vec![Vec::new(); range_count];
+        for (range, pairs) in results.into_iter().flatten() {
+            pieces[range.index].push((range.start, pairs));
+        }
+        let mut result = Vec::new();
+        for mut range_pieces in pieces {
+            range_pieces.sort_by(|a, b| a.0.cmp(&b.0));
+            result.extend(
+                range_pieces
+                    .into_iter()
+                    .flat_map(|(_, pairs)| pairs.into_iter())
+                    .take(each_limit as usize),
+            );
+        }
+        Ok(result)
     }
 
-    pub fn raw_delete_range(
+    pub async fn raw_delete_range(
         &self,
         range: (Key, Option<Key>),
         cf: Option<ColumnFamily>,
-    ) -> impl Future<Item = (), Error = Error> {
-        let scan: ScanRegionsContext<(), Option<ColumnFamily>> = ScanRegionsContext::new(range, cf);
-        let inner = Arc::clone(&self.inner);
-        loop_fn((inner, scan), |(inner, scan)| {
-            inner.locate_key(scan.start_key()).and_then(|location| {
-                let region = location.into_inner();
-                let cf = scan.clone();
-                Self::region_context_by_id(Arc::clone(&inner), region.id)
-                    .map(|(region, client)| {
-                        (scan, region.range(), RawContext::new(region, client, cf))
-                    })
-                    .and_then(|(mut scan, region_range, context)| {
-                        let (start_key, end_key) = scan.range();
-                        let start_key = start_key.expect("start key must be specified");
-                        let end_key = end_key.expect("end key must be specified");
+    ) -> Result<()> {
+        self.with_retry(|inner| {
+            let mut scan: ScanRegionsContext<Option<ColumnFamily>> =
+                ScanRegionsContext::new(range.clone(), cf.clone());
+            async move {
+                loop {
+                    let location = inner.locate_key(scan.start_key()).await?;
+                    let region = location.into_inner();
+                    let (region, client) = Self::region_context_by_id(&inner, region.id).await?;
+                    let region_range = region.range();
+                    let context = RawContext::new(region, client, scan.clone());
+                    let region_id = context.region_id();
+                    let (start_key, end_key) = scan.range();
+                    let start_key = start_key.expect("start key must be specified");
+                    let end_key = end_key.expect("end key must be specified");
+                    context
+                        .client()
+                        .raw_delete_range(context, start_key, end_key)
+                        .await
+                        .map_err(|e| inner.on_region_error(region_id, e))?;
+                    if let ScanRegionsStatus::Break = scan.next(region_range) {
+                        return Ok(());
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn kv_get(&self, key: Key, version: u64) -> Result<Option<Value>> {
+        let mut resp = self
+            .with_lock_retry(|inner| {
+                let key = key.clone();
+                async move {
+                    let context = Self::txn(&inner, &key).await?;
+                    let region_id = context.region_id();
+                    context
+                        .client()
+                        .kv_get(context, version, key)
+                        .await
+                        .map_err(|e| inner.on_region_error(region_id, e))
+                }
+            })
+            .await?;
+        let value = resp.take_value();
+        Ok(if value.is_empty() {
+            None
+        } else {
+            Some(value.into())
+        })
+    }
+
+    pub async fn kv_batch_get(&self, keys: Vec<Key>, version: u64) -> Result<Vec<KvPair>> {
+        let results = self
+            .with_lock_retry(|inner| {
+                let keys = keys.clone();
+                async move {
+                    let task_groups = Self::group_tasks_by_region(&inner, keys)
+                        .await?
+                        .into_inner();
+                    let tasks = task_groups.into_iter().map(|(region, keys)| {
+                        let inner = Arc::clone(&inner);
+                        async move {
+                            let (region, client) =
+                                Self::region_context_by_id(&inner, region.id).await?;
+                            let context = TxnContext::new(region, client);
+                            let region_id = context.region_id();
+                            context
+                                .client()
+                                .kv_batch_get(context, keys.into_iter(), version)
+                                .await
+                                .map_err(|e| inner.on_region_error(region_id, e))
+                        }
+                    });
+                    Self::fan_out(&inner, tasks).await
+                }
+            })
+            .await?;
+        Ok(results
+            .into_iter()
+            .flat_map(|mut resp| resp.take_pairs().into_iter())
+            .map(|mut pair| KvPair::new(pair.take_key(), pair.take_value()))
+            .collect())
+    }
+
+    /// Scan one batch of at most `limit` pairs of `range` from a single region, backwards if
+    /// `reverse` is set. Returns the pairs along with the part of `range` left to scan, if any.
+    pub async fn kv_scan(
+        &self,
+        range: (Key, Option<Key>),
+        version: u64,
+        limit: u32,
+        key_only: bool,
+        reverse: bool,
+    ) -> Result<(Vec<KvPair>, Option<(Key, Option<Key>)>)> {
+        let (pairs, region_range) = self
+            .with_lock_retry(|inner| {
+                let (start, end) = range.clone();
+                async move {
+                    let location = if reverse {
+                        inner
+                            .locate_end_key(&end.clone().unwrap_or_default())
+                            .await?
+                    } else {
+                        inner.locate_key(&start).await?
+                    };
+                    let (region, client) =
+                        Self::region_context_by_id(&inner, location.id()).await?;
+                    let region_range = region.range();
+                    let context = TxnContext::new(region, client);
+                    let region_id = context.region_id();
+                    // A reverse scan starts from the upper bound of the range.
+                    let (start_key, end_key) = if reverse {
+                        (end.unwrap_or_default(), start)
+                    } else {
+                        (start, end.unwrap_or_default())
+                    };
+                    let mut resp = context
+                        .client()
+                        .kv_scan(
+                            context, version, start_key, end_key, limit, key_only, reverse,
+                        )
+                        .await
+                        .map_err(|e| inner.on_region_error(region_id, e))?;
+                    let pairs = resp
+                        .take_pairs()
+                        .into_iter()
+                        .map(|mut pair| KvPair::new(pair.take_key(), pair.take_value()))
+                        .collect::<Vec<_>>();
+                    Ok((pairs, region_range))
+                }
+            })
+            .await?;
+        let rest = next_scan_range(range, region_range, &pairs, limit, reverse);
+        Ok((pairs, rest))
+    }
+
+    /// Clean up the primary lock of the transaction started at `start_version`. Returns its commit
+    /// version, or 0 if it has been rolled back.
+    pub async fn kv_cleanup(&self, primary_lock: Key, start_version: u64) -> Result<u64> {
+        let resp = self
+            .with_retry(|inner| {
+                let key = primary_lock.clone();
+                async move {
+                    let context = Self::txn(&inner, &key).await?;
+                    let region_id = context.region_id();
+                    context
+                        .client()
+                        .kv_cleanup(context, key, start_version)
+                        .await
+                        .map_err(|e| inner.on_region_error(region_id, e))
+                }
+            })
+            .await?;
+        Ok(resp.get_commit_version())
+    }
+
+    /// Commit, or roll back if `commit_version` is 0, the locks left by the transaction started at
+    /// `start_version` in the region containing `key`.
+    pub async fn kv_resolve_lock(
+        &self,
+        key: Key,
+        start_version: u64,
+        commit_version: u64,
+    ) -> Result<()> {
+        self.with_retry(|inner| {
+            let key = key.clone();
+            async move {
+                let context = Self::txn(&inner, &key).await?;
+                let region_id = context.region_id();
+                let txn_info = TxnInfo {
+                    txn: start_version,
+                    status: commit_version,
+                };
+                context
+                    .client()
+                    .kv_resolve_lock(context, iter::once(txn_info), start_version, commit_version)
+                    .await
+                    .map_err(|e| inner.on_region_error(region_id, e))
+            }
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Prewrite `mutations`, each paired with whether its key holds a pessimistic lock of the
+    /// transaction. `for_update_ts` is zero for optimistic transactions.
+    pub async fn kv_prewrite(
+        &self,
+        mutations: Vec<(Mutation, bool)>,
+        primary_lock: Key,
+        start_version: u64,
+        lock_ttl: u64,
+        for_update_ts: u64,
+    ) -> Result<()> {
+        self.with_retry(|inner| {
+            let (mutations, primary_lock) = (mutations.clone(), primary_lock.clone());
+            async move {
+                let task_groups = Self::group_tasks_by_region(&inner, mutations)
+                    .await?
+                    .into_inner();
+                let tasks = task_groups.into_iter().map(|(region, mutations)| {
+                    let inner = Arc::clone(&inner);
+                    let primary_lock = primary_lock.clone();
+                    async move {
+                        let (region, client) =
+                            Self::region_context_by_id(&inner, region.id).await?;
+                        let context = TxnContext::new(region, client);
+                        let region_id = context.region_id();
                         context
                             .client()
-                            .raw_delete_range(context, start_key, end_key)
-                            .map(|_| (scan, region_range))
-                    })
-                    .map(|(mut scan, region_range)| match scan.next(region_range) {
-                        ScanRegionsStatus::Continue => Loop::Continue((inner, scan)),
-                        ScanRegionsStatus::Break => Loop::Break(()),
-                    })
-            })
+                            .kv_prewrite(
+                                context,
+                                mutations.into_iter(),
+                                primary_lock,
+                                start_version,
+                                lock_ttl,
+                                for_update_ts,
+                                false,
+                            )
+                            .await
+                            .map_err(|e| inner.on_region_error(region_id, e))
+                    }
+                });
+                Self::fan_out(&inner, tasks).await
+            }
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Acquire pessimistic locks on `keys` for the transaction started at `start_version`.
+    ///
+    /// Locks held by other transactions are waited for up to the lock wait timeout, after which
+    /// the request fails with `LockWaitTimeout`. TiKV fails it with `Deadlock` if waiting would
+    /// never end.
+    pub async fn kv_pessimistic_lock(
+        &self,
+        keys: Vec<Key>,
+        primary_lock: Key,
+        start_version: u64,
+        lock_ttl: u64,
+        for_update_ts: u64,
+    ) -> Result<()> {
+        retry_on_lock_wait(
+            self.clone(),
+            self.inner.backoff.clone(),
+            self.inner.lock_wait_timeout,
+            |wait_timeout| {
+                let (keys, primary_lock) = (&keys, &primary_lock);
+                self.with_retry(move |inner| {
+                    let (keys, primary_lock) = (keys.clone(), primary_lock.clone());
+                    async move {
+                        let task_groups = Self::group_tasks_by_region(&inner, keys)
+                            .await?
+                            .into_inner();
+                        let tasks = task_groups.into_iter().map(|(region, keys)| {
+                            let inner = Arc::clone(&inner);
+                            let primary_lock = primary_lock.clone();
+                            async move {
+                                let (region, client) =
+                                    Self::region_context_by_id(&inner, region.id).await?;
+                                let context = TxnContext::new(region, client);
+                                let region_id = context.region_id();
+                                context
+                                    .client()
+                                    .kv_pessimistic_lock(
+                                        context,
+                                        keys.into_iter(),
+                                        primary_lock,
+                                        start_version,
+                                        lock_ttl,
+                                        for_update_ts,
+                                        wait_timeout,
+                                    )
+                                    .await
+                                    .map_err(|e| inner.on_region_error(region_id, e))
+                            }
+                        });
+                        Self::fan_out(&inner, tasks).await
+                    }
+                })
+            },
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Release the pessimistic locks on `keys` which the transaction started at `start_version`
+    /// acquired up to `for_update_ts`.
+    pub async fn kv_pessimistic_rollback(
+        &self,
+        keys: Vec<Key>,
+        start_version: u64,
+        for_update_ts: u64,
+    ) -> Result<()> {
+        self.with_retry(|inner| {
+            let keys = keys.clone();
+            async move {
+                let task_groups = Self::group_tasks_by_region(&inner, keys)
+                    .await?
+                    .into_inner();
+                let tasks = task_groups.into_iter().map(|(region, keys)| {
+                    let inner = Arc::clone(&inner);
+                    async move {
+                        let (region, client) =
+                            Self::region_context_by_id(&inner, region.id).await?;
+                        let context = TxnContext::new(region, client);
+                        let region_id = context.region_id();
+                        context
+                            .client()
+                            .kv_pessimistic_rollback(
+                                context,
+                                keys.into_iter(),
+                                start_version,
+                                for_update_ts,
+                            )
+                            .await
+                            .map_err(|e| inner.on_region_error(region_id, e))
+                    }
+                });
+                Self::fan_out(&inner, tasks).await
+            }
+        })
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn kv_commit(
+        &self,
+        keys: Vec<Key>,
+        start_version: u64,
+        commit_version: u64,
+    ) -> Result<()> {
+        self.with_retry(|inner| {
+            let keys = keys.clone();
+            async move {
+                let task_groups = Self::group_tasks_by_region(&inner, keys)
+                    .await?
+                    .into_inner();
+                let tasks = task_groups.into_iter().map(|(region, keys)| {
+                    let inner = Arc::clone(&inner);
+                    async move {
+                        let (region, client) =
+                            Self::region_context_by_id(&inner, region.id).await?;
+                        let context = TxnContext::new(region, client);
+                        let region_id = context.region_id();
+                        context
+                            .client()
+                            .kv_commit(context, keys.into_iter(), start_version, commit_version)
+                            .await
+                            .map_err(|e| inner.on_region_error(region_id, e))
+                    }
+                });
+                Self::fan_out(&inner, tasks).await
+            }
+        })
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn kv_batch_rollback(&self, keys: Vec<Key>, start_version: u64) -> Result<()> {
+        self.with_retry(|inner| {
+            let keys = keys.clone();
+            async move {
+                let task_groups = Self::group_tasks_by_region(&inner, keys)
+                    .await?
+                    .into_inner();
+                let tasks = task_groups.into_iter().map(|(region, keys)| {
+                    let inner = Arc::clone(&inner);
+                    async move {
+                        let (region, client) =
+                            Self::region_context_by_id(&inner, region.id).await?;
+                        let context = TxnContext::new(region, client);
+                        let region_id = context.region_id();
+                        context
+                            .client()
+                            .kv_batch_rollback(context, keys.into_iter(), start_version)
+                            .await
+                            .map_err(|e| inner.on_region_error(region_id, e))
+                    }
+                });
+                Self::fan_out(&inner, tasks).await
+            }
         })
+        .await
+        .map(|_| ())
     }
 }
 
@@ -466,9 +1364,17 @@ impl fmt::Debug for RpcClient {
 pub struct RegionContext {
     region: Region,
     store: Store,
+    /// The peer of the region the request is sent to.
+    peer: Peer,
+    /// Whether `peer` may be a follower or learner serving a replica read.
+    replica_read: bool,
 }
 
 impl RegionContext {
+    fn id(&self) -> RegionId {
+        self.region.id()
+    }
+
     fn address(&self) -> &str {
         self.store.get_address()
     }
@@ -491,7 +1397,8 @@ impl From<RegionContext> for kvrpcpb::Context {
         let mut kvctx = kvrpcpb::Context::new();
         kvctx.set_region_id(ctx.region.id);
         kvctx.set_region_epoch(ctx.region.take_region_epoch());
-        kvctx.set_peer(ctx.region.peer().expect("leader must exist").into_inner());
+        kvctx.set_peer(ctx.peer.into_inner());
+        kvctx.set_replica_read(ctx.replica_read);
         kvctx
     }
 }
@@ -511,6 +1418,10 @@ impl RawContext {
         Arc::clone(&self.client)
     }
 
+    fn region_id(&self) -> RegionId {
+        self.region.id()
+    }
+
     pub fn into_inner(self) -> (RegionContext, Option<ColumnFamily>) {
         (self.region, self.cf)
     }
@@ -518,11 +1429,20 @@ impl RawContext {
 
 pub struct TxnContext {
     region: RegionContext,
+    client: Arc<KvClient>,
 }
 
 impl TxnContext {
-    fn new(region: RegionContext) -> Self {
-        TxnContext { region }
+    fn new(region: RegionContext, client: Arc<KvClient>) -> Self {
+        TxnContext { region, client }
+    }
+
+    fn client(&self) -> Arc<KvClient> {
+        Arc::clone(&self.client)
+    }
+
+    fn region_id(&self) -> RegionId {
+        self.region.id()
     }
 
     pub fn into_inner(self) -> RegionContext {
@@ -537,13 +1457,6 @@ impl KeyLocation {
         KeyLocation(region)
     }
 
-    fn contains(&self, key: &Key) -> bool {
-        let start = self.start_key();
-        let end = self.end_key();
-        let key: &[u8] = key.as_ref();
-        start <= key && (end < key || end.is_empty())
-    }
-
     fn into_inner(self) -> Region {
         self.0
     }
@@ -557,12 +1470,16 @@ impl Deref for KeyLocation {
     }
 }
 
-trait GroupingTask: Clone + Default + Sized {
+trait GroupingTask: Clone + Sized {
     fn key(&self) -> &Key;
+
+    /// The number of bytes the task adds to a request.
+    fn size(&self) -> usize {
+        self.key().len()
+    }
 }
 
-#[derive(Default)]
-struct GroupedTasks<Task: GroupingTask>(HashMap<RegionVerId, Vec<Task>>, RegionVerId);
+struct GroupedTasks<Task: GroupingTask>(HashMap<RegionVerId, Vec<Task>>);
 
 impl<Task> GroupedTasks<Task>
 where
@@ -570,8 +1487,8 @@ where
 {
     fn new(ver_id: RegionVerId, task: Task) -> Self {
         let mut map = HashMap::with_capacity(1);
-        map.insert(ver_id.clone(), vec![task]);
-        GroupedTasks(map, ver_id)
+        map.insert(ver_id, vec![task]);
+        GroupedTasks(map)
     }
 
     fn add(&mut self, ver_id: RegionVerId, task: Task) {
@@ -586,6 +1503,15 @@ where
     }
 }
 
+impl<Task> Default for GroupedTasks<Task>
+where
+    Task: GroupingTask,
+{
+    fn default() -> Self {
+        GroupedTasks(HashMap::new())
+    }
+}
+
 impl<Task> Deref for GroupedTasks<Task>
 where
     Task: GroupingTask,
@@ -607,6 +1533,10 @@ impl GroupingTask for KvPair {
     fn key(&self) -> &Key {
         self.key()
     }
+
+    fn size(&self) -> usize {
+        self.key().len() + self.value().len()
+    }
 }
 
 impl GroupingTask for (Key, Option<Key>) {
@@ -615,32 +1545,212 @@ impl GroupingTask for (Key, Option<Key>) {
     }
 }
 
+impl GroupingTask for Mutation {
+    fn key(&self) -> &Key {
+        self.key()
+    }
+
+    fn size(&self) -> usize {
+        self.size()
+    }
+}
+
+/// A mutation to prewrite, with whether its key holds a pessimistic lock of the transaction.
+impl GroupingTask for (Mutation, bool) {
+    fn key(&self) -> &Key {
+        self.0.key()
+    }
+
+    fn size(&self) -> usize {
+        self.0.size()
+    }
+}
+
+/// A part of one of the ranges of a batch scan.
+#[derive(Clone, Debug, PartialEq)]
+struct ScanRange {
+    index: usize,
+    start: Key,
+    end: Option<Key>,
+}
+
+impl ScanRange {
+    fn is_empty(&self) -> bool {
+        self.end.as_ref().map_or(false, |end| *end <= self.start)
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.start <= *key && self.end.as_ref().map_or(true, |end| key < end)
+    }
+
+    /// Split off the part of the range which lies after the end of `region`, if any.
+    fn split_at_region_end(self, region: &Region) -> (ScanRange, Option<ScanRange>) {
+        let region_end = region.end_key();
+        if region_end.is_empty() || self.end.as_ref().map_or(false, |end| &**end <= region_end) {
+            return (self, None);
+        }
+        let region_end = Key::from(region_end.to_vec());
+        let rest = ScanRange {
+            index: self.index,
+            start: region_end.clone(),
+            end: self.end,
+        };
+        let range = ScanRange {
+            index: self.index,
+            start: self.start,
+            end: Some(region_end),
+        };
+        (range, Some(rest))
+    }
+}
+
+/// Split `tasks` into batches of at most `max_keys` tasks and, unless a single task is larger,
+/// at most `max_bytes` bytes.
+fn split_batch<Task: GroupingTask>(
+    tasks: Vec<Task>,
+    max_keys: usize,
+    max_bytes: usize,
+) -> Vec<Vec<Task>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    for task in tasks {
+        let size = task.size();
+        if !batch.is_empty() && (batch.len() >= max_keys || batch_bytes + size > max_bytes) {
+            batches.push(mem::replace(&mut batch, Vec::new()));
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+        batch.push(task);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Pick the peer to serve a replica read among `replicas`, which must not be empty.
+///
+/// The peer is picked at random to spread the load, among the ones on stores with all of `labels`
+/// if there are any.
+fn select_replica(mut replicas: Vec<(Peer, Store)>, labels: &[(String, String)]) -> (Peer, Store) {
+    let preferred = replicas
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, store))| store_has_labels(store, labels))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let mut rng = rand::thread_rng();
+    let index = if preferred.is_empty() {
+        rng.gen_range(0, replicas.len())
+    } else {
+        preferred[rng.gen_range(0, preferred.len())]
+    };
+    replicas.swap_remove(index)
+}
+
+fn store_has_labels(store: &Store, labels: &[(String, String)]) -> bool {
+    labels.iter().all(|(key, value)| {
+        store
+            .get_labels()
+            .iter()
+            .any(|label| label.get_key() == key && label.get_value() == value)
+    })
+}
+
+fn is_raft_entry_too_large(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::RaftEntryTooLarge { .. } => true,
+        _ => false,
+    }
+}
+
+/// Attribute the pairs returned by a `RawBatchScan` to the ranges which were sent with it.
+///
+/// TiKV returns the pairs of every range one after another, each in ascending order and at most
+/// `each_limit` of them. So a pair starts the next range once it can not belong to the current one.
+fn split_pairs_by_range(
+    ranges: &[ScanRange],
+    pairs: Vec<KvPair>,
+    each_limit: u32,
+) -> Vec<Vec<KvPair>> {
+    let mut result = vec![Vec::new(); ranges.len()];
+    let mut current = 0;
+    for pair in pairs {
+        while current < ranges.len() {
+            let group: &Vec<KvPair> = &result[current];
+            if group.len() < each_limit as usize
+                && ranges[current].contains(pair.key())
+                && group.last().map_or(true, |last| last.key() < pair.key())
+            {
+                break;
+            }
+            current += 1;
+        }
+        if current == ranges.len() {
+            warn!("unexpected pair in batch scan response: {:?}", pair);
+            break;
+        }
+        result[current].push(pair);
+    }
+    result
+}
+
+/// Compute the part of `range` left to scan after a batch of `limit` pairs was requested from the
+/// region covering `region_range`.
+///
+/// A full batch may have stopped in the middle of the region, so the scan goes on right after the
+/// last pair. Otherwise the region is done and the scan goes on with the next one, if the range
+/// extends past it.
+fn next_scan_range(
+    range: (Key, Option<Key>),
+    region_range: (Key, Key),
+    pairs: &[KvPair],
+    limit: u32,
+    reverse: bool,
+) -> Option<(Key, Option<Key>)> {
+    let (mut start, mut end) = range;
+    let (region_start, region_end) = region_range;
+    let last = pairs.last().filter(|_| pairs.len() >= limit as usize);
+    if reverse {
+        if let Some(last) = last {
+            end = Some(last.key().clone());
+        } else if region_start.is_empty() || region_start <= start {
+            return None;
+        } else {
+            end = Some(region_start);
+        }
+    } else if let Some(last) = last {
+        start = last.key().clone();
+        start.push(0);
+    } else if region_end.is_empty() || end.as_ref().map_or(false, |end| *end <= region_end) {
+        return None;
+    } else {
+        start = region_end;
+    }
+    if end.as_ref().map_or(false, |end| *end <= start) {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
 enum ScanRegionsStatus {
     Continue,
     Break,
 }
 
-struct ScanRegionsContext<Res, State>
-where
-    Res: Default,
-    State: Sized,
-{
+struct ScanRegionsContext<State> {
     start_key: Option<Key>,
     end_key: Option<Key>,
-    result: Res,
     state: State,
 }
 
-impl<Res, State> ScanRegionsContext<Res, State>
-where
-    Res: Default,
-    State: Sized,
-{
+impl<State> ScanRegionsContext<State> {
     fn new(range: (Key, Option<Key>), state: State) -> Self {
         ScanRegionsContext {
             start_key: Some(range.0),
             end_key: range.1,
-            result: Res::default(),
             state,
         }
     }
@@ -667,28 +1777,203 @@ where
         self.start_key = Some(region_range.1);
         ScanRegionsStatus::Continue
     }
+}
+
+impl<State> Deref for ScanRegionsContext<State> {
+    type Target = State;
 
-    fn into_inner(self) -> Res {
-        self.result
+    fn deref(&self) -> &Self::Target {
+        &self.state
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvproto::metapb;
 
-    fn result_mut(&mut self) -> &mut Res {
-        &mut self.result
+    fn range(index: usize, start: &'static str, end: Option<&'static str>) -> ScanRange {
+        ScanRange {
+            index,
+            start: start.into(),
+            end: end.map(Into::into),
+        }
     }
 
-    fn result(&self) -> &Res {
-        &self.result
+    fn pairs(keys: &[&'static str]) -> Vec<KvPair> {
+        keys.iter().map(|k| KvPair::new(*k, *k)).collect()
     }
-}
 
-impl<Res, State> Deref for ScanRegionsContext<Res, State>
-where
-    Res: Default,
-    State: Sized,
-{
-    type Target = State;
+    fn replica(id: u64, labels: &[(&str, &str)]) -> (Peer, Store) {
+        let mut peer = metapb::Peer::new();
+        peer.set_id(id);
+        peer.set_store_id(id);
+        let mut store = metapb::Store::new();
+        store.set_id(id);
+        store.set_labels(
+            labels
+                .iter()
+                .map(|(key, value)| {
+                    let mut label = metapb::StoreLabel::new();
+                    label.set_key(key.to_string());
+                    label.set_value(value.to_string());
+                    label
+                })
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        (peer.into(), store.into())
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.state
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_timeout_keeps_connection() {
+        use crate::mock::MockCluster;
+        use futures::executor::block_on;
+
+        let cluster = MockCluster::start().unwrap();
+        let store = cluster.store_ids()[0];
+        let client =
+            RpcClient::connect(&cluster.config().timeout(Duration::from_millis(200))).unwrap();
+        block_on(client.raw_put("k1".into(), "v1".into(), 0, None)).unwrap();
+
+        // A request which timed out may have been applied, so it is neither retried nor taken as
+        // a sign of a broken connection.
+        cluster.set_store_delay(store, Duration::from_millis(500));
+        let sent = cluster.request_count(store);
+        let e = block_on(client.raw_get("k1".into(), None, None)).unwrap_err();
+        assert!(e.is_deadline_exceeded());
+        assert_eq!(cluster.request_count(store), sent + 1);
+        assert!(client
+            .inner
+            .tikv
+            .rl()
+            .values()
+            .all(|conn| !conn.is_broken()));
+
+        cluster.set_store_delay(store, Duration::from_millis(0));
+        assert_eq!(
+            block_on(client.raw_get("k1".into(), None, None)).unwrap(),
+            Some(Value::from("v1"))
+        );
+    }
+
+    #[test]
+    fn test_select_replica() {
+        let replicas = vec![
+            replica(1, &[("zone", "a")]),
+            replica(2, &[("zone", "b"), ("host", "x")]),
+            replica(3, &[("zone", "b"), ("host", "y")]),
+        ];
+        let labels = |labels: &[(&str, &str)]| -> Vec<(String, String)> {
+            labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        for _ in 0..10 {
+            let (peer, _) = select_replica(replicas.clone(), &labels(&[("zone", "a")]));
+            assert_eq!(peer.get_id(), 1);
+            let (peer, _) = select_replica(replicas.clone(), &labels(&[("zone", "b")]));
+            assert!(peer.get_id() == 2 || peer.get_id() == 3);
+            let (peer, _) =
+                select_replica(replicas.clone(), &labels(&[("zone", "b"), ("host", "y")]));
+            assert_eq!(peer.get_id(), 3);
+            // Without any matching store, any replica may be picked.
+            let (peer, _) = select_replica(replicas.clone(), &labels(&[("zone", "c")]));
+            assert!(peer.get_id() >= 1 && peer.get_id() <= 3);
+        }
+    }
+
+    #[test]
+    fn test_split_batch() {
+        let sizes =
+            |batches: Vec<Vec<KvPair>>| -> Vec<usize> { batches.iter().map(Vec::len).collect() };
+        let tasks = pairs(&["a", "b", "c", "d", "e"]);
+        assert_eq!(sizes(split_batch(tasks.clone(), 2, 100)), vec![2, 2, 1]);
+        // Each pair is two bytes.
+        assert_eq!(sizes(split_batch(tasks.clone(), 10, 6)), vec![3, 2]);
+        assert_eq!(sizes(split_batch(tasks.clone(), 10, 1)), vec![1; 5]);
+        assert_eq!(sizes(split_batch(tasks, 10, 100)), vec![5]);
+        assert!(split_batch(Vec::<KvPair>::new(), 10, 100).is_empty());
+    }
+
+    #[test]
+    fn test_split_pairs_by_range() {
+        let ranges = vec![
+            range(0, "a", Some("m")),
+            range(1, "a", Some("c")),
+            range(2, "k", Some("n")),
+            range(3, "x", None),
+        ];
+        let result = split_pairs_by_range(
+            &ranges,
+            pairs(&["a", "b", "k", "a", "b", "k", "l", "m", "y"]),
+            3,
+        );
+        assert_eq!(
+            result,
+            vec![
+                pairs(&["a", "b", "k"]),
+                pairs(&["a", "b"]),
+                pairs(&["k", "l", "m"]),
+                pairs(&["y"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_scan_range() {
+        let region = || (Key::from("b"), Key::from("f"));
+        let next =
+            |start: &'static str, end: Option<&'static str>, keys: &[&'static str], reverse| {
+                next_scan_range(
+                    (start.into(), end.map(Into::into)),
+                    region(),
+                    &pairs(keys),
+                    2,
+                    reverse,
+                )
+            };
+
+        assert_eq!(
+            next("a", None, &["c", "d"], false),
+            Some((Key::from(b"d\0".to_vec()), None))
+        );
+        assert_eq!(next("a", None, &["c"], false), Some(("f".into(), None)));
+        assert_eq!(next("a", Some("f"), &["c"], false), None);
+        assert_eq!(next("a", Some("d\0"), &["c", "d"], false), None);
+
+        assert_eq!(
+            next("a", Some("z"), &["e", "d"], true),
+            Some(("a".into(), Some("d".into())))
+        );
+        assert_eq!(
+            next("a", Some("z"), &["e"], true),
+            Some(("a".into(), Some("b".into())))
+        );
+        assert_eq!(next("b", Some("z"), &["e"], true), None);
+    }
+
+    #[test]
+    fn test_split_at_region_end() {
+        let mut meta = kvproto::metapb::Region::new();
+        meta.set_start_key(b"a".to_vec());
+        meta.set_end_key(b"f".to_vec());
+        let region = Region::new(meta, None);
+
+        assert_eq!(
+            range(0, "b", Some("e")).split_at_region_end(&region),
+            (range(0, "b", Some("e")), None)
+        );
+        assert_eq!(
+            range(1, "b", Some("g")).split_at_region_end(&region),
+            (range(1, "b", Some("f")), Some(range(1, "f", Some("g"))))
+        );
+        assert_eq!(
+            range(2, "b", None).split_at_region_end(&region),
+            (range(2, "b", Some("f")), Some(range(2, "f", None)))
+        );
+        assert!(range(3, "c", Some("c")).is_empty());
     }
 }
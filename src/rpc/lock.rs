@@ -0,0 +1,176 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use kvproto::kvrpcpb;
+use log::*;
+
+use crate::{
+    rpc::{
+        client::RpcClient,
+        retry::{limit_delay, sleep},
+    },
+    transaction::Timestamp,
+    Backoff, Error, ErrorKind, Key, Result,
+};
+
+/// Returns the lock a transactional request ran into, if that is why it failed.
+pub fn lock_of(e: &Error) -> Option<kvrpcpb::LockInfo> {
+    match e.kind() {
+        ErrorKind::KeyError(err) if err.has_locked() => Some(err.get_locked().clone()),
+        _ => None,
+    }
+}
+
+/// Resolve `lock` according to the status of the transaction which left it.
+///
+/// A lock whose TTL has not run out yet, compared to a fresh timestamp from PD, is left alone: its
+/// transaction may still be alive. Otherwise the primary lock of that transaction is cleaned up
+/// first, which either finds its commit timestamp or rolls it back. The locks the transaction left
+/// in the region of `lock` are then committed or rolled back the same way. Returns whether the lock
+/// is gone.
+pub async fn resolve_lock(rpc: &RpcClient, lock: kvrpcpb::LockInfo) -> Result<bool> {
+    let lock_version = lock.get_lock_version();
+    let current = rpc.get_fresh_timestamp().await?;
+    let expire_physical = Timestamp::from(lock_version)
+        .physical()
+        .saturating_add(lock.get_lock_ttl() as i64);
+    if expire_physical > current.physical() {
+        return Ok(false);
+    }
+    let key = Key::from(lock.get_key().to_vec());
+    let commit_version = rpc
+        .kv_cleanup(lock.get_primary_lock().to_vec().into(), lock_version)
+        .await?;
+    info!(
+        "resolve locks of transaction {} with commit version {}",
+        lock_version, commit_version
+    );
+    rpc.kv_resolve_lock(key, lock_version, commit_version)
+        .await?;
+    Ok(true)
+}
+
+/// Resolve the request built by `f`, resolving the lock it runs into and resending it, as long as
+/// `backoff` allows. Locks which can not be resolved yet are waited for.
+pub async fn retry_on_lock<F, Fut, T>(rpc: RpcClient, backoff: Backoff, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let (e, lock) = match f().await {
+            Ok(resp) => return Ok(resp),
+            Err(e) => match lock_of(&e) {
+                Some(lock) => (e, lock),
+                None => return Err(e),
+            },
+        };
+        // Retry right away once the lock is gone, otherwise give it time to expire.
+        let delay = if resolve_lock(&rpc, lock).await? {
+            Duration::from_millis(0)
+        } else {
+            backoff.delay(attempt)
+        };
+        match limit_delay(&backoff, delay, attempt, start.elapsed()) {
+            Some(delay) => {
+                warn!(
+                    "request ran into a lock, retry {} after {:?}: {}",
+                    attempt + 1,
+                    delay,
+                    e
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            None => return Err(e),
+        }
+    }
+}
+
+/// Resolve the pessimistic lock request built by `f`, which is given how much longer it may wait
+/// for the locks of other transactions. Expired locks are resolved, and the request is resent
+/// until it gets the locks or `wait_timeout` has passed since the first attempt.
+pub async fn retry_on_lock_wait<F, Fut, T>(
+    rpc: RpcClient,
+    backoff: Backoff,
+    wait_timeout: Duration,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let remaining = wait_timeout
+            .checked_sub(start.elapsed())
+            .unwrap_or_default();
+        let lock = match f(remaining).await {
+            Ok(resp) => return Ok(resp),
+            Err(e) => match lock_of(&e) {
+                Some(lock) => lock,
+                None => return Err(e),
+            },
+        };
+        if resolve_lock(&rpc, lock.clone()).await? {
+            continue;
+        }
+        let delay = backoff.delay(attempt);
+        if start.elapsed() + delay >= wait_timeout {
+            return Err(Error::lock_wait_timeout(lock));
+        }
+        debug!(
+            "waiting for the lock of transaction {}, retry {} after {:?}",
+            lock.get_lock_version(),
+            attempt + 1,
+            delay
+        );
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_of() {
+        let mut lock = kvrpcpb::LockInfo::new();
+        lock.set_primary_lock(b"primary".to_vec());
+        lock.set_lock_version(42);
+        let mut err = kvrpcpb::KeyError::new();
+        err.set_locked(lock.clone());
+        assert_eq!(lock_of(&Error::from(err)), Some(lock));
+
+        let mut err = kvrpcpb::KeyError::new();
+        err.set_abort("aborted".to_owned());
+        assert_eq!(lock_of(&Error::from(err)), None);
+        assert_eq!(lock_of(&Error::empty_value()), None);
+
+        let mut err = kvrpcpb::KeyError::new();
+        err.mut_deadlock().set_lock_ts(42);
+        let err = Error::from(err);
+        assert_eq!(lock_of(&err), None);
+        match err.kind() {
+            ErrorKind::Deadlock { lock_ts, .. } => assert_eq!(*lock_ts, 42),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+}
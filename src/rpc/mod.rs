@@ -15,7 +15,10 @@
 mod util;
 mod client;
 mod context;
+mod lock;
 mod pd;
+mod region_cache;
+mod retry;
 mod security;
 mod tikv;
 
@@ -17,7 +17,7 @@ use std::{
     time::Duration,
 };
 
-use futures::Future;
+use futures::{compat::Future01CompatExt, prelude::*};
 use grpcio::{CallOption, Environment};
 use kvproto::{metapb, pdpb, pdpb_grpc::PdClient as RpcClient};
 
@@ -71,8 +71,9 @@ impl PdClient {
         endpoints: &[String],
         security_mgr: Arc<SecurityManager>,
         timeout: Duration,
+        tso_prefetch: u32,
     ) -> Result<PdClient> {
-        let leader = LeaderClient::connect(env, endpoints, security_mgr, timeout)?;
+        let leader = LeaderClient::connect(env, endpoints, security_mgr, timeout, tso_prefetch)?;
         let cluster_id = leader.rl().cluster_id();
 
         Ok(PdClient {
@@ -86,66 +87,91 @@ impl PdClient {
         self.leader.rl().members.get_leader().clone()
     }
 
-    fn get_region_and_leader(
+    async fn get_region_and_leader(
         &self,
         key: &[u8],
-    ) -> impl Future<Item = (metapb::Region, Option<metapb::Peer>), Error = Error> {
+    ) -> Result<(metapb::Region, Option<metapb::Peer>)> {
         let mut req = pd_request!(self.cluster_id, pdpb::GetRegionRequest);
         req.set_region_key(key.to_owned());
-        let key = req.get_region_key().to_owned();
-
-        self.execute(request_context(
-            "get_region",
-            move |cli: &RpcClient, opt: _| cli.get_region_async_opt(&req, opt),
-        ))
-        .and_then(move |mut resp| {
-            let region = if resp.has_region() {
-                resp.take_region()
-            } else {
-                Err(Error::region_for_key_not_found(key))?
-            };
-            let leader = if resp.has_leader() {
-                Some(resp.take_leader())
-            } else {
-                None
-            };
-            Ok((region, leader))
-        })
+
+        let mut resp = self
+            .execute(request_context(
+                "get_region",
+                move |cli: &RpcClient, opt: _| cli.get_region_async_opt(&req, opt),
+            ))
+            .await?;
+        let region = if resp.has_region() {
+            resp.take_region()
+        } else {
+            return Err(Error::region_for_key_not_found(key.to_owned()));
+        };
+        let leader = if resp.has_leader() {
+            Some(resp.take_leader())
+        } else {
+            None
+        };
+        Ok((region, leader))
+    }
+
+    async fn get_prev_region_and_leader(
+        &self,
+        key: &[u8],
+    ) -> Result<(metapb::Region, Option<metapb::Peer>)> {
+        let mut req = pd_request!(self.cluster_id, pdpb::GetRegionRequest);
+        req.set_region_key(key.to_owned());
+
+        let mut resp = self
+            .execute(request_context(
+                "get_prev_region",
+                move |cli: &RpcClient, opt: _| cli.get_prev_region_async_opt(&req, opt),
+            ))
+            .await?;
+        let region = if resp.has_region() {
+            resp.take_region()
+        } else {
+            return Err(Error::region_for_key_not_found(key.to_owned()));
+        };
+        let leader = if resp.has_leader() {
+            Some(resp.take_leader())
+        } else {
+            None
+        };
+        Ok((region, leader))
     }
 
-    fn get_region_and_leader_by_id(
+    async fn get_region_and_leader_by_id(
         &self,
         region_id: u64,
-    ) -> impl Future<Item = (metapb::Region, Option<metapb::Peer>), Error = Error> {
+    ) -> Result<(metapb::Region, Option<metapb::Peer>)> {
         let mut req = pd_request!(self.cluster_id, pdpb::GetRegionByIDRequest);
         req.set_region_id(region_id);
 
-        self.execute(request_context(
-            "get_region_by_id",
-            move |cli: &RpcClient, opt: _| cli.get_region_by_id_async_opt(&req, opt),
-        ))
-        .and_then(move |mut resp| {
-            let region = if resp.has_region() {
-                resp.take_region()
-            } else {
-                Err(Error::region_not_found(region_id, None))?
-            };
-            let leader = if resp.has_leader() {
-                Some(resp.take_leader())
-            } else {
-                None
-            };
-            Ok((region, leader))
-        })
+        let mut resp = self
+            .execute(request_context(
+                "get_region_by_id",
+                move |cli: &RpcClient, opt: _| cli.get_region_by_id_async_opt(&req, opt),
+            ))
+            .await?;
+        let region = if resp.has_region() {
+            resp.take_region()
+        } else {
+            return Err(Error::region_not_found(region_id, None));
+        };
+        let leader = if resp.has_leader() {
+            Some(resp.take_leader())
+        } else {
+            None
+        };
+        Ok((region, leader))
     }
 
-    fn execute<Resp, Executor, RpcFuture>(
+    async fn execute<Resp, Executor, RpcFuture>(
         &self,
         mut context: PdRequestContext<Executor>,
-    ) -> impl Future<Item = Resp, Error = Error>
+    ) -> Result<Resp>
     where
         Resp: PdResponse + Send + fmt::Debug + 'static,
-        RpcFuture: Future<Item = Resp, Error = ::grpcio::Error> + Send + 'static,
+        RpcFuture: futures01::Future<Item = Resp, Error = ::grpcio::Error> + Send + 'static,
         Executor: FnMut(&RpcClient, CallOption) -> ::grpcio::Result<RpcFuture> + Send + 'static,
     {
         let timeout = self.timeout;
@@ -155,8 +181,9 @@ impl PdClient {
             let cli = &cli.rl().client;
             executor(cli, option)
                 .unwrap()
-                .map_err(Into::into)
-                .and_then(|r| {
+                .compat()
+                .map(|r| -> Result<Resp> {
+                    let r = r?;
                     {
                         let header = r.header();
                         if header.has_error() {
@@ -166,49 +193,66 @@ impl PdClient {
                     Ok(r)
                 })
         };
-        Request::new(
+        let r = Request::new(
             wrapper,
             Arc::clone(&self.leader),
             LeaderClient::reconnect,
             LEADER_CHANGE_RETRY,
         )
         .execute()
-        .then(move |r| context.done(r))
+        .await;
+        context.done(r)
     }
 
-    pub fn get_all_stores(&self) -> impl Future<Item = Vec<Store>, Error = Error> {
+    pub async fn get_all_stores(&self) -> Result<Vec<Store>> {
         let req = pd_request!(self.cluster_id, pdpb::GetAllStoresRequest);
 
-        self.execute(request_context(
-            "get_all_stores",
-            move |cli: &RpcClient, opt: _| cli.get_all_stores_async_opt(&req, opt),
-        ))
-        .map(|mut resp| resp.take_stores().into_iter().map(Into::into).collect())
+        let mut resp = self
+            .execute(request_context(
+                "get_all_stores",
+                move |cli: &RpcClient, opt: _| cli.get_all_stores_async_opt(&req, opt),
+            ))
+            .await?;
+        Ok(resp.take_stores().into_iter().map(Into::into).collect())
     }
 
-    pub fn get_store(&self, store_id: StoreId) -> impl Future<Item = Store, Error = Error> {
+    pub async fn get_store(&self, store_id: StoreId) -> Result<Store> {
         let mut req = pd_request!(self.cluster_id, pdpb::GetStoreRequest);
         req.set_store_id(store_id);
 
-        self.execute(request_context(
-            "get_store",
-            move |cli: &RpcClient, opt: _| cli.get_store_async_opt(&req, opt),
-        ))
-        .map(|mut resp| resp.take_store().into())
+        let mut resp = self
+            .execute(request_context(
+                "get_store",
+                move |cli: &RpcClient, opt: _| cli.get_store_async_opt(&req, opt),
+            ))
+            .await?;
+        Ok(resp.take_store().into())
+    }
+
+    pub async fn get_region(&self, key: &[u8]) -> Result<Region> {
+        let (region, leader) = self.get_region_and_leader(key).await?;
+        Ok(Region::new(region, leader))
+    }
+
+    /// Get the region right before the one containing `key`.
+    pub async fn get_prev_region(&self, key: &[u8]) -> Result<Region> {
+        let (region, leader) = self.get_prev_region_and_leader(key).await?;
+        Ok(Region::new(region, leader))
     }
 
-    pub fn get_region(&self, key: &[u8]) -> impl Future<Item = Region, Error = Error> {
-        self.get_region_and_leader(key)
-            .map(|x| Region::new(x.0, x.1))
+    pub async fn get_region_by_id(&self, id: RegionId) -> Result<Region> {
+        let (region, leader) = self.get_region_and_leader_by_id(id).await?;
+        Ok(Region::new(region, leader))
     }
 
-    pub fn get_region_by_id(&self, id: RegionId) -> impl Future<Item = Region, Error = Error> {
-        self.get_region_and_leader_by_id(id)
-            .map(|x| Region::new(x.0, x.1))
+    pub async fn get_ts(&self) -> Result<PdTimestamp> {
+        let ts = self.leader.wl().get_ts();
+        ts.await
     }
 
-    pub fn get_ts(&self) -> impl Future<Item = PdTimestamp, Error = Error> {
-        self.leader.wl().get_ts()
+    pub async fn get_fresh_ts(&self) -> Result<PdTimestamp> {
+        let ts = self.leader.wl().get_fresh_ts();
+        ts.await
     }
 }
 
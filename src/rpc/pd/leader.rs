@@ -12,28 +12,34 @@
 // limitations under the License.
 
 use std::{
-    sync::{Arc, RwLock},
+    mem,
+    sync::{Arc, RwLock, Weak},
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
 use futures::{
-    sync::{
+    channel::{
         mpsc::{channel, unbounded, Receiver, Sender, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
-    Future, Sink, Stream,
+    compat::{Sink01CompatExt, Stream01CompatExt},
+    executor::{LocalPool, LocalSpawner},
+    future,
+    prelude::*,
+    task::LocalSpawnExt,
 };
 use fxhash::FxHashSet as HashSet;
 use grpcio::{CallOption, Environment, WriteFlags};
 use kvproto::{pdpb, pdpb_grpc};
 use log::*;
-use tokio_core::reactor::{Core, Handle as OtherHandle};
 
 use crate::{
     rpc::{
         pd::{
             context::{observe_tso_batch, request_context},
+            request::RECONNECT_INTERVAL_SEC,
+            tso::TsoAllocator,
             PdTimestamp,
         },
         security::SecurityManager,
@@ -52,12 +58,15 @@ macro_rules! pd_request {
     }};
 }
 
-type TsoChannel = oneshot::Sender<PdTimestamp>;
+type TsoChannel = oneshot::Sender<Result<PdTimestamp>>;
+
+/// How often the PD membership is refreshed in the background, when no request asks for it.
+const MEMBERS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
 
 enum PdTask {
     Init,
     Request,
-    Response(Vec<oneshot::Sender<PdTimestamp>>, pdpb::TsoResponse),
+    Response(Vec<TsoChannel>, pdpb::TsoResponse),
 }
 
 struct PdReactor {
@@ -69,6 +78,9 @@ struct PdReactor {
     tso_pending: Option<Vec<TsoChannel>>,
     tso_buffer: Option<Vec<TsoChannel>>,
     tso_batch: Vec<TsoChannel>,
+    tso: TsoAllocator,
+    /// Counts the TSO streams opened, so responses and errors of a replaced one are ignored.
+    tso_generation: u64,
 }
 
 impl Drop for PdReactor {
@@ -80,7 +92,7 @@ impl Drop for PdReactor {
 }
 
 impl PdReactor {
-    fn new() -> Self {
+    fn new(tso_prefetch: u32) -> Self {
         let (tso_tx, tso_rx) = channel(1);
         PdReactor {
             task_tx: None,
@@ -90,6 +102,8 @@ impl PdReactor {
             tso_buffer: Some(Vec::with_capacity(8)),
             tso_batch: Vec::with_capacity(8),
             tso_pending: None,
+            tso: TsoAllocator::new(tso_prefetch),
+            tso_generation: 0,
         }
     }
 
@@ -107,10 +121,20 @@ impl PdReactor {
             )
         } else {
             warn!("tso sender and receiver are stale, refreshing...");
+            self.tso.reset();
+            self.tso_generation += 1;
             let (tso_tx, tso_rx) = channel(1);
             self.tso_tx = tso_tx;
             self.tso_rx = Some(tso_rx);
+            // The old stream will not answer the batch in flight, so it is sent again.
+            if let Some(mut pending) = self.tso_pending.take() {
+                self.tso_batch.splice(0..0, pending.drain(..));
+                self.tso_buffer = Some(pending);
+            }
             self.schedule(PdTask::Init);
+            if !self.tso_batch.is_empty() {
+                self.schedule(PdTask::Request);
+            }
         }
     }
 
@@ -123,63 +147,96 @@ impl PdReactor {
     }
 
     fn poll(client: &Arc<RwLock<LeaderClient>>, rx: UnboundedReceiver<Option<PdTask>>) {
-        let mut core = Core::new().unwrap();
-        let handle = core.handle();
-        {
-            let f = rx.take_while(|t| Ok(t.is_some())).for_each(|t| {
-                Self::dispatch(&client, t.unwrap(), &handle);
-                Ok(())
-            });
-            core.run(f).unwrap();
-        }
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        pool.run_until(rx.take_while(|t| future::ready(t.is_some())).for_each(|t| {
+            Self::dispatch(&client, t.unwrap(), &spawner);
+            future::ready(())
+        }));
     }
 
-    fn init(client: &Arc<RwLock<LeaderClient>>, handle: &OtherHandle) {
+    fn init(client: &Arc<RwLock<LeaderClient>>, spawner: &LocalSpawner) {
         let client = Arc::clone(client);
-        let (tx, rx) = client.wl().client.tso().unwrap();
-        let tso_rx = client.wl().reactor.tso_rx.take().unwrap();
-        handle.spawn(
-            tx.sink_map_err(Into::into)
-                .send_all(tso_rx.then(|r| match r {
-                    Ok(r) => Ok((r, WriteFlags::default())),
-                    Err(()) => Err(internal_err!("failed to recv tso requests")),
-                }))
-                .then(|r: Result<_>| match r {
-                    Ok((mut sender, _)) => {
-                        sender.get_mut().cancel();
-                        Ok(())
+        let (generation, tso_rx) = {
+            let mut client = client.wl();
+            (client.reactor.tso_generation, client.reactor.tso_rx.take())
+        };
+        let mut tso_rx = match tso_rx {
+            Some(tso_rx) => tso_rx.map(|r| Ok::<_, grpcio::Error>((r, WriteFlags::default()))),
+            // Restarted again before this stream was opened, a previous `Init` opened the latest.
+            None => return,
+        };
+        // Bind the stream first, the read guard must be released before restarting.
+        let stream = client.rl().client.tso();
+        let (tx, rx) = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("failed to open tso stream: {:?}", e);
+                client.wl().restart_tso();
+                return;
+            }
+        };
+        spawner
+            .spawn_local(async move {
+                let mut sender = tx.sink_compat();
+                match sender.send_all(&mut tso_rx).await {
+                    Ok(()) => sender.get_mut().cancel(),
+                    Err(e) => error!("failed to send tso requests: {:?}", e),
+                }
+            })
+            .unwrap();
+        spawner
+            .spawn_local(async move {
+                let mut rx = rx.compat();
+                loop {
+                    let resp = match rx.try_next().await {
+                        Ok(Some(resp)) => resp,
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("tso stream failed: {:?}", e);
+                            let mut client = client.wl();
+                            if client.reactor.tso_generation == generation {
+                                client.restart_tso();
+                            }
+                            break;
+                        }
+                    };
+                    let mut client = client.wl();
+                    let reactor = &mut client.reactor;
+                    if reactor.tso_generation != generation {
+                        // The requests of a replaced stream were sent again on the new one.
+                        break;
                     }
-                    Err(e) => {
-                        error!("failed to send tso requests: {:?}", e);
-                        Err(())
+                    let tso_pending = match reactor.tso_pending.take() {
+                        Some(tso_pending) => tso_pending,
+                        None => {
+                            warn!("unexpected tso response {:?}", resp);
+                            continue;
+                        }
+                    };
+                    reactor.schedule(PdTask::Response(tso_pending, resp));
+                    if !reactor.tso_batch.is_empty() {
+                        // Schedule another tso_batch of request
+                        reactor.schedule(PdTask::Request);
                     }
-                }),
-        );
-        handle.spawn(
-            rx.for_each(move |resp| {
-                let mut client = client.wl();
-                let reactor = &mut client.reactor;
-                let tso_pending = reactor.tso_pending.take().unwrap();
-                reactor.schedule(PdTask::Response(tso_pending, resp));
-                if !reactor.tso_batch.is_empty() {
-                    // Schedule another tso_batch of request
-                    reactor.schedule(PdTask::Request);
                 }
-                Ok(())
             })
-            .map_err(|e| panic!("unexpected error: {:?}", e)),
-        );
+            .unwrap();
     }
 
     fn tso_request(client: &Arc<RwLock<LeaderClient>>) {
         let mut client = client.wl();
         let cluster_id = client.cluster_id;
         let reactor = &mut client.reactor;
+        if reactor.tso_pending.is_some() || reactor.tso_batch.is_empty() {
+            // Scheduled more than once, the batch is sent once the pending one is answered.
+            return;
+        }
         let mut tso_batch = reactor.tso_buffer.take().unwrap();
         tso_batch.extend(reactor.tso_batch.drain(..));
         let mut request = pd_request!(cluster_id, pdpb::TsoRequest);
         let batch_size = observe_tso_batch(tso_batch.len());
-        request.set_count(batch_size);
+        request.set_count(reactor.tso.count(batch_size));
         reactor.tso_pending = Some(tso_batch);
         reactor
             .tso_tx
@@ -193,34 +250,60 @@ impl PdReactor {
         response: &pdpb::TsoResponse,
     ) {
         let timestamp = response.get_timestamp();
-        for (offset, request) in requests.drain(..).enumerate() {
-            request
-                .send(PdTimestamp {
-                    physical: timestamp.physical,
-                    logical: timestamp.logical + offset as i64,
-                })
-                .unwrap();
+        let tail = PdTimestamp {
+            physical: timestamp.physical,
+            logical: timestamp.logical,
+        };
+        let mut client = client.wl();
+        let reactor = &mut client.reactor;
+        // Callers which went away meanwhile are ignored.
+        match reactor
+            .tso
+            .allocate(tail, response.get_count(), requests.len())
+        {
+            Ok(timestamps) => {
+                for (request, ts) in requests.drain(..).zip(timestamps) {
+                    let _ = request.send(Ok(ts));
+                }
+            }
+            Err(e) => {
+                error!("{}", e);
+                let message = e.to_string();
+                for request in requests.drain(..) {
+                    let _ = request.send(Err(internal_err!(message.clone())));
+                }
+            }
         }
-        client.wl().reactor.tso_buffer = Some(requests);
+        reactor.tso_buffer = Some(requests);
     }
 
-    fn dispatch(client: &Arc<RwLock<LeaderClient>>, task: PdTask, handle: &OtherHandle) {
+    fn dispatch(client: &Arc<RwLock<LeaderClient>>, task: PdTask, spawner: &LocalSpawner) {
         match task {
             PdTask::Request => Self::tso_request(client),
             PdTask::Response(requests, response) => Self::tso_response(client, requests, &response),
-            PdTask::Init => Self::init(client, handle),
+            PdTask::Init => Self::init(client, spawner),
         }
     }
 
-    fn get_ts(&mut self) -> impl Future<Item = PdTimestamp, Error = Error> {
+    /// Get a timestamp, taking it from the prefetched window when `prefetched` is set.
+    ///
+    /// Without `prefetched` the timestamp is always allocated by PD after this call.
+    fn get_ts(&mut self, prefetched: bool) -> impl Future<Output = Result<PdTimestamp>> {
+        if prefetched {
+            if let Some(ts) = self.tso.take() {
+                return future::Either::Left(future::ok(ts));
+            }
+        }
         let context = request_context("get_ts", ());
-        let (tx, rx) = oneshot::channel::<PdTimestamp>();
+        let (tx, rx) = oneshot::channel::<Result<PdTimestamp>>();
         self.tso_batch.push(tx);
         if self.tso_pending.is_none() {
             // Schedule tso request to run.
             self.schedule(PdTask::Request);
         }
-        rx.map_err(Into::into).then(move |r| context.done(r))
+        future::Either::Right(
+            async move { context.done(rx.await.map_err(Into::into).and_then(|r| r)) },
+        )
     }
 }
 
@@ -234,6 +317,13 @@ pub struct LeaderClient {
     last_update: Instant,
     reactor: PdReactor,
     timeout: Duration,
+
+    /// The thread refreshing the PD membership in the background.
+    refresher: Option<thread::Thread>,
+    /// Requests waiting for the next refresh, told whether it succeeded.
+    update_waiters: Vec<oneshot::Sender<bool>>,
+    /// Set when the TSO stream failed, so the next refresh opens a new one.
+    tso_broken: bool,
 }
 
 impl LeaderClient {
@@ -246,6 +336,7 @@ impl LeaderClient {
         endpoints: &[String],
         security_mgr: Arc<SecurityManager>,
         timeout: Duration,
+        tso_prefetch: u32,
     ) -> Result<Arc<RwLock<LeaderClient>>> {
         let (client, members) = validate_endpoints(&env, endpoints, &security_mgr, timeout)?;
         let cluster_id = members.get_header().get_cluster_id();
@@ -255,47 +346,159 @@ impl LeaderClient {
             members,
             security_mgr,
             last_update: Instant::now(),
-            reactor: PdReactor::new(),
+            reactor: PdReactor::new(tso_prefetch),
             cluster_id,
             timeout,
+            refresher: None,
+            update_waiters: Vec::new(),
+            tso_broken: false,
         }));
 
         client.wl().reactor.start(Arc::clone(&client));
+        let weak = Arc::downgrade(&client);
+        let refresher = thread::Builder::new()
+            .name("pd membership".to_owned())
+            .spawn(move || Self::refresh_members(&weak))?;
+        client.wl().refresher = Some(refresher.thread().clone());
         Ok(client)
     }
 
-    pub fn get_ts(&mut self) -> impl Future<Item = PdTimestamp, Error = Error> {
-        self.reactor.get_ts()
+    pub fn get_ts(&mut self) -> impl Future<Output = Result<PdTimestamp>> {
+        self.reactor.get_ts(true)
     }
 
-    // Re-establish connection with PD leader in synchronized fashion.
-    pub fn reconnect(leader: &Arc<RwLock<LeaderClient>>, interval: u64) -> Result<()> {
-        warn!("updating pd client, blocking the tokio core");
-        let ((client, members), start) = {
-            let leader = leader.rl();
-            if leader.last_update.elapsed() < Duration::from_secs(interval) {
-                // Avoid unnecessary updating.
-                return Ok(());
+    pub fn get_fresh_ts(&mut self) -> impl Future<Output = Result<PdTimestamp>> {
+        self.reactor.get_ts(false)
+    }
+
+    /// Ask for the PD leader to be looked up again, because requests to it are failing.
+    ///
+    /// The lookup happens in the background, the returned future resolves once it is done.
+    pub fn reconnect(leader: &Arc<RwLock<LeaderClient>>) -> impl Future<Output = Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut leader = leader.wl();
+            leader.update_waiters.push(tx);
+            if let Some(refresher) = &leader.refresher {
+                refresher.unpark();
+            }
+        }
+        async move {
+            if rx.await? {
+                Ok(())
+            } else {
+                Err(internal_err!("failed to update PD leader"))
+            }
+        }
+    }
+
+    /// Have the refresher open a new TSO stream, after the current one failed.
+    fn restart_tso(&mut self) {
+        self.tso_broken = true;
+        if let Some(refresher) = &self.refresher {
+            refresher.unpark();
+        }
+    }
+
+    /// Refresh the PD membership periodically, or as soon as a request asks for it, until the
+    /// client is dropped.
+    fn refresh_members(leader: &Weak<RwLock<LeaderClient>>) {
+        let mut interval = MEMBERS_REFRESH_INTERVAL;
+        loop {
+            thread::park_timeout(interval);
+            let leader = match leader.upgrade() {
+                Some(leader) => leader,
+                None => return,
+            };
+            let (requested, tso_broken) = {
+                let mut leader = leader.wl();
+                let tso_broken = mem::replace(&mut leader.tso_broken, false);
+                (!leader.update_waiters.is_empty(), tso_broken)
+            };
+            let updated = Self::update_members(&leader, requested, tso_broken);
+            let mut leader = leader.wl();
+            for waiter in leader.update_waiters.drain(..) {
+                // The request may have been dropped meanwhile.
+                let _ = waiter.send(updated);
             }
+            // Timestamp requests wait for the stream, so it is retried sooner.
+            interval = if !updated && tso_broken {
+                leader.tso_broken = true;
+                Duration::from_secs(RECONNECT_INTERVAL_SEC)
+            } else {
+                MEMBERS_REFRESH_INTERVAL
+            };
+        }
+    }
 
-            let start = Instant::now();
-            let timeout = leader.timeout;
+    /// Look up the PD leader and, if it changed, swap in a client connected to the new one. The
+    /// TSO stream is restarted if the leader changed or the stream failed. Returns whether the
+    /// lookup succeeded.
+    fn update_members(
+        leader: &Arc<RwLock<LeaderClient>>,
+        requested: bool,
+        tso_broken: bool,
+    ) -> bool {
+        let (env, security_mgr, client, members, timeout) = {
+            let leader = leader.rl();
+            if requested
+                && !tso_broken
+                && leader.last_update.elapsed() < Duration::from_secs(RECONNECT_INTERVAL_SEC)
+            {
+                // Another request just had the client updated.
+                return true;
+            }
             (
-                try_connect_leader(&leader.env, &leader.security_mgr, &leader.members, timeout)?,
-                start,
+                Arc::clone(&leader.env),
+                Arc::clone(&leader.security_mgr),
+                leader.client.clone(),
+                leader.members.clone(),
+                leader.timeout,
             )
         };
 
-        {
-            let leader_clone = Arc::clone(leader);
-            let mut leader = leader.wl();
+        let start = Instant::now();
+        // Ask the current leader first, so that nothing is reconnected while it stays the leader.
+        let unchanged = match get_members(&client, timeout) {
+            Ok(resp)
+                if resp.get_header().get_cluster_id() == members.get_header().get_cluster_id()
+                    && resp.get_leader() == members.get_leader() =>
+            {
+                Some(resp)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("failed to get PD members from the leader: {:?}", e);
+                None
+            }
+        };
+        let (client, members) = match unchanged {
+            Some(members) => (None, members),
+            None => match try_connect_leader(&env, &security_mgr, &members, timeout) {
+                Ok((client, members)) => (Some(client), members),
+                Err(e) => {
+                    error!("failed to update PD membership: {:?}", e);
+                    return false;
+                }
+            },
+        };
+        let leader_clone = Arc::clone(leader);
+        let mut leader = leader.wl();
+        let changed = leader.members.get_leader() != members.get_leader();
+        if let Some(client) = client {
             leader.client = client;
-            leader.members = members;
-            leader.last_update = Instant::now();
+        }
+        leader.members = members;
+        leader.last_update = Instant::now();
+        if changed || tso_broken {
+            info!(
+                "PD leader is now {:?}, updated in {:?}",
+                leader.members.get_leader().get_name(),
+                start.elapsed()
+            );
             leader.reactor.start(leader_clone);
         }
-        warn!("updating PD client done, spent {:?}", start.elapsed());
-        Ok(())
+        true
     }
 }
 
@@ -361,11 +564,18 @@ fn connect(
     timeout: Duration,
 ) -> Result<(pdpb_grpc::PdClient, pdpb::GetMembersResponse)> {
     let client = security_mgr.connect(env, addr, pdpb_grpc::PdClient::new)?;
+    let resp = get_members(&client, timeout)?;
+    Ok((client, resp))
+}
+
+fn get_members(
+    client: &pdpb_grpc::PdClient,
+    timeout: Duration,
+) -> Result<pdpb::GetMembersResponse> {
     let option = CallOption::default().timeout(timeout);
-    let resp = client
+    client
         .get_members_opt(&pdpb::GetMembersRequest::new(), option)
-        .map_err(Error::from)?;
-    Ok((client, resp))
+        .map_err(Error::from)
 }
 
 fn try_connect(
@@ -26,6 +26,7 @@ mod leader;
 mod client;
 mod context;
 mod request;
+mod tso;
 
 pub type RegionId = u64;
 pub type StoreId = u64;
@@ -65,8 +66,25 @@ impl Region {
         }
     }
 
-    pub fn switch_peer(&mut self, _to: StoreId) -> Result<()> {
-        unimplemented!()
+    /// Treat the peer of the region on store `to` as its leader from now on.
+    pub fn switch_peer(&mut self, to: StoreId) -> Result<()> {
+        let peer = self
+            .region
+            .get_peers()
+            .iter()
+            .find(|peer| peer.get_store_id() == to)
+            .cloned();
+        match peer {
+            Some(peer) => {
+                self.leader = Some(Peer(peer));
+                Ok(())
+            }
+            None => Err(Error::not_leader(
+                self.region.get_id(),
+                None,
+                Some(format!("no peer on store {}", to)),
+            )),
+        }
     }
 
     pub fn contains(&self, key: &Key) -> bool {
@@ -79,7 +97,7 @@ impl Region {
     pub fn context(&self) -> Result<kvrpcpb::Context> {
         self.leader
             .as_ref()
-            .ok_or_else(|| Error::not_leader(self.region.get_id(), None))
+            .ok_or_else(|| Error::not_leader(self.region.get_id(), None, None))
             .map(|l| {
                 let mut ctx = kvrpcpb::Context::default();
                 ctx.set_region_id(self.region.get_id());
@@ -119,6 +137,18 @@ impl Region {
             .ok_or_else(|| Error::stale_epoch(None))
     }
 
+    /// The followers and learners of the region, which are all its peers but the leader.
+    pub fn replicas(&self) -> Vec<Peer> {
+        let leader_id = self.leader.as_ref().map(|leader| leader.get_id());
+        self.region
+            .get_peers()
+            .iter()
+            .filter(|peer| Some(peer.get_id()) != leader_id)
+            .cloned()
+            .map(Peer)
+            .collect()
+    }
+
     pub fn meta(&self) -> metapb::Region {
         Clone::clone(&self.region)
     }
@@ -176,8 +206,57 @@ impl Peer {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+/// Timestamps are ordered by their physical, then their logical part.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
 pub struct PdTimestamp {
     pub physical: i64,
     pub logical: i64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region() -> Region {
+        let mut region = metapb::Region::new();
+        region.set_id(1);
+        let peers = (1..=3)
+            .map(|id| {
+                let mut peer = metapb::Peer::new();
+                peer.set_id(id * 10);
+                peer.set_store_id(id);
+                peer
+            })
+            .collect::<Vec<_>>();
+        let leader = peers[0].clone();
+        region.set_peers(peers.into());
+        Region::new(region, Some(leader))
+    }
+
+    #[test]
+    fn test_switch_peer() {
+        let mut region = region();
+        assert_eq!(
+            region
+                .replicas()
+                .iter()
+                .map(|p| p.get_id())
+                .collect::<Vec<_>>(),
+            vec![20, 30]
+        );
+
+        region.switch_peer(3).unwrap();
+        assert_eq!(region.peer().unwrap().get_id(), 30);
+        assert_eq!(
+            region
+                .replicas()
+                .iter()
+                .map(|p| p.get_id())
+                .collect::<Vec<_>>(),
+            vec![10, 20]
+        );
+
+        assert!(region.switch_peer(4).is_err());
+        assert_eq!(region.peer().unwrap().get_id(), 30);
+    }
+}
@@ -12,125 +12,140 @@
 // limitations under the License.
 
 use std::{
-    result,
     sync::{Arc, RwLock},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use futures::future::{loop_fn, ok, Either, Future, Loop};
+use futures::prelude::*;
 use log::*;
-use tokio_timer::timer::Handle;
 
-use crate::{rpc::util::GLOBAL_TIMER_HANDLE, Error, Result};
+use crate::{rpc::retry::sleep, Result};
 
 pub const RECONNECT_INTERVAL_SEC: u64 = 1; // 1s
 
 /// The context of sending requets.
-pub struct Request<Resp, Func, Cli, Reconnect> {
+pub struct Request<Func, Cli, Reconnect> {
     reconnect_count: usize,
     request_sent: usize,
 
     client: Arc<RwLock<Cli>>,
-    timer: Handle,
 
-    resp: Option<Result<Resp>>,
     func: Func,
     reconnect: Reconnect,
 }
 
 const MAX_REQUEST_COUNT: usize = 3;
 
-impl<Resp, Func, Cli, Reconnect, RespFuture> Request<Resp, Func, Cli, Reconnect>
+impl<Resp, Func, Cli, Reconnect, RespFuture, ReconnectFuture> Request<Func, Cli, Reconnect>
 where
     Resp: Send + 'static,
     Func: FnMut(&RwLock<Cli>) -> RespFuture + Send + 'static,
     Cli: Send + Sync + 'static,
-    Reconnect: FnMut(&Arc<RwLock<Cli>>, u64) -> Result<()> + Send + 'static,
-    RespFuture: Future<Item = Resp, Error = Error> + Send + 'static,
+    Reconnect: FnMut(&Arc<RwLock<Cli>>) -> ReconnectFuture + Send + 'static,
+    RespFuture: Future<Output = Result<Resp>> + Send + 'static,
+    ReconnectFuture: Future<Output = Result<()>> + Send,
 {
     pub fn new(func: Func, client: Arc<RwLock<Cli>>, reconnect: Reconnect, retry: usize) -> Self {
         Request {
             reconnect_count: retry,
             request_sent: 0,
             client,
-            timer: GLOBAL_TIMER_HANDLE.clone(),
-            resp: None,
             func,
             reconnect,
         }
     }
 
-    fn reconnect_if_needed(mut self) -> impl Future<Item = Self, Error = Self> + Send {
+    /// Reconnects once too many requests have failed. Returns whether the request can be sent.
+    async fn reconnect_if_needed(&mut self) -> bool {
         debug!("reconnect remains: {}", self.reconnect_count);
 
         if self.request_sent < MAX_REQUEST_COUNT {
-            return Either::A(ok(self));
+            return true;
         }
 
-        // Updating client.
+        // Wait for the client to be updated.
         self.reconnect_count -= 1;
 
-        // FIXME: should not block the core.
-        match (self.reconnect)(&self.client, RECONNECT_INTERVAL_SEC) {
+        match (self.reconnect)(&self.client).await {
             Ok(_) => {
                 self.request_sent = 0;
-                Either::A(ok(self))
+                true
+            }
+            Err(_) => {
+                sleep(Duration::from_secs(RECONNECT_INTERVAL_SEC)).await;
+                false
             }
-            Err(_) => Either::B(
-                self.timer
-                    .delay(Instant::now() + Duration::from_secs(RECONNECT_INTERVAL_SEC))
-                    .then(|_| Err(self)),
-            ),
         }
     }
 
-    fn send_and_receive(mut self) -> impl Future<Item = Self, Error = Self> + Send {
+    async fn send_and_receive(&mut self) -> Option<Resp> {
         self.request_sent += 1;
         debug!("request sent: {}", self.request_sent);
 
-        ok(self).and_then(|mut ctx| {
-            let req = (ctx.func)(&ctx.client);
-            req.then(|resp| match resp {
-                Ok(resp) => {
-                    ctx.resp = Some(Ok(resp));
-                    Ok(ctx)
-                }
-                Err(err) => {
-                    error!("request failed: {:?}", err);
-                    Err(ctx)
-                }
-            })
-        })
-    }
-
-    fn break_or_continue(ctx: result::Result<Self, Self>) -> Result<Loop<Self, Self>> {
-        let ctx = match ctx {
-            Ok(ctx) | Err(ctx) => ctx,
-        };
-        let done = ctx.reconnect_count == 0 || ctx.resp.is_some();
-        if done {
-            Ok(Loop::Break(ctx))
-        } else {
-            Ok(Loop::Continue(ctx))
+        match (self.func)(&self.client).await {
+            Ok(resp) => Some(resp),
+            Err(err) => {
+                error!("request failed: {:?}", err);
+                None
+            }
         }
     }
 
-    fn post_loop(ctx: Result<Self>) -> Result<Resp> {
-        let ctx = ctx.expect("end loop with Ok(_)");
-        ctx.resp
-            .unwrap_or_else(|| Err(internal_err!("fail to request")))
+    /// Resolves once a future returned by the closure is resolved successfully, otherwise it
+    /// repeats `retry` times.
+    pub async fn execute(mut self) -> Result<Resp> {
+        loop {
+            if self.reconnect_if_needed().await {
+                if let Some(resp) = self.send_and_receive().await {
+                    return Ok(resp);
+                }
+            }
+            if self.reconnect_count == 0 {
+                return Err(internal_err!("fail to request"));
+            }
+        }
     }
+}
 
-    /// Returns a Future, it is resolves once a future returned by the closure
-    /// is resolved successfully, otherwise it repeats `retry` times.
-    pub fn execute(self) -> impl Future<Item = Resp, Error = Error> {
-        let ctx = self;
-        loop_fn(ctx, |ctx| {
-            ctx.reconnect_if_needed()
-                .and_then(Self::send_and_receive)
-                .then(Self::break_or_continue)
-        })
-        .then(Self::post_loop)
-        .map_err(|e| e)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::util::HandyRwLock;
+    use futures::{executor, future};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_reconnect_after_failures() {
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        let reconnects_clone = Arc::clone(&reconnects);
+        // The request fails until the client has been reconnected once.
+        let func = |cli: &RwLock<Arc<AtomicUsize>>| {
+            let reconnected = cli.rl().load(Ordering::SeqCst) > 0;
+            future::ready(if reconnected {
+                Ok(42)
+            } else {
+                Err(internal_err!("no leader"))
+            })
+        };
+        let reconnect = move |_: &Arc<RwLock<Arc<AtomicUsize>>>| {
+            reconnects_clone.fetch_add(1, Ordering::SeqCst);
+            future::ready(Ok(()))
+        };
+        let request = Request::new(
+            func,
+            Arc::new(RwLock::new(Arc::clone(&reconnects))),
+            reconnect,
+            2,
+        );
+        assert_eq!(executor::block_on(request.execute()).unwrap(), 42);
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+
+        let request = Request::new(
+            |_: &RwLock<()>| future::ready(Err::<(), _>(internal_err!("no leader"))),
+            Arc::new(RwLock::new(())),
+            |_: &Arc<RwLock<()>>| future::ready(Ok(())),
+            2,
+        );
+        assert!(executor::block_on(request.execute()).is_err());
     }
 }
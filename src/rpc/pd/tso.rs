@@ -0,0 +1,183 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use crate::{rpc::pd::PdTimestamp, Result};
+
+/// How long prefetched timestamps may be handed out, so they do not lag far behind PD.
+const TSO_WINDOW_TTL: Duration = Duration::from_millis(500);
+
+/// Logical timestamps reserved from PD but not handed out yet.
+#[derive(Debug)]
+struct TsoWindow {
+    next: PdTimestamp,
+    end: i64,
+    fetched_at: Instant,
+}
+
+/// Hands out the timestamps returned by PD, reserving `prefetch` more of them with each request
+/// so that later callers are served locally.
+///
+/// Timestamps are strictly increasing, a response from PD which would break this is rejected.
+#[derive(Debug)]
+pub(super) struct TsoAllocator {
+    prefetch: u32,
+    window: Option<TsoWindow>,
+    last: Option<PdTimestamp>,
+}
+
+impl TsoAllocator {
+    pub fn new(prefetch: u32) -> TsoAllocator {
+        TsoAllocator {
+            prefetch,
+            window: None,
+            last: None,
+        }
+    }
+
+    /// The number of timestamps to ask PD for, to serve `requested` callers.
+    pub fn count(&self, requested: u32) -> u32 {
+        requested.saturating_add(self.prefetch)
+    }
+
+    /// Hand out a prefetched timestamp, if there is one left.
+    pub fn take(&mut self) -> Option<PdTimestamp> {
+        let window = self.window.as_mut()?;
+        if window.next.logical >= window.end || window.fetched_at.elapsed() > TSO_WINDOW_TTL {
+            self.window = None;
+            return None;
+        }
+        let ts = window.next.clone();
+        window.next.logical += 1;
+        self.last = Some(ts.clone());
+        Some(ts)
+    }
+
+    /// Split the `count` timestamps up to `tail` which PD returned into the ones for the
+    /// `requested` callers, and keep the rest for later.
+    ///
+    /// PD answers with the last timestamp it allocated, the first one is `count - 1` before it.
+    pub fn allocate(
+        &mut self,
+        tail: PdTimestamp,
+        count: u32,
+        requested: usize,
+    ) -> Result<Vec<PdTimestamp>> {
+        if count == 0 || (count as usize) < requested {
+            self.window = None;
+            return Err(internal_err!(
+                "PD returned {} timestamps for {} requests",
+                count,
+                requested
+            ));
+        }
+        let first = PdTimestamp {
+            physical: tail.physical,
+            logical: tail.logical - i64::from(count) + 1,
+        };
+        if let Some(last) = &self.last {
+            if first <= *last {
+                self.window = None;
+                return Err(internal_err!(
+                    "PD returned timestamp {:?}, which is not after {:?}",
+                    first,
+                    last
+                ));
+            }
+        }
+
+        let end = tail.logical + 1;
+        let timestamps: Vec<_> = (0..requested as i64)
+            .map(|offset| PdTimestamp {
+                physical: first.physical,
+                logical: first.logical + offset,
+            })
+            .collect();
+        let next = PdTimestamp {
+            physical: first.physical,
+            logical: first.logical + requested as i64,
+        };
+        self.window = if next.logical < end {
+            Some(TsoWindow {
+                next,
+                end,
+                fetched_at: Instant::now(),
+            })
+        } else {
+            None
+        };
+        if let Some(ts) = timestamps.last() {
+            self.last = Some(ts.clone());
+        }
+        Ok(timestamps)
+    }
+
+    /// Drop the prefetched timestamps, after the PD leader changed.
+    pub fn reset(&mut self) {
+        self.window = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(physical: i64, logical: i64) -> PdTimestamp {
+        PdTimestamp { physical, logical }
+    }
+
+    #[test]
+    fn test_tso_prefetch() {
+        let mut tso = TsoAllocator::new(3);
+        assert_eq!(tso.count(2), 5);
+        assert_eq!(tso.take(), None);
+
+        let timestamps = tso.allocate(ts(10, 4), 5, 2).unwrap();
+        assert_eq!(timestamps, vec![ts(10, 0), ts(10, 1)]);
+        assert_eq!(tso.take(), Some(ts(10, 2)));
+        assert_eq!(tso.take(), Some(ts(10, 3)));
+        assert_eq!(tso.take(), Some(ts(10, 4)));
+        assert_eq!(tso.take(), None);
+
+        // Nothing is prefetched without a window.
+        let mut tso = TsoAllocator::new(0);
+        assert_eq!(tso.allocate(ts(10, 0), 1, 1).unwrap(), vec![ts(10, 0)]);
+        assert_eq!(tso.take(), None);
+
+        // The window ends at the timestamp PD returned, the following allocations start after it.
+        let mut tso = TsoAllocator::new(2);
+        assert_eq!(tso.allocate(ts(10, 2), 3, 1).unwrap(), vec![ts(10, 0)]);
+        assert_eq!(tso.take(), Some(ts(10, 1)));
+        assert_eq!(tso.take(), Some(ts(10, 2)));
+        assert_eq!(tso.take(), None);
+        assert_eq!(tso.allocate(ts(10, 5), 3, 1).unwrap(), vec![ts(10, 3)]);
+        assert!(tso.allocate(ts(10, 9), 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_tso_monotonic() {
+        let mut tso = TsoAllocator::new(2);
+        tso.allocate(ts(10, 2), 3, 1).unwrap();
+        assert_eq!(tso.take(), Some(ts(10, 1)));
+
+        // A new leader drops the window, and must not go back in time.
+        tso.reset();
+        assert_eq!(tso.take(), None);
+        assert!(tso.allocate(ts(10, 3), 3, 1).is_err());
+        assert!(tso.allocate(ts(9, 100), 3, 1).is_err());
+        assert_eq!(tso.take(), None);
+        assert_eq!(tso.allocate(ts(11, 2), 3, 1).unwrap(), vec![ts(11, 0)]);
+        assert_eq!(tso.take(), Some(ts(11, 1)));
+    }
+}
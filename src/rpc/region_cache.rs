@@ -0,0 +1,228 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound::{Excluded, Included, Unbounded},
+};
+
+use crate::{
+    rpc::pd::{Region, RegionId, Store, StoreId},
+    Key,
+};
+
+/// A client side cache of the region and store metadata fetched from PD.
+///
+/// Regions are indexed by their start key, so the region owning a key is the one with the greatest
+/// start key not greater than that key. Entries are dropped when TiKV tells us they are stale.
+#[derive(Default)]
+pub struct RegionCache {
+    regions: BTreeMap<Key, Region>,
+    id_to_start_key: HashMap<RegionId, Key>,
+    stores: HashMap<StoreId, Store>,
+}
+
+impl RegionCache {
+    pub fn get_region_by_key(&self, key: &Key) -> Option<Region> {
+        self.regions
+            .range::<Key, _>((Unbounded, Included(key)))
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(key))
+            .cloned()
+    }
+
+    /// Get the region containing the keys right before `key`, which is exclusive as the end of a
+    /// range. An empty `key` stands for the end of the key space.
+    pub fn get_region_by_end_key(&self, key: &Key) -> Option<Region> {
+        let upper = if key.is_empty() {
+            Unbounded
+        } else {
+            Excluded(key)
+        };
+        self.regions
+            .range::<Key, _>((Unbounded, upper))
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| {
+                region.end_key().is_empty() || (!key.is_empty() && region.end_key() >= &**key)
+            })
+            .cloned()
+    }
+
+    pub fn get_region_by_id(&self, id: RegionId) -> Option<Region> {
+        self.id_to_start_key
+            .get(&id)
+            .and_then(|start_key| self.regions.get(start_key))
+            .cloned()
+    }
+
+    pub fn add_region(&mut self, region: Region) {
+        self.invalidate_region(region.id());
+
+        // Any cached region overlapping with the new one must be stale.
+        let start_key = Key::from(region.start_key().to_vec());
+        let mut stale = Vec::new();
+        if let Some((key, prev)) = self
+            .regions
+            .range::<Key, _>((Unbounded, Excluded(&start_key)))
+            .next_back()
+        {
+            if prev.end_key().is_empty() || prev.end_key() > region.start_key() {
+                stale.push(key.clone());
+            }
+        }
+        let upper = if region.end_key().is_empty() {
+            Unbounded
+        } else {
+            Excluded(Key::from(region.end_key().to_vec()))
+        };
+        stale.extend(
+            self.regions
+                .range((Included(start_key.clone()), upper))
+                .map(|(key, _)| key.clone()),
+        );
+        for key in stale {
+            if let Some(region) = self.regions.remove(&key) {
+                self.id_to_start_key.remove(&region.id());
+            }
+        }
+
+        self.id_to_start_key.insert(region.id(), start_key.clone());
+        self.regions.insert(start_key, region);
+    }
+
+    /// Switch the leader of the cached region `id` to its peer on store `to`. Returns whether the
+    /// region is cached and has a peer on that store.
+    pub fn switch_leader(&mut self, id: RegionId, to: StoreId) -> bool {
+        let region = self
+            .id_to_start_key
+            .get(&id)
+            .and_then(|start_key| self.regions.get_mut(start_key));
+        match region {
+            Some(region) => region.switch_peer(to).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn invalidate_region(&mut self, id: RegionId) {
+        if let Some(start_key) = self.id_to_start_key.remove(&id) {
+            self.regions.remove(&start_key);
+        }
+    }
+
+    pub fn get_store(&self, id: StoreId) -> Option<Store> {
+        self.stores.get(&id).cloned()
+    }
+
+    pub fn add_store(&mut self, store: Store) {
+        self.stores.insert(store.get_id(), store);
+    }
+
+    pub fn invalidate_store(&mut self, id: StoreId) {
+        self.stores.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvproto::metapb;
+
+    fn region(id: RegionId, start: &'static str, end: &'static str) -> Region {
+        let mut region = metapb::Region::new();
+        region.set_id(id);
+        region.set_start_key(start.as_bytes().to_vec());
+        region.set_end_key(end.as_bytes().to_vec());
+        Region::new(region, None)
+    }
+
+    #[test]
+    fn test_get_region_by_key() {
+        let mut cache = RegionCache::default();
+        cache.add_region(region(1, "", "b"));
+        cache.add_region(region(2, "b", "d"));
+        cache.add_region(region(3, "f", ""));
+
+        assert_eq!(cache.get_region_by_key(&"a".into()).unwrap().id(), 1);
+        assert_eq!(cache.get_region_by_key(&"b".into()).unwrap().id(), 2);
+        assert_eq!(cache.get_region_by_key(&"c".into()).unwrap().id(), 2);
+        assert!(cache.get_region_by_key(&"e".into()).is_none());
+        assert_eq!(cache.get_region_by_key(&"z".into()).unwrap().id(), 3);
+        assert_eq!(cache.get_region_by_id(3).unwrap().start_key(), b"f");
+    }
+
+    #[test]
+    fn test_get_region_by_end_key() {
+        let mut cache = RegionCache::default();
+        cache.add_region(region(1, "", "b"));
+        cache.add_region(region(2, "b", "d"));
+        cache.add_region(region(3, "f", ""));
+
+        assert_eq!(cache.get_region_by_end_key(&"b".into()).unwrap().id(), 1);
+        assert_eq!(cache.get_region_by_end_key(&"c".into()).unwrap().id(), 2);
+        assert_eq!(cache.get_region_by_end_key(&"d".into()).unwrap().id(), 2);
+        assert!(cache.get_region_by_end_key(&"e".into()).is_none());
+        assert!(cache.get_region_by_end_key(&"f".into()).is_none());
+        assert_eq!(cache.get_region_by_end_key(&"".into()).unwrap().id(), 3);
+    }
+
+    #[test]
+    fn test_add_overlapping_region() {
+        let mut cache = RegionCache::default();
+        cache.add_region(region(1, "", "c"));
+        cache.add_region(region(2, "c", "f"));
+        cache.add_region(region(3, "f", ""));
+
+        // Region 1 split, region 2 and 3 merged.
+        cache.add_region(region(4, "b", "c"));
+        cache.add_region(region(2, "c", ""));
+
+        assert!(cache.get_region_by_key(&"a".into()).is_none());
+        assert!(cache.get_region_by_id(1).is_none());
+        assert!(cache.get_region_by_id(3).is_none());
+        assert_eq!(cache.get_region_by_key(&"b".into()).unwrap().id(), 4);
+        assert_eq!(cache.get_region_by_key(&"g".into()).unwrap().id(), 2);
+    }
+
+    #[test]
+    fn test_switch_leader() {
+        let mut cache = RegionCache::default();
+        let mut meta = region(1, "", "").region;
+        let peers = (1..=2)
+            .map(|id| {
+                let mut peer = metapb::Peer::new();
+                peer.set_id(id * 10);
+                peer.set_store_id(id);
+                peer
+            })
+            .collect::<Vec<_>>();
+        meta.set_peers(peers.clone().into());
+        cache.add_region(Region::new(meta, Some(peers[0].clone())));
+
+        assert!(cache.switch_leader(1, 2));
+        let leader = cache.get_region_by_id(1).unwrap().leader.unwrap();
+        assert_eq!(leader.get_id(), 20);
+        assert!(!cache.switch_leader(1, 3));
+        assert!(!cache.switch_leader(2, 1));
+    }
+
+    #[test]
+    fn test_invalidate_region() {
+        let mut cache = RegionCache::default();
+        cache.add_region(region(1, "", ""));
+        cache.invalidate_region(1);
+        assert!(cache.get_region_by_key(&"a".into()).is_none());
+        assert!(cache.get_region_by_id(1).is_none());
+    }
+}
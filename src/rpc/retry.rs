@@ -0,0 +1,152 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+use futures::prelude::*;
+use futures_timer::Delay;
+use log::*;
+
+use crate::{Backoff, Error, ErrorKind, Result};
+
+/// Resolve the request built by `f`, rebuilding and resending it while it fails with a region
+/// error that a fresh region lookup may fix, as long as `backoff` allows.
+pub async fn retry_on_region_error<F, Fut, T>(backoff: Backoff, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let e = match f().await {
+            Ok(resp) => return Ok(resp),
+            Err(e) => e,
+        };
+        match next_delay(&backoff, &e, attempt, start.elapsed()) {
+            Some(delay) => {
+                warn!(
+                    "request failed, retry {} after {:?}: {}",
+                    attempt + 1,
+                    delay,
+                    e
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            None => return Err(e),
+        }
+    }
+}
+
+fn next_delay(backoff: &Backoff, e: &Error, attempt: u32, elapsed: Duration) -> Option<Duration> {
+    let delay = match e.kind() {
+        ErrorKind::ServerIsBusy { backoff_ms, .. } => {
+            cmp::max(backoff.delay(attempt), Duration::from_millis(*backoff_ms))
+        }
+        // The request can be resent to the new leader right away. Later hints are backed off
+        // like other errors, so peers handing the leadership back and forth (such as a hinted
+        // leader on a store considered down) do not use up the attempts in a tight loop.
+        ErrorKind::NotLeader {
+            leader: Some(_), ..
+        } if attempt == 0 => Duration::from_millis(0),
+        ErrorKind::NotLeader { .. }
+        | ErrorKind::StaleEpoch { .. }
+        | ErrorKind::RegionNotFound { .. }
+        | ErrorKind::KeyNotInRegion { .. }
+        | ErrorKind::StaleCommand { .. } => backoff.delay(attempt),
+        // The request is sent to another peer once the store is considered down. A request which
+        // timed out is not retried, as it may have been applied and may not be idempotent.
+        _ if e.is_transport_failure() => backoff.delay(attempt),
+        _ => return None,
+    };
+    limit_delay(backoff, delay, attempt, elapsed)
+}
+
+/// Returns `delay` if `backoff` still allows another try after `attempt` failed, `elapsed` after
+/// the first one started.
+pub fn limit_delay(
+    backoff: &Backoff,
+    delay: Duration,
+    attempt: u32,
+    elapsed: Duration,
+) -> Option<Duration> {
+    if attempt + 1 >= backoff.max_attempts || elapsed + delay > backoff.deadline {
+        None
+    } else {
+        Some(delay)
+    }
+}
+
+pub async fn sleep(delay: Duration) {
+    Delay::new(delay).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep() {
+        let timer = Instant::now();
+        futures::executor::block_on(sleep(Duration::from_millis(100)));
+        assert!(timer.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(100))
+            .max_attempts(3)
+            .deadline(Duration::from_secs(1));
+        let stale = Error::stale_command(String::new());
+
+        let delay = next_delay(&backoff, &stale, 0, Duration::from_secs(0)).unwrap();
+        assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(10));
+        let delay = next_delay(&backoff, &stale, 1, Duration::from_secs(0)).unwrap();
+        assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+        assert!(next_delay(&backoff, &stale, 2, Duration::from_secs(0)).is_none());
+        assert!(next_delay(&backoff, &stale, 0, Duration::from_secs(1)).is_none());
+        assert!(next_delay(&backoff, &Error::empty_value(), 0, Duration::from_secs(0)).is_none());
+
+        let mut busy = kvproto::errorpb::ServerIsBusy::new();
+        busy.set_backoff_ms(300);
+        let busy = Error::server_is_busy(busy);
+        assert_eq!(
+            next_delay(&backoff, &busy, 0, Duration::from_secs(0)),
+            Some(Duration::from_millis(300))
+        );
+
+        let not_leader = Error::not_leader(1, Some(kvproto::metapb::Peer::new()), None);
+        assert_eq!(
+            next_delay(&backoff, &not_leader, 0, Duration::from_secs(0)),
+            Some(Duration::from_millis(0))
+        );
+        let delay = next_delay(&backoff, &not_leader, 1, Duration::from_secs(0)).unwrap();
+        assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+
+        let grpc_error = |code| {
+            Error::from(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+                code, None,
+            )))
+        };
+        let unavailable = grpc_error(grpcio::RpcStatusCode::Unavailable);
+        assert!(next_delay(&backoff, &unavailable, 0, Duration::from_secs(0)).is_some());
+        let timeout = grpc_error(grpcio::RpcStatusCode::DeadlineExceeded);
+        assert!(next_delay(&backoff, &timeout, 0, Duration::from_secs(0)).is_none());
+        let invalid = grpc_error(grpcio::RpcStatusCode::InvalidArgument);
+        assert!(next_delay(&backoff, &invalid, 0, Duration::from_secs(0)).is_none());
+    }
+}
@@ -11,12 +11,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-// TODO: Remove this when txn is done.
-#![allow(dead_code)]
-
-use std::{fmt, sync::Arc, time::Duration};
+use std::{
+    cmp, fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use futures::Future;
+use futures::{compat::Future01CompatExt, prelude::*};
 use grpcio::{CallOption, Environment};
 use kvproto::{errorpb, kvrpcpb, tikvpb_grpc::TikvClient};
 use protobuf;
@@ -25,7 +29,10 @@ use crate::{
     rpc::{
         client::{RawContext, TxnContext},
         security::SecurityManager,
-        tikv::context::{request_context, RequestContext},
+        tikv::{
+            context::{request_context, RequestContext},
+            StoreHealth,
+        },
     },
     transaction::{Mutation, TxnInfo},
     Error, ErrorKind, Key, KvPair, Result, Value,
@@ -43,9 +50,13 @@ impl From<errorpb::Error> for Error {
     fn from(mut e: errorpb::Error) -> Error {
         let message = e.take_message();
         if e.has_not_leader() {
-            let e = e.get_not_leader();
-            let message = format!("{}. Leader: {:?}", message, e.get_leader());
-            Error::not_leader(e.get_region_id(), Some(message))
+            let mut e = e.take_not_leader();
+            let leader = if e.has_leader() {
+                Some(e.take_leader())
+            } else {
+                None
+            };
+            Error::not_leader(e.get_region_id(), leader, Some(message))
         } else if e.has_region_not_found() {
             Error::region_not_found(e.get_region_not_found().get_region_id(), Some(message))
         } else if e.has_key_not_in_region() {
@@ -88,24 +99,24 @@ macro_rules! has_region_error {
 has_region_error!(kvrpcpb::GetResponse);
 has_region_error!(kvrpcpb::ScanResponse);
 has_region_error!(kvrpcpb::PrewriteResponse);
+has_region_error!(kvrpcpb::PessimisticLockResponse);
+has_region_error!(kvrpcpb::PessimisticRollbackResponse);
 has_region_error!(kvrpcpb::CommitResponse);
-has_region_error!(kvrpcpb::ImportResponse);
 has_region_error!(kvrpcpb::BatchRollbackResponse);
 has_region_error!(kvrpcpb::CleanupResponse);
 has_region_error!(kvrpcpb::BatchGetResponse);
-has_region_error!(kvrpcpb::ScanLockResponse);
 has_region_error!(kvrpcpb::ResolveLockResponse);
-has_region_error!(kvrpcpb::GCResponse);
 has_region_error!(kvrpcpb::RawGetResponse);
 has_region_error!(kvrpcpb::RawBatchGetResponse);
 has_region_error!(kvrpcpb::RawPutResponse);
 has_region_error!(kvrpcpb::RawBatchPutResponse);
 has_region_error!(kvrpcpb::RawDeleteResponse);
 has_region_error!(kvrpcpb::RawBatchDeleteResponse);
-has_region_error!(kvrpcpb::DeleteRangeResponse);
 has_region_error!(kvrpcpb::RawDeleteRangeResponse);
 has_region_error!(kvrpcpb::RawScanResponse);
 has_region_error!(kvrpcpb::RawBatchScanResponse);
+has_region_error!(kvrpcpb::RawCASResponse);
+has_region_error!(kvrpcpb::RawGetKeyTTLResponse);
 
 macro_rules! has_key_error {
     ($type:ty) => {
@@ -125,9 +136,37 @@ has_key_error!(kvrpcpb::GetResponse);
 has_key_error!(kvrpcpb::CommitResponse);
 has_key_error!(kvrpcpb::BatchRollbackResponse);
 has_key_error!(kvrpcpb::CleanupResponse);
-has_key_error!(kvrpcpb::ScanLockResponse);
 has_key_error!(kvrpcpb::ResolveLockResponse);
-has_key_error!(kvrpcpb::GCResponse);
+
+macro_rules! has_key_errors {
+    ($type:ty) => {
+        impl HasError for $type {
+            fn error(&mut self) -> Option<Error> {
+                self.take_errors().into_iter().next().map(Into::into)
+            }
+        }
+    };
+}
+
+has_key_errors!(kvrpcpb::PrewriteResponse);
+has_key_errors!(kvrpcpb::PessimisticLockResponse);
+has_key_errors!(kvrpcpb::PessimisticRollbackResponse);
+
+macro_rules! has_pair_error {
+    ($type:ty) => {
+        impl HasError for $type {
+            fn error(&mut self) -> Option<Error> {
+                self.mut_pairs()
+                    .iter_mut()
+                    .find(|pair| pair.has_error())
+                    .map(|pair| pair.take_error().into())
+            }
+        }
+    };
+}
+
+has_pair_error!(kvrpcpb::BatchGetResponse);
+has_pair_error!(kvrpcpb::ScanResponse);
 
 macro_rules! has_str_error {
     ($type:ty) => {
@@ -149,8 +188,8 @@ has_str_error!(kvrpcpb::RawBatchPutResponse);
 has_str_error!(kvrpcpb::RawDeleteResponse);
 has_str_error!(kvrpcpb::RawBatchDeleteResponse);
 has_str_error!(kvrpcpb::RawDeleteRangeResponse);
-has_str_error!(kvrpcpb::ImportResponse);
-has_str_error!(kvrpcpb::DeleteRangeResponse);
+has_str_error!(kvrpcpb::RawCASResponse);
+has_str_error!(kvrpcpb::RawGetKeyTTLResponse);
 
 macro_rules! has_no_error {
     ($type:ty) => {
@@ -162,9 +201,6 @@ macro_rules! has_no_error {
     };
 }
 
-has_no_error!(kvrpcpb::ScanResponse);
-has_no_error!(kvrpcpb::PrewriteResponse);
-has_no_error!(kvrpcpb::BatchGetResponse);
 has_no_error!(kvrpcpb::RawBatchGetResponse);
 has_no_error!(kvrpcpb::RawScanResponse);
 has_no_error!(kvrpcpb::RawBatchScanResponse);
@@ -228,6 +264,10 @@ pub struct KvClient {
     client: Arc<TikvClient>,
     timeout: Duration,
     address: String,
+    /// The health of the store, shared by every connection to it.
+    health: Arc<StoreHealth>,
+    /// Set once the transport of a request failed, after which the connection is replaced.
+    broken: Arc<AtomicBool>,
 }
 
 impl KvClient {
@@ -236,21 +276,29 @@ impl KvClient {
         addr: &str,
         security_mgr: &Arc<SecurityManager>,
         timeout: Duration,
+        health: Arc<StoreHealth>,
     ) -> Result<KvClient> {
         let client = Arc::new(security_mgr.connect(env, addr, TikvClient::new)?);
         Ok(KvClient {
             client,
             timeout,
             address: addr.to_owned(),
+            health,
+            broken: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Whether the transport of a request sent on this connection failed.
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::SeqCst)
+    }
+
     pub fn kv_get(
         &self,
         context: TxnContext,
         version: u64,
         key: Key,
-    ) -> impl Future<Item = kvrpcpb::GetResponse, Error = Error> {
+    ) -> impl Future<Output = Result<kvrpcpb::GetResponse>> {
         let mut req = txn_request!(context, kvrpcpb::GetRequest);
         req.set_key(key.into_inner());
         req.set_version(version);
@@ -269,13 +317,15 @@ impl KvClient {
         end_key: Key,
         limit: u32,
         key_only: bool,
-    ) -> impl Future<Item = kvrpcpb::ScanResponse, Error = Error> {
+        reverse: bool,
+    ) -> impl Future<Output = Result<kvrpcpb::ScanResponse>> {
         let mut req = txn_request!(context, kvrpcpb::ScanRequest);
         req.set_start_key(start_key.into_inner());
         req.set_end_key(end_key.into_inner());
         req.set_version(version);
         req.set_limit(limit);
         req.set_key_only(key_only);
+        req.set_reverse(reverse);
 
         self.execute(request_context(
             "kv_scan",
@@ -283,21 +333,29 @@ impl KvClient {
         ))
     }
 
+    /// Prewrite `mutations`, each paired with whether its key holds a pessimistic lock of the
+    /// transaction. `for_update_ts` is zero for optimistic transactions.
     pub fn kv_prewrite(
         &self,
         context: TxnContext,
-        mutations: impl Iterator<Item = Mutation>,
+        mutations: impl Iterator<Item = (Mutation, bool)>,
         primary_lock: Key,
         start_version: u64,
         lock_ttl: u64,
+        for_update_ts: u64,
         skip_constraint_check: bool,
-    ) -> impl Future<Item = kvrpcpb::PrewriteResponse, Error = Error> {
+    ) -> impl Future<Output = Result<kvrpcpb::PrewriteResponse>> {
         let mut req = txn_request!(context, kvrpcpb::PrewriteRequest);
-        req.set_mutations(mutations.map(Into::into).collect());
+        let (mutations, is_pessimistic_lock): (Vec<_>, Vec<_>) = mutations.unzip();
+        req.set_mutations(mutations.into_iter().map(Into::into).collect());
         req.set_primary_lock(primary_lock.into_inner());
         req.set_start_version(start_version);
         req.set_lock_ttl(lock_ttl);
         req.set_skip_constraint_check(skip_constraint_check);
+        if for_update_ts != 0 {
+            req.set_is_pessimistic_lock(is_pessimistic_lock);
+            req.set_for_update_ts(for_update_ts);
+        }
 
         self.execute(request_context(
             "kv_prewrite",
@@ -305,36 +363,78 @@ impl KvClient {
         ))
     }
 
-    pub fn kv_commit(
+    /// Lock `keys` for the pessimistic transaction started at `start_version`, waiting up to
+    /// `wait_timeout` for the locks of other transactions to be released.
+    pub fn kv_pessimistic_lock(
         &self,
         context: TxnContext,
         keys: impl Iterator<Item = Key>,
+        primary_lock: Key,
         start_version: u64,
-        commit_version: u64,
-    ) -> impl Future<Item = kvrpcpb::CommitResponse, Error = Error> {
-        let mut req = txn_request!(context, kvrpcpb::CommitRequest);
+        lock_ttl: u64,
+        for_update_ts: u64,
+        wait_timeout: Duration,
+    ) -> impl Future<Output = Result<kvrpcpb::PessimisticLockResponse>> {
+        let mut req = txn_request!(context, kvrpcpb::PessimisticLockRequest);
+        req.set_mutations(
+            keys.map(|key| {
+                let mut mutation = kvrpcpb::Mutation::new();
+                mutation.set_op(kvrpcpb::Op::PessimisticLock);
+                mutation.set_key(key.into_inner());
+                mutation
+            })
+            .collect(),
+        );
+        req.set_primary_lock(primary_lock.into_inner());
+        req.set_start_version(start_version);
+        req.set_lock_ttl(lock_ttl);
+        req.set_for_update_ts(for_update_ts);
+        // A zero wait timeout makes TiKV use its own default, a negative one does not wait.
+        req.set_wait_timeout(cmp::max(wait_timeout.as_millis() as i64, 1));
+
+        // TiKV holds the request back while it waits.
+        self.execute_with_timeout(
+            request_context(
+                "kv_pessimistic_lock",
+                move |cli: Arc<TikvClient>, opt: _| cli.kv_pessimistic_lock_async_opt(&req, opt),
+            ),
+            self.timeout + wait_timeout,
+        )
+    }
+
+    pub fn kv_pessimistic_rollback(
+        &self,
+        context: TxnContext,
+        keys: impl Iterator<Item = Key>,
+        start_version: u64,
+        for_update_ts: u64,
+    ) -> impl Future<Output = Result<kvrpcpb::PessimisticRollbackResponse>> {
+        let mut req = txn_request!(context, kvrpcpb::PessimisticRollbackRequest);
         req.set_keys(keys.map(|x| x.into_inner()).collect());
         req.set_start_version(start_version);
-        req.set_commit_version(commit_version);
+        req.set_for_update_ts(for_update_ts);
 
         self.execute(request_context(
-            "kv_commit",
-            move |cli: Arc<TikvClient>, opt: _| cli.kv_commit_async_opt(&req, opt),
+            "kv_pessimistic_rollback",
+            move |cli: Arc<TikvClient>, opt: _| cli.kv_pessimistic_rollback_async_opt(&req, opt),
         ))
     }
 
-    pub fn kv_import(
+    pub fn kv_commit(
         &self,
-        mutations: impl Iterator<Item = Mutation>,
+        context: TxnContext,
+        keys: impl Iterator<Item = Key>,
+        start_version: u64,
         commit_version: u64,
-    ) -> impl Future<Item = kvrpcpb::ImportResponse, Error = Error> {
-        let mut req = kvrpcpb::ImportRequest::new();
-        req.set_mutations(mutations.map(Into::into).collect());
+    ) -> impl Future<Output = Result<kvrpcpb::CommitResponse>> {
+        let mut req = txn_request!(context, kvrpcpb::CommitRequest);
+        req.set_keys(keys.map(|x| x.into_inner()).collect());
+        req.set_start_version(start_version);
         req.set_commit_version(commit_version);
 
         self.execute(request_context(
-            "kv_import",
-            move |cli: Arc<TikvClient>, opt: _| cli.kv_import_async_opt(&req, opt),
+            "kv_commit",
+            move |cli: Arc<TikvClient>, opt: _| cli.kv_commit_async_opt(&req, opt),
         ))
     }
 
@@ -343,7 +443,7 @@ impl KvClient {
         context: TxnContext,
         key: Key,
         start_version: u64,
-    ) -> impl Future<Item = kvrpcpb::CleanupResponse, Error = Error> {
+    ) -> impl Future<Output = Result<kvrpcpb::CleanupResponse>> {
         let mut req = txn_request!(context, kvrpcpb::CleanupRequest);
         req.set_key(key.into_inner());
         req.set_start_version(start_version);
@@ -359,7 +459,7 @@ impl KvClient {
         context: TxnContext,
         keys: impl Iterator<Item = Key>,
         version: u64,
-    ) -> impl Future<Item = kvrpcpb::BatchGetResponse, Error = Error> {
+    ) -> impl Future<Output = Result<kvrpcpb::BatchGetResponse>> {
         let mut req = txn_request!(context, kvrpcpb::BatchGetRequest);
         req.set_keys(keys.map(|x| x.into_inner()).collect());
         req.set_version(version);
@@ -375,7 +475,7 @@ impl KvClient {
         context: TxnContext,
         keys: impl Iterator<Item = Key>,
         start_version: u64,
-    ) -> impl Future<Item = kvrpcpb::BatchRollbackResponse, Error = Error> {
+    ) -> impl Future<Output = Result<kvrpcpb::BatchRollbackResponse>> {
         let mut req = txn_request!(context, kvrpcpb::BatchRollbackRequest);
         req.set_keys(keys.map(|x| x.into_inner()).collect());
         req.set_start_version(start_version);
@@ -386,31 +486,13 @@ impl KvClient {
         ))
     }
 
-    pub fn kv_scan_lock(
-        &self,
-        context: TxnContext,
-        start_key: Key,
-        max_version: u64,
-        limit: u32,
-    ) -> impl Future<Item = kvrpcpb::ScanLockResponse, Error = Error> {
-        let mut req = txn_request!(context, kvrpcpb::ScanLockRequest);
-        req.set_start_key(start_key.into_inner());
-        req.set_max_version(max_version);
-        req.set_limit(limit);
-
-        self.execute(request_context(
-            "kv_scan_lock",
-            move |cli: Arc<TikvClient>, opt: _| cli.kv_scan_lock_async_opt(&req, opt),
-        ))
-    }
-
     pub fn kv_resolve_lock(
         &self,
         context: TxnContext,
         txn_infos: impl Iterator<Item = TxnInfo>,
         start_version: u64,
         commit_version: u64,
-    ) -> impl Future<Item = kvrpcpb::ResolveLockResponse, Error = Error> {
+    ) -> impl Future<Output = Result<kvrpcpb::ResolveLockResponse>> {
         let mut req = txn_request!(context, kvrpcpb::ResolveLockRequest);
         req.set_start_version(start_version);
         req.set_commit_version(commit_version);
@@ -422,41 +504,7 @@ impl KvClient {
         ))
     }
 
-    pub fn kv_gc(
-        &self,
-        context: TxnContext,
-        safe_point: u64,
-    ) -> impl Future<Item = kvrpcpb::GCResponse, Error = Error> {
-        let mut req = txn_request!(context, kvrpcpb::GCRequest);
-        req.set_safe_point(safe_point);
-
-        self.execute(request_context(
-            "kv_gc",
-            move |cli: Arc<TikvClient>, opt: _| cli.kv_gc_async_opt(&req, opt),
-        ))
-    }
-
-    pub fn kv_delete_range(
-        &self,
-        context: TxnContext,
-        start_key: Key,
-        end_key: Key,
-    ) -> impl Future<Item = kvrpcpb::DeleteRangeResponse, Error = Error> {
-        let mut req = txn_request!(context, kvrpcpb::DeleteRangeRequest);
-        req.set_start_key(start_key.into_inner());
-        req.set_end_key(end_key.into_inner());
-
-        self.execute(request_context(
-            "kv_delete_range",
-            move |cli: Arc<TikvClient>, opt: _| cli.kv_delete_range_async_opt(&req, opt),
-        ))
-    }
-
-    pub fn raw_get(
-        &self,
-        context: RawContext,
-        key: Key,
-    ) -> impl Future<Item = Value, Error = Error> {
+    pub fn raw_get(&self, context: RawContext, key: Key) -> impl Future<Output = Result<Value>> {
         let mut req = raw_request!(context, kvrpcpb::RawGetRequest);
         req.set_key(key.into_inner());
 
@@ -464,14 +512,14 @@ impl KvClient {
             "raw_get",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_get_async_opt(&req, opt),
         ))
-        .map(|mut resp| resp.take_value().into())
+        .map_ok(|mut resp| resp.take_value().into())
     }
 
     pub fn raw_batch_get(
         &self,
         context: RawContext,
         keys: impl Iterator<Item = Key>,
-    ) -> impl Future<Item = Vec<KvPair>, Error = Error> {
+    ) -> impl Future<Output = Result<Vec<KvPair>>> {
         let mut req = raw_request!(context, kvrpcpb::RawBatchGetRequest);
         req.set_keys(keys.map(|x| x.into_inner()).collect());
 
@@ -479,7 +527,7 @@ impl KvClient {
             "raw_batch_get",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_batch_get_async_opt(&req, opt),
         ))
-        .map(|mut resp| Self::convert_from_grpc_pairs(resp.take_pairs()))
+        .map_ok(|mut resp| Self::convert_from_grpc_pairs(resp.take_pairs()))
     }
 
     pub fn raw_put(
@@ -487,38 +535,88 @@ impl KvClient {
         context: RawContext,
         key: Key,
         value: Value,
-    ) -> impl Future<Item = (), Error = Error> {
+        ttl: u64,
+    ) -> impl Future<Output = Result<()>> {
         let mut req = raw_request!(context, kvrpcpb::RawPutRequest);
         req.set_key(key.into_inner());
         req.set_value(value.into_inner());
+        req.set_ttl(ttl);
 
         self.execute(request_context(
             "raw_put",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_put_async_opt(&req, opt),
         ))
-        .map(|_| ())
+        .map_ok(|_| ())
+    }
+
+    pub fn raw_get_key_ttl(
+        &self,
+        context: RawContext,
+        key: Key,
+    ) -> impl Future<Output = Result<Option<u64>>> {
+        let mut req = raw_request!(context, kvrpcpb::RawGetKeyTTLRequest);
+        req.set_key(key.into_inner());
+
+        self.execute(request_context(
+            "raw_get_key_ttl",
+            move |cli: Arc<TikvClient>, opt: _| cli.raw_get_key_ttl_async_opt(&req, opt),
+        ))
+        .map_ok(|resp| {
+            if resp.get_not_found() {
+                None
+            } else {
+                Some(resp.get_ttl())
+            }
+        })
+    }
+
+    pub fn raw_compare_and_swap(
+        &self,
+        context: RawContext,
+        key: Key,
+        expected: Option<Value>,
+        new: Value,
+    ) -> impl Future<Output = Result<(Option<Value>, bool)>> {
+        let mut req = raw_request!(context, kvrpcpb::RawCASRequest);
+        req.set_key(key.into_inner());
+        match expected {
+            Some(value) => req.set_previous_value(value.into_inner()),
+            None => req.set_previous_not_exist(true),
+        }
+        req.set_value(new.into_inner());
+
+        self.execute(request_context(
+            "raw_compare_and_swap",
+            move |cli: Arc<TikvClient>, opt: _| cli.raw_compare_and_swap_async_opt(&req, opt),
+        ))
+        .map_ok(|mut resp| {
+            let previous = if resp.get_previous_not_exist() {
+                None
+            } else {
+                Some(Value::from(resp.take_previous_value()))
+            };
+            (previous, resp.get_succeed())
+        })
     }
 
     pub fn raw_batch_put(
         &self,
         context: RawContext,
         pairs: Vec<KvPair>,
-    ) -> impl Future<Item = (), Error = Error> {
+        ttl: u64,
+    ) -> impl Future<Output = Result<()>> {
         let mut req = raw_request!(context, kvrpcpb::RawBatchPutRequest);
         req.set_pairs(Self::convert_to_grpc_pairs(pairs));
+        req.set_ttl(ttl);
 
         self.execute(request_context(
             "raw_batch_put",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_batch_put_async_opt(&req, opt),
         ))
-        .map(|_| ())
+        .map_ok(|_| ())
     }
 
-    pub fn raw_delete(
-        &self,
-        context: RawContext,
-        key: Key,
-    ) -> impl Future<Item = (), Error = Error> {
+    pub fn raw_delete(&self, context: RawContext, key: Key) -> impl Future<Output = Result<()>> {
         let mut req = raw_request!(context, kvrpcpb::RawDeleteRequest);
         req.set_key(key.into_inner());
 
@@ -526,14 +624,14 @@ impl KvClient {
             "raw_delete",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_delete_async_opt(&req, opt),
         ))
-        .map(|_| ())
+        .map_ok(|_| ())
     }
 
     pub fn raw_batch_delete(
         &self,
         context: RawContext,
         keys: Vec<Key>,
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> impl Future<Output = Result<()>> {
         let mut req = raw_request!(context, kvrpcpb::RawBatchDeleteRequest);
         req.set_keys(keys.into_iter().map(|x| x.into_inner()).collect());
 
@@ -541,7 +639,7 @@ impl KvClient {
             "raw_batch_delete",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_batch_delete_async_opt(&req, opt),
         ))
-        .map(|_| ())
+        .map_ok(|_| ())
     }
 
     pub fn raw_scan(
@@ -551,20 +649,24 @@ impl KvClient {
         end_key: Option<Key>,
         limit: u32,
         key_only: bool,
-    ) -> impl Future<Item = Vec<KvPair>, Error = Error> {
+        reverse: bool,
+    ) -> impl Future<Output = Result<Vec<KvPair>>> {
         let mut req = raw_request!(context, kvrpcpb::RawScanRequest);
-        start_key
-            .map(|k| req.set_start_key(k.into_inner()))
-            .unwrap();
-        end_key.map(|k| req.set_end_key(k.into_inner())).unwrap();
+        if let Some(k) = start_key {
+            req.set_start_key(k.into_inner());
+        }
+        if let Some(k) = end_key {
+            req.set_end_key(k.into_inner());
+        }
         req.set_limit(limit);
         req.set_key_only(key_only);
+        req.set_reverse(reverse);
 
         self.execute(request_context(
             "raw_scan",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_scan_async_opt(&req, opt),
         ))
-        .map(|mut resp| Self::convert_from_grpc_pairs(resp.take_kvs()))
+        .map_ok(|mut resp| Self::convert_from_grpc_pairs(resp.take_kvs()))
     }
 
     pub fn raw_batch_scan(
@@ -573,7 +675,7 @@ impl KvClient {
         ranges: impl Iterator<Item = (Option<Key>, Option<Key>)>,
         each_limit: u32,
         key_only: bool,
-    ) -> impl Future<Item = Vec<KvPair>, Error = Error> {
+    ) -> impl Future<Output = Result<Vec<KvPair>>> {
         let mut req = raw_request!(context, kvrpcpb::RawBatchScanRequest);
         req.set_ranges(Self::convert_to_grpc_ranges(ranges));
         req.set_each_limit(each_limit);
@@ -583,7 +685,7 @@ impl KvClient {
             "raw_batch_scan",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_batch_scan_async_opt(&req, opt),
         ))
-        .map(|mut resp| Self::convert_from_grpc_pairs(resp.take_kvs()))
+        .map_ok(|mut resp| Self::convert_from_grpc_pairs(resp.take_kvs()))
     }
 
     pub fn raw_delete_range(
@@ -591,7 +693,7 @@ impl KvClient {
         context: RawContext,
         start_key: Key,
         end_key: Key,
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> impl Future<Output = Result<()>> {
         let mut req = raw_request!(context, kvrpcpb::RawDeleteRangeRequest);
         req.set_start_key(start_key.into_inner());
         req.set_end_key(end_key.into_inner());
@@ -600,37 +702,66 @@ impl KvClient {
             "raw_delete_range",
             move |cli: Arc<TikvClient>, opt: _| cli.raw_delete_range_async_opt(&req, opt),
         ))
-        .map(|_| ())
+        .map_ok(|_| ())
     }
 
     fn execute<Executor, Resp, RpcFuture>(
+        &self,
+        context: RequestContext<Executor>,
+    ) -> impl Future<Output = Result<Resp>>
+    where
+        Executor: FnOnce(Arc<TikvClient>, CallOption) -> ::grpcio::Result<RpcFuture>,
+        RpcFuture: futures01::Future<Item = Resp, Error = ::grpcio::Error>,
+        Resp: HasRegionError + HasError + Sized + Clone,
+    {
+        self.execute_with_timeout(context, self.timeout)
+    }
+
+    fn execute_with_timeout<Executor, Resp, RpcFuture>(
         &self,
         mut context: RequestContext<Executor>,
-    ) -> impl Future<Item = Resp, Error = Error>
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Resp>>
     where
         Executor: FnOnce(Arc<TikvClient>, CallOption) -> ::grpcio::Result<RpcFuture>,
-        RpcFuture: Future<Item = Resp, Error = ::grpcio::Error>,
+        RpcFuture: futures01::Future<Item = Resp, Error = ::grpcio::Error>,
         Resp: HasRegionError + HasError + Sized + Clone,
     {
         let executor = context.executor();
+        let health = Arc::clone(&self.health);
+        let broken = Arc::clone(&self.broken);
         executor(
             Arc::clone(&self.client),
-            CallOption::default().timeout(self.timeout),
+            CallOption::default().timeout(timeout),
         )
         .unwrap()
-        .then(|r| match r {
-            Err(e) => Err(ErrorKind::Grpc(e))?,
-            Ok(mut r) => {
-                if let Some(e) = r.region_error() {
-                    Err(e)
-                } else if let Some(e) = r.error() {
+        .compat()
+        .map(move |r| {
+            let r = match r {
+                Err(e) => {
+                    let e = Error::from(ErrorKind::Grpc(e));
+                    if e.is_unreachable() {
+                        health.on_failure();
+                    }
+                    // A slow store does not make the connection to it unusable.
+                    if e.is_transport_failure() {
+                        broken.store(true, Ordering::SeqCst);
+                    }
                     Err(e)
-                } else {
-                    Ok(r)
                 }
-            }
+                Ok(mut r) => {
+                    health.on_success();
+                    if let Some(e) = r.region_error() {
+                        Err(e)
+                    } else if let Some(e) = r.error() {
+                        Err(e)
+                    } else {
+                        Ok(r)
+                    }
+                }
+            };
+            context.done(r)
         })
-        .then(move |r| context.done(r))
     }
 
     #[inline]
@@ -665,8 +796,12 @@ impl KvClient {
     fn convert_to_grpc_range(range: (Option<Key>, Option<Key>)) -> kvrpcpb::KeyRange {
         let (start, end) = range;
         let mut range = kvrpcpb::KeyRange::new();
-        start.map(|k| range.set_start_key(k.into_inner())).unwrap();
-        end.map(|k| range.set_end_key(k.into_inner())).unwrap();
+        if let Some(k) = start {
+            range.set_start_key(k.into_inner());
+        }
+        if let Some(k) = end {
+            range.set_end_key(k.into_inner());
+        }
         range
     }
 
@@ -0,0 +1,94 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use crate::rpc::util::HandyRwLock;
+
+/// The number of consecutive failed requests after which a store is considered down.
+const STORE_DOWN_FAILURES: u32 = 3;
+/// How long a store is considered down after its last failure. Once it is over, requests are sent
+/// to the store again, so it is noticed when it comes back.
+const STORE_DOWN_DURATION: Duration = Duration::from_secs(10);
+
+#[derive(Default, Debug)]
+struct HealthState {
+    consecutive_failures: u32,
+    last_success: Option<Instant>,
+    last_failure: Option<Instant>,
+}
+
+/// The health of a TiKV store, as seen from the requests this client sent to it.
+///
+/// Only failures to reach the store count, any response from TiKV counts as a success.
+#[derive(Default, Debug)]
+pub struct StoreHealth {
+    state: RwLock<HealthState>,
+}
+
+impl StoreHealth {
+    pub fn on_success(&self) {
+        let mut state = self.state.wl();
+        state.consecutive_failures = 0;
+        state.last_success = Some(Instant::now());
+    }
+
+    pub fn on_failure(&self) {
+        let mut state = self.state.wl();
+        state.consecutive_failures += 1;
+        state.last_failure = Some(Instant::now());
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.state.rl().consecutive_failures
+    }
+
+    pub fn last_success(&self) -> Option<Instant> {
+        self.state.rl().last_success
+    }
+
+    /// Whether the store has failed enough requests lately that it should be avoided.
+    pub fn is_down(&self) -> bool {
+        let state = self.state.rl();
+        state.consecutive_failures >= STORE_DOWN_FAILURES
+            && state
+                .last_failure
+                .map_or(false, |at| at.elapsed() < STORE_DOWN_DURATION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_health() {
+        let health = StoreHealth::default();
+        assert!(!health.is_down());
+        for _ in 0..STORE_DOWN_FAILURES {
+            assert!(!health.is_down());
+            health.on_failure();
+        }
+        assert!(health.is_down());
+        assert_eq!(health.consecutive_failures(), STORE_DOWN_FAILURES);
+        assert!(health.last_success().is_none());
+
+        health.on_success();
+        assert!(!health.is_down());
+        assert_eq!(health.consecutive_failures(), 0);
+        assert!(health.last_success().is_some());
+    }
+}
@@ -13,5 +13,6 @@
 
 pub mod client;
 pub mod context;
+mod health;
 
-pub use self::client::KvClient;
+pub use self::{client::KvClient, health::StoreHealth};
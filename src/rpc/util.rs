@@ -12,14 +12,11 @@
 // limitations under the License.
 
 use std::{
-    sync::{mpsc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
     thread,
     time::Duration,
 };
 
-use lazy_static::*;
-use tokio_timer::{self, timer::Handle};
-
 macro_rules! internal_err {
     ($e:expr) => ({
         let kind = $crate::Error::internal_error(
@@ -73,29 +70,9 @@ pub fn duration_to_sec(d: Duration) -> f64 {
     d.as_secs() as f64 + (nanos / 1_000_000_000.0)
 }
 
-lazy_static! {
-    pub static ref GLOBAL_TIMER_HANDLE: Handle = start_global_timer();
-}
-
-fn start_global_timer() -> Handle {
-    let (tx, rx) = mpsc::channel();
-    thread::Builder::new()
-        .name(thd_name!("timer"))
-        .spawn(move || {
-            let mut timer = tokio_timer::Timer::default();
-            tx.send(timer.handle()).unwrap();
-            loop {
-                timer.turn(None).unwrap();
-            }
-        })
-        .unwrap();
-    rx.recv().unwrap()
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::Future;
     use std::*;
 
     #[test]
@@ -134,14 +111,4 @@ mod tests {
             format!("[{}:{}]: hi", file_name, line_number + 1)
         );
     }
-
-    #[test]
-    fn test_global_timer() {
-        let handle = super::GLOBAL_TIMER_HANDLE.clone();
-        let delay =
-            handle.delay(::std::time::Instant::now() + ::std::time::Duration::from_millis(100));
-        let timer = ::std::time::Instant::now();
-        delay.wait().unwrap();
-        assert!(timer.elapsed() >= ::std::time::Duration::from_millis(100));
-    }
 }
@@ -0,0 +1,64 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Result;
+use futures::{executor::ThreadPool, prelude::*, task::SpawnExt};
+
+const RUNTIME_THREAD_PREFIX: &str = "tikv-client-sync-";
+
+/// The executor owned by the blocking clients.
+///
+/// Requests are driven on the runtime's own threads while the calling thread blocks on the
+/// result, so callers never need to set up an executor of their own.
+#[derive(Clone)]
+pub(crate) struct Runtime {
+    pool: ThreadPool,
+}
+
+impl Runtime {
+    pub fn new() -> Result<Runtime> {
+        let pool = ThreadPool::builder()
+            .name_prefix(RUNTIME_THREAD_PREFIX)
+            .create()?;
+        Ok(Runtime { pool })
+    }
+
+    /// Run `future` to completion on the runtime, blocking the current thread until it resolves.
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        let handle = self
+            .pool
+            .spawn_with_handle(future)
+            .expect("The runtime thread pool has shut down");
+        futures::executor::block_on(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn test_block_on() {
+        let runtime = Runtime::new().unwrap();
+        assert_eq!(runtime.block_on(future::ready(42)), 42);
+        let name = runtime.block_on(future::lazy(|_| {
+            std::thread::current().name().map(ToOwned::to_owned)
+        }));
+        assert!(name.unwrap().starts_with(RUNTIME_THREAD_PREFIX));
+    }
+}
@@ -20,87 +20,180 @@
 //! **Warning:** It is not advisable to use both raw and transactional functionality in the same keyspace.
 //!
 
-use crate::{Config, Error, Key, KvPair, Value};
-use futures::{Future, Poll, Stream};
-use std::ops::RangeBounds;
+use crate::{
+    rpc::RpcClient, runtime::Runtime, Config, Error, Key, KvFuture, KvPair, Result, Value,
+};
+use futures::{
+    future,
+    prelude::*,
+    task::{Context, Poll},
+};
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    cmp::{self, Ordering},
+    collections::{BTreeMap, BTreeSet},
+    fmt, iter,
+    ops::{Bound, RangeBounds},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+    vec,
+};
+
+/// The least time to live of the locks a transaction leaves on its keys while committing, in
+/// milliseconds. Once it expires, other transactions may clean up the locks.
+const TXN_LOCK_TTL: u64 = 3000;
+
+/// The upper bound of the time to live of transaction locks, in milliseconds.
+const MAX_TXN_LOCK_TTL: u64 = 120_000;
+
+/// How the time to live of transaction locks grows with the square root of the transaction size
+/// in MiB, so that large transactions have time to finish prewriting before they are cleaned up.
+const TXN_LOCK_TTL_FACTOR: f64 = 6000.0;
+
+/// The time to live of pessimistic locks, in milliseconds, counted from when they were acquired.
+/// It is longer than the default lock wait timeout, so that other transactions give up waiting
+/// before the locks of a live transaction could be cleaned up.
+const PESSIMISTIC_LOCK_TTL: u64 = 20000;
+
+/// The number of pairs a [`Scanner`](struct.Scanner.html) fetches per request by default.
+const DEFAULT_SCAN_BATCH_SIZE: u32 = 256;
 
 /// The TiKV transactional [`Client`](struct.Client.html) is used to issue requests to the TiKV server and PD cluster.
-pub struct Client;
+pub struct Client {
+    rpc: Arc<RpcClient>,
+}
 
 impl Client {
     /// Create a new [`Client`](struct.Client.html) once the [`Connect`](struct.Connect.html) resolves.
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, transaction::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait();
+    /// let client = block_on(connect);
     /// ```
     #[cfg_attr(feature = "cargo-clippy", allow(clippy::new_ret_no_self))]
     pub fn new(config: Config) -> Connect {
         Connect::new(config)
     }
 
+    #[inline]
+    fn rpc(&self) -> Arc<RpcClient> {
+        Arc::clone(&self.rpc)
+    }
+
     /// Create a new [`Transaction`](struct.Transaction.html) using the timestamp from [`current_timestamp`](struct.Client.html#method.current_timestamp).
     ///
-    /// Using the transaction you can issue commands like [`get`](struct.Transaction.html#method.get) or [`set`](file:///home/hoverbear/git/client-rust/target/doc/tikv_client/transaction/struct.Transaction.html#method.set).
+    /// Using the transaction you can issue commands like [`get`](struct.Transaction.html#method.get) or [`set`](struct.Transaction.html#method.set).
+    ///
+    /// ```rust,no_run
+    /// use tikv_client::{Config, transaction::Client};
+    /// use futures::executor::block_on;
+    /// let connect = Client::new(Config::default());
+    /// let client = block_on(connect).unwrap();
+    /// let transaction = block_on(client.begin()).unwrap();
+    /// // ... Issue some commands.
+    /// let commit = transaction.commit();
+    /// let result: () = block_on(commit).unwrap();
+    /// ```
+    pub fn begin(&self) -> KvFuture<Transaction> {
+        let rpc = self.rpc();
+        Box::pin(async move {
+            let timestamp = rpc.get_timestamp().await?;
+            Ok(Transaction::new(Snapshot::new(rpc, timestamp)))
+        })
+    }
+
+    /// Create a new pessimistic [`Transaction`](struct.Transaction.html) using the timestamp from
+    /// [`current_timestamp`](struct.Client.html#method.current_timestamp).
+    ///
+    /// A pessimistic transaction locks keys on TiKV as soon as
+    /// [`lock_keys`](struct.Transaction.html#method.lock_keys) or
+    /// [`get_for_update`](struct.Transaction.html#method.get_for_update) is called, rather than
+    /// when it commits, so the writes it makes to these keys can not conflict with other
+    /// transactions.
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, transaction::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait().unwrap();
-    /// let transaction = client.begin();
+    /// let client = block_on(connect).unwrap();
+    /// let mut transaction = block_on(client.begin_pessimistic()).unwrap();
+    /// let value = block_on(transaction.get_for_update("TiKV")).unwrap();
     /// // ... Issue some commands.
     /// let commit = transaction.commit();
-    /// let result: () = commit.wait().unwrap();
+    /// let result: () = block_on(commit).unwrap();
     /// ```
-    pub fn begin(&self) -> Transaction {
-        unimplemented!()
+    pub fn begin_pessimistic(&self) -> KvFuture<Transaction> {
+        let rpc = self.rpc();
+        Box::pin(async move {
+            let timestamp = rpc.get_timestamp().await?;
+            Ok(Transaction::new_pessimistic(Snapshot::new(rpc, timestamp)))
+        })
     }
 
     /// Create a new [`Transaction`](struct.Transaction.html) at the provded timestamp.
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, transaction::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait().unwrap();
-    /// let timestamp = client.current_timestamp();
+    /// let client = block_on(connect).unwrap();
+    /// let timestamp = block_on(client.current_timestamp()).unwrap();
     /// let transaction = client.begin_with_timestamp(timestamp);
     /// // ... Issue some commands.
     /// let commit = transaction.commit();
-    /// let result: () = commit.wait().unwrap();
+    /// let result: () = block_on(commit).unwrap();
+    /// ```
+    ///
+    /// An older timestamp gives a read of slightly stale data, which any replica can serve:
+    ///
+    /// ```rust,no_run
+    /// use tikv_client::{Config, transaction::Client};
+    /// use futures::executor::block_on;
+    /// use std::time::Duration;
+    /// let connect = Client::new(Config::default());
+    /// let client = block_on(connect).unwrap();
+    /// let timestamp = block_on(client.current_timestamp()).unwrap();
+    /// let stale = timestamp.checked_sub(Duration::from_secs(5)).unwrap();
+    /// let transaction = client.begin_with_timestamp(stale);
     /// ```
-    pub fn begin_with_timestamp(&self, _timestamp: Timestamp) -> Transaction {
-        unimplemented!()
+    pub fn begin_with_timestamp(&self, timestamp: Timestamp) -> Transaction {
+        Transaction::new(Snapshot::new(self.rpc(), timestamp))
     }
 
     /// Get a [`Snapshot`](struct.Snapshot.html) using the timestamp from [`current_timestamp`](struct.Client.html#method.current_timestamp).
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, transaction::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait().unwrap();
-    /// let snapshot = client.snapshot();
+    /// let client = block_on(connect).unwrap();
+    /// let snapshot = block_on(client.snapshot()).unwrap();
     /// // ... Issue some commands.
     /// ```
-    pub fn snapshot(&self) -> Snapshot {
-        unimplemented!()
+    pub fn snapshot(&self) -> KvFuture<Snapshot> {
+        let rpc = self.rpc();
+        Box::pin(async move {
+            let timestamp = rpc.get_timestamp().await?;
+            Ok(Snapshot::new(rpc, timestamp))
+        })
     }
 
     /// Retrieve the current [`Timestamp`](struct.Timestamp.html).
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, transaction::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait().unwrap();
-    /// let timestamp = client.current_timestamp();
+    /// let client = block_on(connect).unwrap();
+    /// let timestamp = block_on(client.current_timestamp()).unwrap();
     /// ```
-    pub fn current_timestamp(&self) -> Timestamp {
-        unimplemented!()
+    pub fn current_timestamp(&self) -> KvFuture<Timestamp> {
+        let rpc = self.rpc();
+        Box::pin(async move { rpc.get_timestamp().await })
     }
 }
 
@@ -110,10 +203,10 @@ impl Client {
 ///
 /// ```rust,no_run
 /// use tikv_client::{Config, transaction::{Client, Connect}};
-/// use futures::Future;
+/// use futures::executor::block_on;
 ///
 /// let connect: Connect = Client::new(Config::default());
-/// let client: Client = connect.wait().unwrap();
+/// let client: Client = block_on(connect).unwrap();
 /// ```
 pub struct Connect {
     config: Config,
@@ -125,6 +218,7 @@ impl Connect {
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum Mutation {
     Put(Key, Value),
     Del(Key),
@@ -132,23 +226,207 @@ pub enum Mutation {
     Rollback(Key),
 }
 
+impl Mutation {
+    /// Returns the key the mutation applies to.
+    pub fn key(&self) -> &Key {
+        match self {
+            Mutation::Put(key, _)
+            | Mutation::Del(key)
+            | Mutation::Lock(key)
+            | Mutation::Rollback(key) => key,
+        }
+    }
+
+    /// Returns the number of bytes the mutation writes.
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Mutation::Put(key, value) => key.len() + value.len(),
+            _ => self.key().len(),
+        }
+    }
+}
+
 pub struct TxnInfo {
     pub txn: u64,
     pub status: u64,
 }
 
 impl Future for Connect {
-    type Item = Client;
-    type Error = Error;
+    type Output = Result<Client>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _config = &self.config;
-        unimplemented!()
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+        let config = &self.config;
+        let rpc = RpcClient::connect(config).map(Arc::new);
+        Poll::Ready(rpc.map(|rpc| Client { rpc }))
     }
 }
 
+/// A blocking wrapper around the transactional [`Client`](struct.Client.html).
+///
+/// The [`SyncClient`](struct.SyncClient.html) owns the runtime its requests are driven on, so it
+/// can be used from code which has no executor of its own. Each method blocks the calling thread
+/// until the request resolves.
+///
+/// ```rust,no_run
+/// use tikv_client::{Config, transaction::SyncClient};
+///
+/// let client = SyncClient::new(Config::default()).unwrap();
+/// let mut txn = client.begin().unwrap();
+/// txn.set("TiKV", "Rust").unwrap();
+/// txn.commit().unwrap();
+/// ```
+pub struct SyncClient {
+    client: Client,
+    runtime: Runtime,
+}
+
+impl SyncClient {
+    /// Create a new [`SyncClient`](struct.SyncClient.html), blocking until it is connected.
+    pub fn new(config: Config) -> Result<SyncClient> {
+        let runtime = Runtime::new()?;
+        let client = runtime.block_on(Client::new(config))?;
+        Ok(SyncClient { client, runtime })
+    }
+
+    /// Begin a new [`SyncTransaction`](struct.SyncTransaction.html) at the current timestamp.
+    ///
+    /// See [`Client::begin`](struct.Client.html#method.begin).
+    pub fn begin(&self) -> Result<SyncTransaction> {
+        let txn = self.runtime.block_on(self.client.begin())?;
+        Ok(SyncTransaction::new(txn, self.runtime.clone()))
+    }
+
+    /// Begin a new pessimistic [`SyncTransaction`](struct.SyncTransaction.html) at the current
+    /// timestamp.
+    ///
+    /// See [`Client::begin_pessimistic`](struct.Client.html#method.begin_pessimistic).
+    pub fn begin_pessimistic(&self) -> Result<SyncTransaction> {
+        let txn = self.runtime.block_on(self.client.begin_pessimistic())?;
+        Ok(SyncTransaction::new(txn, self.runtime.clone()))
+    }
+
+    /// Retrieve the current [`Timestamp`](struct.Timestamp.html).
+    ///
+    /// See [`Client::current_timestamp`](struct.Client.html#method.current_timestamp).
+    pub fn current_timestamp(&self) -> Result<Timestamp> {
+        self.runtime.block_on(self.client.current_timestamp())
+    }
+}
+
+/// A blocking wrapper around a [`Transaction`](struct.Transaction.html), created by
+/// [`SyncClient::begin`](struct.SyncClient.html#method.begin).
+pub struct SyncTransaction {
+    txn: Transaction,
+    runtime: Runtime,
+}
+
+impl SyncTransaction {
+    fn new(txn: Transaction, runtime: Runtime) -> Self {
+        SyncTransaction { txn, runtime }
+    }
+
+    /// Returns the timestamp which the transaction started at.
+    pub fn start_ts(&self) -> Timestamp {
+        self.txn.start_ts()
+    }
+
+    /// Returns whether the transaction has not buffered any writes.
+    pub fn is_readonly(&self) -> bool {
+        self.txn.is_readonly()
+    }
+
+    /// Fetch the value associated with the given key.
+    ///
+    /// See [`Transaction::get`](struct.Transaction.html#method.get).
+    pub fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.runtime.block_on(self.txn.get(key))
+    }
+
+    /// Fetch the values associated with the given keys.
+    ///
+    /// See [`Transaction::batch_get`](struct.Transaction.html#method.batch_get).
+    pub fn batch_get(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Result<Vec<KvPair>> {
+        self.runtime.block_on(self.txn.batch_get(keys))
+    }
+
+    /// Fetch up to `limit` pairs in the given range, in ascending order.
+    ///
+    /// See [`Transaction::scan`](struct.Transaction.html#method.scan).
+    pub fn scan(&self, range: impl RangeBounds<Key>, limit: u32) -> Result<Vec<KvPair>> {
+        let scanner = self.txn.scan(range).take(limit as usize);
+        self.runtime.block_on(scanner.try_collect())
+    }
+
+    /// Fetch up to `limit` pairs in the given range, in descending order.
+    ///
+    /// See [`Transaction::scan_reverse`](struct.Transaction.html#method.scan_reverse).
+    pub fn scan_reverse(&self, range: impl RangeBounds<Key>, limit: u32) -> Result<Vec<KvPair>> {
+        let scanner = self.txn.scan_reverse(range).take(limit as usize);
+        self.runtime.block_on(scanner.try_collect())
+    }
+
+    /// Set the value associated with the given key.
+    ///
+    /// See [`Transaction::set`](struct.Transaction.html#method.set).
+    pub fn set(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.runtime.block_on(self.txn.set(key, value))
+    }
+
+    /// Delete the given key.
+    ///
+    /// See [`Transaction::delete`](struct.Transaction.html#method.delete).
+    pub fn delete(&mut self, key: impl Into<Key>) -> Result<()> {
+        self.runtime.block_on(self.txn.delete(key))
+    }
+
+    /// Lock the given keys.
+    ///
+    /// See [`Transaction::lock_keys`](struct.Transaction.html#method.lock_keys).
+    pub fn lock_keys(&mut self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Result<()> {
+        self.runtime.block_on(self.txn.lock_keys(keys))
+    }
+
+    /// Lock the given key and fetch its latest value.
+    ///
+    /// See [`Transaction::get_for_update`](struct.Transaction.html#method.get_for_update).
+    pub fn get_for_update(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.runtime.block_on(self.txn.get_for_update(key))
+    }
+
+    /// Commit the actions of the transaction.
+    ///
+    /// See [`Transaction::commit`](struct.Transaction.html#method.commit).
+    pub fn commit(self) -> Result<()> {
+        self.runtime.block_on(self.txn.commit())
+    }
+
+    /// Rollback the actions of the transaction.
+    ///
+    /// See [`Transaction::rollback`](struct.Transaction.html#method.rollback).
+    pub fn rollback(self) -> Result<()> {
+        self.runtime.block_on(self.txn.rollback())
+    }
+}
+
+/// The number of low bits of a [`Timestamp`](struct.Timestamp.html) holding the logical part.
+const PHYSICAL_SHIFT_BITS: i64 = 18;
+const LOGICAL_MASK: i64 = (1 << PHYSICAL_SHIFT_BITS) - 1;
+
 /// A logical timestamp produced by PD.
-#[derive(Copy, Clone)]
+///
+/// Its high bits hold the physical time, in milliseconds since the Unix epoch, and its low 18 bits
+/// a logical counter telling apart the timestamps of the same millisecond. Timestamps are ordered
+/// by time, and serialized as their `u64` value.
+///
+/// ```rust
+/// # use tikv_client::transaction::Timestamp;
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// let timestamp = Timestamp::from(UNIX_EPOCH + Duration::from_millis(1_560_000_000_123));
+/// assert_eq!(timestamp.physical(), 1_560_000_000_123);
+/// assert_eq!(timestamp.to_string(), "2019-06-08T13:20:00.123Z#0");
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Timestamp(u64);
 
 impl From<u64> for Timestamp {
@@ -158,27 +436,168 @@ impl From<u64> for Timestamp {
 }
 
 impl Timestamp {
+    pub(crate) fn compose(physical: i64, logical: i64) -> Self {
+        Timestamp(((physical << PHYSICAL_SHIFT_BITS) + logical) as u64)
+    }
+
     pub fn timestamp(self) -> u64 {
         self.0
     }
 
     pub fn physical(self) -> i64 {
-        (self.0 >> 16) as i64
+        self.0 as i64 >> PHYSICAL_SHIFT_BITS
     }
 
     pub fn logical(self) -> i64 {
-        (self.0 & 0xFFFF as u64) as i64
+        self.0 as i64 & LOGICAL_MASK
+    }
+
+    /// The timestamp `duration` later, with the same logical part.
+    ///
+    /// Returns `None` if the physical time overflows.
+    pub fn checked_add(self, duration: Duration) -> Option<Timestamp> {
+        let millis = duration_millis(duration)?;
+        let physical = self.physical().checked_add(millis)?;
+        Timestamp::checked_compose(physical, self.logical())
+    }
+
+    /// The timestamp `duration` earlier, with the same logical part.
+    ///
+    /// Returns `None` if it would be before the Unix epoch.
+    pub fn checked_sub(self, duration: Duration) -> Option<Timestamp> {
+        let millis = duration_millis(duration)?;
+        let physical = self.physical().checked_sub(millis)?;
+        Timestamp::checked_compose(physical, self.logical())
+    }
+
+    /// The physical time elapsed from `earlier` to this timestamp, or `None` if `earlier` is
+    /// later.
+    pub fn duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        let millis = self.physical().checked_sub(earlier.physical())?;
+        if millis < 0 {
+            return None;
+        }
+        Some(Duration::from_millis(millis as u64))
+    }
+
+    fn checked_compose(physical: i64, logical: i64) -> Option<Timestamp> {
+        if physical < 0 || physical > (i64::max_value() >> PHYSICAL_SHIFT_BITS) {
+            return None;
+        }
+        Some(Timestamp::compose(physical, logical))
+    }
+}
+
+fn duration_millis(duration: Duration) -> Option<i64> {
+    let millis = duration
+        .as_secs()
+        .checked_mul(1000)?
+        .checked_add(u64::from(duration.subsec_millis()))?;
+    if millis > i64::max_value() as u64 {
+        return None;
+    }
+    Some(millis as i64)
+}
+
+/// The timestamp of a time with a zero logical part. Times before the Unix epoch map to the epoch.
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(duration_millis)
+            .unwrap_or(0);
+        Timestamp::checked_compose(millis, 0)
+            .unwrap_or_else(|| Timestamp::compose(i64::max_value() >> PHYSICAL_SHIFT_BITS, 0))
+    }
+}
+
+/// The physical time of a timestamp, dropping its logical part.
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        UNIX_EPOCH + Duration::from_millis(timestamp.0 >> PHYSICAL_SHIFT_BITS)
+    }
+}
+
+impl fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Timestamp")
+            .field("physical", &self.physical())
+            .field("logical", &self.logical())
+            .finish()
     }
 }
 
+/// Formats the physical time as an RFC 3339 UTC date, followed by `#` and the logical part.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let physical = (self.0 >> PHYSICAL_SHIFT_BITS) as i64;
+        let (days, millis) = (physical / 86_400_000, physical % 86_400_000);
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z#{}",
+            year,
+            month,
+            day,
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000,
+            self.logical()
+        )
+    }
+}
+
+/// The Gregorian calendar date `days` after the Unix epoch, which must not be negative.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
 /// A undo-able set of actions on the dataset.
 ///
 /// Using a transaction you can prepare a set of actions (such as `get`, or `set`) on data at a
 /// particular timestamp obtained from the placement driver.
 ///
 /// Once a transaction is commited, a new commit timestamp is obtained from the placement driver.
+///
+/// Writes are buffered in the transaction and only sent to TiKV when it is committed. Reads
+/// through the transaction see its own buffered writes, merged over the snapshot.
+///
+/// A pessimistic transaction, created by
+/// [`Client::begin_pessimistic`](struct.Client.html#method.begin_pessimistic), also locks keys on
+/// TiKV before committing, see [`lock_keys`](struct.Transaction.html#method.lock_keys).
 pub struct Transaction {
     snapshot: Snapshot,
+    isolation_level: IsolationLevel,
+    mutations: BTreeMap<Key, Mutation>,
+    /// The locks acquired on TiKV, for a pessimistic transaction.
+    pessimistic: Option<Arc<RwLock<PessimisticLocks>>>,
+}
+
+/// The pessimistic locks a transaction holds on TiKV.
+#[derive(Default)]
+struct PessimisticLocks {
+    /// The primary key of the transaction, which is the first key it locked successfully.
+    primary: Option<Key>,
+    keys: BTreeSet<Key>,
+    /// The latest timestamp keys were locked at.
+    for_update_ts: u64,
 }
 
 impl Transaction {
@@ -186,13 +605,25 @@ impl Transaction {
     ///
     /// ```rust,no_run
     /// use tikv_client::{Config, transaction::Client};
-    /// use futures::Future;
+    /// use futures::executor::block_on;
     /// let connect = Client::new(Config::default());
-    /// let client = connect.wait().unwrap();
-    /// let txn = client.begin();
+    /// let client = block_on(connect).unwrap();
+    /// let txn = block_on(client.begin()).unwrap();
     /// ```
     pub fn new(snapshot: Snapshot) -> Self {
-        Self { snapshot }
+        Self {
+            snapshot,
+            isolation_level: IsolationLevel::SnapshotIsolation,
+            mutations: BTreeMap::new(),
+            pessimistic: None,
+        }
+    }
+
+    fn new_pessimistic(snapshot: Snapshot) -> Self {
+        Self {
+            pessimistic: Some(Default::default()),
+            ..Transaction::new(snapshot)
+        }
     }
 
     /// Commit the actions of the transaction.
@@ -201,13 +632,13 @@ impl Transaction {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connect = Client::new(Config::default());
-    /// # let connected_client = connect.wait().unwrap();
-    /// let txn = connected_client.begin();
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let txn = block_on(connected_client.begin()).unwrap();
     /// // ... Do some actions.
     /// let req = txn.commit();
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn commit(self) -> Commit {
         Commit::new(self)
@@ -217,13 +648,13 @@ impl Transaction {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connect = Client::new(Config::default());
-    /// # let connected_client = connect.wait().unwrap();
-    /// let txn = connected_client.begin();
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let txn = block_on(connected_client.begin()).unwrap();
     /// // ... Do some actions.
     /// let req = txn.rollback();
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn rollback(self) -> Rollback {
         Rollback::new(self)
@@ -231,87 +662,160 @@ impl Transaction {
 
     /// Lock the given keys.
     ///
+    /// A pessimistic transaction locks the keys on TiKV at a fresh timestamp from PD, waiting up
+    /// to the [lock wait timeout](../struct.Config.html#method.lock_wait_timeout) for other
+    /// transactions holding them. The request fails with
+    /// [`ErrorKind::Deadlock`](../enum.ErrorKind.html) if this transaction and the one holding a
+    /// lock are waiting for each other; the transaction should then be rolled back. The locks are
+    /// released when the transaction commits or rolls back.
+    ///
+    /// An optimistic transaction buffers the locks like writes, and the keys are locked when it
+    /// is committed. The commit then fails if another transaction wrote any of the keys since this
+    /// one started, even if this one does not write them.
+    ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connect = Client::new(Config::default());
-    /// # let connected_client = connect.wait().unwrap();
-    /// let mut txn = connected_client.begin();
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
     /// // ... Do some actions.
     /// let req = txn.lock_keys(vec!["TiKV", "Rust"]);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// ```
     pub fn lock_keys(&mut self, keys: impl IntoIterator<Item = impl Into<Key>>) -> LockKeys {
-        LockKeys::new(keys.into_iter().map(|v| v.into()).collect())
+        LockKeys::new(self.lock_keys_inner(keys.into_iter().map(Into::into).collect()))
     }
 
+    /// Create a new [`GetForUpdate`](struct.GetForUpdate.html) request.
+    ///
+    /// Once resolved this request will result in the locking of the given key, as
+    /// [`lock_keys`](struct.Transaction.html#method.lock_keys) does, and the fetching of its
+    /// value. A pessimistic transaction reads the latest value, committed before the lock was
+    /// acquired, rather than the value at its start timestamp.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, Config, transaction::Client};
+    /// # use futures::executor::block_on;
+    /// # let connect = Client::new(Config::default());
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let mut txn = block_on(connected_client.begin_pessimistic()).unwrap();
+    /// let req = txn.get_for_update("TiKV");
+    /// let result: Option<Value> = block_on(req).unwrap();
+    /// // Finish the transaction...
+    /// block_on(txn.commit()).unwrap();
+    /// ```
+    pub fn get_for_update(&mut self, key: impl Into<Key>) -> GetForUpdate {
+        let key = key.into();
+        let lock = self.lock_keys_inner(vec![key.clone()]);
+        let get =
+            GetInner::new(self.snapshot.clone(), key.clone()).buffered(self.buffered_value(&key));
+        GetForUpdate::new(GetForUpdateInner { lock, get })
+    }
+
+    fn lock_keys_inner(&mut self, keys: Vec<Key>) -> LockKeysInner {
+        if self.pessimistic.is_none() {
+            for key in &keys {
+                // A key written in the transaction is locked by its write already.
+                self.mutations
+                    .entry(key.clone())
+                    .or_insert_with(|| Mutation::Lock(key.clone()));
+            }
+        }
+        LockKeysInner {
+            snapshot: self.snapshot.clone(),
+            keys,
+            locks: self.pessimistic.clone(),
+        }
+    }
+
+    /// Returns whether the transaction locks keys on TiKV before committing.
+    pub fn is_pessimistic(&self) -> bool {
+        self.pessimistic.is_some()
+    }
+
+    /// Returns whether the transaction has not buffered any writes.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, transaction::Client};
+    /// # use futures::executor::block_on;
+    /// # let connect = Client::new(Config::default());
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
+    /// assert!(txn.is_readonly());
+    /// txn.set("TiKV", "Rust");
+    /// assert!(!txn.is_readonly());
+    /// ```
     pub fn is_readonly(&self) -> bool {
-        unimplemented!()
+        self.mutations.is_empty()
     }
 
     /// Returns the timestamp which the transaction started at.
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, transaction::{Client, Timestamp}};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connect = Client::new(Config::default());
-    /// # let connected_client = connect.wait().unwrap();
-    /// let txn = connected_client.begin();
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let txn = block_on(connected_client.begin()).unwrap();
     /// // ... Do some actions.
     /// let ts: Timestamp = txn.start_ts();
     /// ```
     pub fn start_ts(&self) -> Timestamp {
-        unimplemented!()
+        self.snapshot.timestamp
     }
 
     /// Get the `Snapshot` the transaction is operating on.
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, transaction::{Client, Snapshot}};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connect = Client::new(Config::default());
-    /// # let connected_client = connect.wait().unwrap();
-    /// let txn = connected_client.begin();
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let txn = block_on(connected_client.begin()).unwrap();
     /// // ... Do some actions.
     /// let snap: Snapshot = txn.snapshot();
     /// ```
     pub fn snapshot(&self) -> Snapshot {
-        unimplemented!()
+        self.snapshot.clone()
     }
 
     /// Set the isolation level of the transaction.
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Config, transaction::{Client, IsolationLevel}};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connect = Client::new(Config::default());
-    /// # let connected_client = connect.wait().unwrap();
-    /// let mut txn = connected_client.begin();
+    /// # let connected_client = block_on(connect).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
     /// txn.set_isolation_level(IsolationLevel::SnapshotIsolation);
     /// ```
-    pub fn set_isolation_level(&mut self, _level: IsolationLevel) {
-        unimplemented!()
+    pub fn set_isolation_level(&mut self, level: IsolationLevel) {
+        self.isolation_level = level;
     }
 
     /// Create a new [`Get`](struct.Get.html) request.
     ///
     /// Once resolved this request will result in the fetching of the value associated with the
-    /// given key.
+    /// given key. A key written in the transaction resolves to the buffered value, or to `None`
+    /// if it was deleted, without a request to TiKV.
     ///
     /// ```rust,no_run
     /// # use tikv_client::{Value, Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
-    /// let mut txn = connected_client.begin();
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
     /// let key = "TiKV";
     /// let req = txn.get(key);
-    /// let result: Value = req.wait().unwrap();
+    /// let result: Option<Value> = block_on(req).unwrap();
     /// // Finish the transaction...
-    /// txn.commit().wait().unwrap();
+    /// block_on(txn.commit()).unwrap();
     /// ```
     pub fn get(&self, key: impl Into<Key>) -> Get {
-        self.snapshot.get(key.into())
+        let key = key.into();
+        let buffered = self.buffered_value(&key);
+        Get::new(GetInner::new(self.snapshot.clone(), key).buffered(buffered))
     }
 
     /// Create a new [`BatchGet`](struct.BatchGet.html) request.
@@ -321,104 +825,364 @@ impl Transaction {
     ///
     /// ```rust,no_run
     /// # use tikv_client::{KvPair, Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
-    /// let mut txn = connected_client.begin();
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
     /// let keys = vec!["TiKV", "TiDB"];
     /// let req = txn.batch_get(keys);
-    /// let result: Vec<KvPair> = req.wait().unwrap();
+    /// let result: Vec<KvPair> = block_on(req).unwrap();
     /// // Finish the transaction...
-    /// txn.commit().wait().unwrap();
+    /// block_on(txn.commit()).unwrap();
     /// ```
     pub fn batch_get(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> BatchGet {
-        self.snapshot.batch_get(keys)
+        let mut unbuffered = Vec::new();
+        let mut buffered = Vec::new();
+        for key in keys.into_iter().map(Into::into) {
+            match self.buffered_value(&key) {
+                Some(Some(value)) => buffered.push(KvPair::new(key, value)),
+                Some(None) => {}
+                None => unbuffered.push(key),
+            }
+        }
+        BatchGet::new(BatchGetInner::new(self.snapshot.clone(), unbuffered).buffered(buffered))
     }
 
+    /// Create a new [`Scanner`](struct.Scanner.html) over the given range, in ascending order.
+    ///
+    /// The writes buffered in the transaction are merged over the pairs of the snapshot.
     pub fn scan(&self, range: impl RangeBounds<Key>) -> Scanner {
-        self.snapshot.scan(range)
+        let range = scan_range(range);
+        let buffered = self.buffered_range(&range);
+        Scanner::new(self.snapshot.clone(), range, false).buffered(buffered)
     }
 
+    /// Create a new [`Scanner`](struct.Scanner.html) over the given range, in descending order.
+    ///
+    /// The writes buffered in the transaction are merged over the pairs of the snapshot.
     pub fn scan_reverse(&self, range: impl RangeBounds<Key>) -> Scanner {
-        self.snapshot.scan_reverse(range)
+        let range = scan_range(range);
+        let mut buffered = self.buffered_range(&range);
+        buffered.reverse();
+        Scanner::new(self.snapshot.clone(), range, true).buffered(buffered)
+    }
+
+    /// The value written to `key` in the transaction: `Some(None)` if it was deleted, and `None`
+    /// if it was not written.
+    fn buffered_value(&self, key: &Key) -> Option<Option<Value>> {
+        match self.mutations.get(key)? {
+            Mutation::Put(_, value) => Some(Some(value.clone())),
+            Mutation::Del(_) => Some(None),
+            Mutation::Lock(_) | Mutation::Rollback(_) => None,
+        }
+    }
+
+    /// The keys written in the transaction within `range`, in ascending order, with their values
+    /// or `None` if they were deleted.
+    fn buffered_range(&self, range: &(Key, Option<Key>)) -> Vec<(Key, Option<Value>)> {
+        let (start, end) = range;
+        if end.as_ref().map_or(false, |end| end <= start) {
+            return Vec::new();
+        }
+        let end = end.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+        self.mutations
+            .range::<Key, _>((Bound::Included(start), end))
+            .filter_map(|(key, mutation)| match mutation {
+                Mutation::Put(_, value) => Some((key.clone(), Some(value.clone()))),
+                Mutation::Del(_) => Some((key.clone(), None)),
+                Mutation::Lock(_) | Mutation::Rollback(_) => None,
+            })
+            .collect()
     }
 
     /// Create a new [`Set`](struct.Set.html) request.
     ///
     /// Once resolved this request will result in the setting of the value associated with the given key.
     ///
+    /// The write is buffered in the transaction until it is committed.
+    ///
     /// ```rust,no_run
     /// # use tikv_client::{Key, Value, Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
-    /// let mut txn = connected_client.begin();
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
     /// let key = "TiKV";
     /// let val = "TiKV";
     /// let req = txn.set(key, val);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// // Finish the transaction...
-    /// txn.commit().wait().unwrap();
+    /// block_on(txn.commit()).unwrap();
     /// ```
     pub fn set(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Set {
-        Set::new(key.into(), value.into())
+        let key = key.into();
+        self.mutations
+            .insert(key.clone(), Mutation::Put(key, value.into()));
+        Set::new()
     }
 
     /// Create a new [`Delete`](struct.Delete.html) request.
     ///
     /// Once resolved this request will result in the deletion of the given key.
     ///
+    /// The write is buffered in the transaction until it is committed.
+    ///
     /// ```rust,no_run
     /// # use tikv_client::{Key, Config, transaction::Client};
-    /// # use futures::Future;
+    /// # use futures::executor::block_on;
     /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
-    /// # let connected_client = connecting_client.wait().unwrap();
-    /// let mut txn = connected_client.begin();
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let mut txn = block_on(connected_client.begin()).unwrap();
     /// let key = "TiKV";
     /// let req = txn.delete(key);
-    /// let result: () = req.wait().unwrap();
+    /// let result: () = block_on(req).unwrap();
     /// // Finish the transaction...
-    /// txn.commit().wait().unwrap();
+    /// block_on(txn.commit()).unwrap();
     /// ```
     pub fn delete(&mut self, key: impl Into<Key>) -> Delete {
-        Delete::new(key.into())
+        let key = key.into();
+        self.mutations.insert(key.clone(), Mutation::Del(key));
+        Delete::new()
     }
 }
 
 /// A snapshot of dataset at a particular point in time.
-pub struct Snapshot;
+#[derive(Clone)]
+pub struct Snapshot {
+    rpc: Arc<RpcClient>,
+    timestamp: Timestamp,
+}
 
 impl Snapshot {
+    fn new(rpc: Arc<RpcClient>, timestamp: Timestamp) -> Self {
+        Snapshot { rpc, timestamp }
+    }
+
+    /// Returns the timestamp the snapshot reads at.
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    /// Create a new [`Get`](struct.Get.html) request.
+    ///
+    /// Once resolved this request will result in the fetching of the value associated with the
+    /// given key, as of the timestamp of the snapshot.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, Config, transaction::Client};
+    /// # use futures::executor::block_on;
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let snapshot = block_on(connected_client.snapshot()).unwrap();
+    /// let req = snapshot.get("TiKV");
+    /// let result: Option<Value> = block_on(req).unwrap();
+    /// ```
     pub fn get(&self, key: impl Into<Key>) -> Get {
-        Get::new(key.into())
+        Get::new(GetInner::new(self.clone(), key.into()))
     }
 
+    /// Create a new [`BatchGet`](struct.BatchGet.html) request.
+    ///
+    /// Once resolved this request will result in the fetching of the values associated with the
+    /// given keys, as of the timestamp of the snapshot. Keys which have no value are not included
+    /// in the result.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, Config, transaction::Client};
+    /// # use futures::executor::block_on;
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let snapshot = block_on(connected_client.snapshot()).unwrap();
+    /// let req = snapshot.batch_get(vec!["TiKV", "TiDB"]);
+    /// let result: Vec<KvPair> = block_on(req).unwrap();
+    /// ```
     pub fn batch_get(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> BatchGet {
-        BatchGet::new(keys.into_iter().map(|v| v.into()).collect())
+        BatchGet::new(BatchGetInner::new(
+            self.clone(),
+            keys.into_iter().map(Into::into).collect(),
+        ))
     }
 
+    /// Create a new [`Scanner`](struct.Scanner.html) over the given range, in ascending order.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, transaction::Client};
+    /// # use futures::executor::{block_on, block_on_stream};
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let snapshot = block_on(connected_client.snapshot()).unwrap();
+    /// let scanner = snapshot.scan(Key::from("TiDB")..Key::from("TiKV")).batch_size(64);
+    /// for pair in block_on_stream(scanner) {
+    ///     println!("{:?}", pair.unwrap());
+    /// }
+    /// ```
     pub fn scan(&self, range: impl RangeBounds<Key>) -> Scanner {
-        drop(range);
-        unimplemented!()
+        Scanner::new(self.clone(), scan_range(range), false)
     }
 
+    /// Create a new [`Scanner`](struct.Scanner.html) over the given range, in descending order.
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, transaction::Client};
+    /// # use futures::{executor::block_on, prelude::*};
+    /// # let connecting_client = Client::new(Config::new(vec!["192.168.0.100", "192.168.0.101"]));
+    /// # let connected_client = block_on(connecting_client).unwrap();
+    /// let snapshot = block_on(connected_client.snapshot()).unwrap();
+    /// let scanner = snapshot.scan_reverse(..Key::from("TiKV")).key_only();
+    /// let last_ten: Vec<_> = block_on(scanner.take(10).try_collect()).unwrap();
+    /// ```
     pub fn scan_reverse(&self, range: impl RangeBounds<Key>) -> Scanner {
-        drop(range);
-        unimplemented!()
+        Scanner::new(self.clone(), scan_range(range), true)
     }
 }
 
-/// An unresolved [`Transaction::scan`](struct.Transaction.html#method.scan) request.
+/// Convert `range` to the inclusive start key and optional exclusive end key TiKV scans take.
+fn scan_range(range: impl RangeBounds<Key>) -> (Key, Option<Key>) {
+    let start = match range.start_bound() {
+        Bound::Included(key) => key.clone(),
+        Bound::Excluded(key) => {
+            let mut key = key.clone();
+            key.push(0);
+            key
+        }
+        Bound::Unbounded => Key::default(),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(key) => {
+            let mut key = key.clone();
+            key.push(0);
+            Some(key)
+        }
+        Bound::Excluded(key) => Some(key.clone()),
+        Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+type ScanBatch = (Vec<KvPair>, Option<(Key, Option<Key>)>);
+
+/// A stream over the pairs of a range as of a snapshot, created by
+/// [`Snapshot::scan`](struct.Snapshot.html#method.scan) or
+/// [`Snapshot::scan_reverse`](struct.Snapshot.html#method.scan_reverse).
 ///
-/// Once resolved this request will result in a scanner over the given keys.
-pub struct Scanner;
+/// Pairs are fetched from TiKV in batches, one region at a time, as the stream is polled.
+///
+/// A scanner created by a [`Transaction`](struct.Transaction.html) merges the writes buffered in
+/// the transaction over the pairs fetched from TiKV.
+pub struct Scanner {
+    snapshot: Snapshot,
+    /// The part of the range which has not been fetched yet, if any.
+    range: Option<(Key, Option<Key>)>,
+    batch_size: u32,
+    key_only: bool,
+    reverse: bool,
+    batch: iter::Peekable<vec::IntoIter<KvPair>>,
+    pending: Option<KvFuture<ScanBatch>>,
+    /// The buffered writes within the range, in scan order, `None` for deleted keys.
+    buffered: iter::Peekable<vec::IntoIter<(Key, Option<Value>)>>,
+}
 
-impl Stream for Scanner {
-    type Item = KvPair;
-    type Error = Error;
+impl Scanner {
+    fn new(snapshot: Snapshot, range: (Key, Option<Key>), reverse: bool) -> Self {
+        let is_empty = range.1.as_ref().map_or(false, |end| *end <= range.0);
+        Scanner {
+            snapshot,
+            range: if is_empty { None } else { Some(range) },
+            batch_size: DEFAULT_SCAN_BATCH_SIZE,
+            key_only: false,
+            reverse,
+            batch: Vec::new().into_iter().peekable(),
+            pending: None,
+            buffered: Vec::new().into_iter().peekable(),
+        }
+    }
+
+    fn buffered(mut self, buffered: Vec<(Key, Option<Value>)>) -> Self {
+        self.buffered = buffered.into_iter().peekable();
+        self
+    }
+
+    /// Set the number of pairs fetched from TiKV per request.
+    ///
+    /// A batch size of zero makes the scanner fail.
+    pub fn batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Only fetch the keys, the values of the pairs will be empty.
+    pub fn key_only(mut self) -> Self {
+        self.key_only = true;
+        self
+    }
+
+    fn next_batch(&mut self, range: (Key, Option<Key>)) -> KvFuture<ScanBatch> {
+        if self.batch_size == 0 {
+            return Box::pin(future::err(Error::zero_scan_batch_size()));
+        }
+        let rpc = Arc::clone(&self.snapshot.rpc);
+        let version = self.snapshot.timestamp.timestamp();
+        let (batch_size, key_only, reverse) = (self.batch_size, self.key_only, self.reverse);
+        Box::pin(async move {
+            rpc.kv_scan(range, version, batch_size, key_only, reverse)
+                .await
+        })
+    }
+}
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        unimplemented!()
+impl Stream for Scanner {
+    type Item = Result<KvPair>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            // The next pair from TiKV must be known before it can be merged with the buffer.
+            if this.batch.peek().is_none() && (this.pending.is_some() || this.range.is_some()) {
+                if this.pending.is_none() {
+                    let range = this.range.take().unwrap();
+                    this.pending = Some(this.next_batch(range));
+                }
+                let (pairs, rest) = match this.pending.as_mut().unwrap().as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(batch)) => batch,
+                    Poll::Ready(Err(e)) => {
+                        this.pending = None;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                };
+                this.pending = None;
+                this.range = rest;
+                this.batch = pairs.into_iter().peekable();
+                continue;
+            }
+
+            let order = match (this.batch.peek(), this.buffered.peek()) {
+                (None, None) => return Poll::Ready(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(pair), Some((key, _))) if this.reverse => key.cmp(pair.key()),
+                (Some(pair), Some((key, _))) => pair.key().cmp(key),
+            };
+            if order == Ordering::Less {
+                return Poll::Ready(this.batch.next().map(Ok));
+            }
+            if order == Ordering::Equal {
+                // The buffered write replaces the pair from TiKV.
+                this.batch.next();
+            }
+            match this.buffered.next() {
+                Some((key, Some(value))) => {
+                    let value = if this.key_only {
+                        Value::default()
+                    } else {
+                        value
+                    };
+                    return Poll::Ready(Some(Ok(KvPair::new(key, value))));
+                }
+                // Deleted in the transaction.
+                _ => continue,
+            }
+        }
     }
 }
 
@@ -453,27 +1217,95 @@ pub enum IsolationLevel {
     ReadCommitted,
 }
 
+trait RequestInner: Sized {
+    type Resp;
+
+    fn execute(self) -> KvFuture<Self::Resp>;
+}
+
+/// A request which is only sent once it is first polled.
+enum RequestState<Inner>
+where
+    Inner: RequestInner,
+{
+    Uninitiated(Option<Inner>),
+    Initiated(KvFuture<Inner::Resp>),
+}
+
+impl<Inner> RequestState<Inner>
+where
+    Inner: RequestInner,
+{
+    fn new(inner: Inner) -> Self {
+        RequestState::Uninitiated(Some(inner))
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Result<Inner::Resp>> {
+        if let RequestState::Uninitiated(inner) = self {
+            *self = RequestState::Initiated(inner.take().unwrap().execute());
+        }
+        match self {
+            RequestState::Initiated(ref mut future) => future.as_mut().poll(cx),
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// An unresolved [`Transaction::get`](struct.Transaction.html#method.get) request.
 ///
 /// Once resolved this request will result in the fetching of the value associated with the given
 /// key.
 pub struct Get {
-    key: Key,
+    state: RequestState<GetInner>,
 }
 
 impl Get {
-    fn new(key: Key) -> Self {
-        Get { key }
+    fn new(inner: GetInner) -> Self {
+        Get {
+            state: RequestState::new(inner),
+        }
     }
 }
 
 impl Future for Get {
-    type Item = Value;
-    type Error = Error;
+    type Output = Result<Option<Value>>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _key = &self.key;
-        unimplemented!()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+struct GetInner {
+    snapshot: Snapshot,
+    key: Key,
+    /// The value written to the key in the transaction, if it was.
+    buffered: Option<Option<Value>>,
+}
+
+impl GetInner {
+    fn new(snapshot: Snapshot, key: Key) -> Self {
+        GetInner {
+            snapshot,
+            key,
+            buffered: None,
+        }
+    }
+
+    fn buffered(mut self, buffered: Option<Option<Value>>) -> Self {
+        self.buffered = buffered;
+        self
+    }
+}
+
+impl RequestInner for GetInner {
+    type Resp = Option<Value>;
+
+    fn execute(self) -> KvFuture<Option<Value>> {
+        if let Some(value) = self.buffered {
+            return Box::pin(future::ok(value));
+        }
+        let version = self.snapshot.timestamp.timestamp();
+        Box::pin(async move { self.snapshot.rpc.kv_get(self.key, version).await })
     }
 }
 
@@ -482,22 +1314,62 @@ impl Future for Get {
 /// Once resolved this request will result in the fetching of the values associated with the given
 /// keys.
 pub struct BatchGet {
-    keys: Vec<Key>,
+    state: RequestState<BatchGetInner>,
 }
 
 impl BatchGet {
-    fn new(keys: Vec<Key>) -> Self {
-        BatchGet { keys }
+    fn new(inner: BatchGetInner) -> Self {
+        BatchGet {
+            state: RequestState::new(inner),
+        }
     }
 }
 
 impl Future for BatchGet {
-    type Item = Vec<KvPair>;
-    type Error = Error;
+    type Output = Result<Vec<KvPair>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+struct BatchGetInner {
+    snapshot: Snapshot,
+    keys: Vec<Key>,
+    /// The pairs written in the transaction, whose keys are not in `keys`.
+    buffered: Vec<KvPair>,
+}
+
+impl BatchGetInner {
+    fn new(snapshot: Snapshot, keys: Vec<Key>) -> Self {
+        BatchGetInner {
+            snapshot,
+            keys,
+            buffered: Vec::new(),
+        }
+    }
+
+    fn buffered(mut self, buffered: Vec<KvPair>) -> Self {
+        self.buffered = buffered;
+        self
+    }
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _keys = &self.keys;
-        unimplemented!()
+impl RequestInner for BatchGetInner {
+    type Resp = Vec<KvPair>;
+
+    fn execute(self) -> KvFuture<Vec<KvPair>> {
+        let version = self.snapshot.timestamp.timestamp();
+        Box::pin(async move {
+            let mut pairs = if self.keys.is_empty() {
+                Vec::new()
+            } else {
+                self.snapshot.rpc.kv_batch_get(self.keys, version).await?
+            };
+            pairs.extend(self.buffered);
+            pairs.sort_by(|a, b| a.key().cmp(b.key()));
+            Ok(pairs)
+        })
     }
 }
 
@@ -505,45 +1377,219 @@ impl Future for BatchGet {
 ///
 /// Once resolved this request will result in the committing of the transaction.
 pub struct Commit {
-    txn: Transaction,
+    state: RequestState<TwoPhaseCommitter>,
 }
 
 impl Commit {
     fn new(txn: Transaction) -> Self {
-        Commit { txn }
+        Commit {
+            state: RequestState::new(TwoPhaseCommitter::new(txn)),
+        }
     }
 }
 
 impl Future for Commit {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+/// Commits the buffered mutations of a transaction using the Percolator protocol.
+///
+/// Every key is first prewritten, which locks it and checks for conflicting writes since the start
+/// timestamp. The first key is the primary: once it is committed the whole transaction is, and the
+/// locks left on the secondary keys can be resolved from it by any later reader.
+///
+/// The keys a pessimistic transaction locked are prewritten as well, which turns their pessimistic
+/// locks into regular ones, and its first locked key stays the primary.
+struct TwoPhaseCommitter {
+    rpc: Arc<RpcClient>,
+    /// The mutations to prewrite, primary first, with whether their key is pessimistically locked.
+    mutations: Vec<(Mutation, bool)>,
+    start_version: u64,
+    for_update_ts: u64,
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _txn = &self.txn;
-        unimplemented!()
+impl TwoPhaseCommitter {
+    fn new(txn: Transaction) -> Self {
+        let mut mutations = txn.mutations;
+        let (primary, locked, for_update_ts) = match txn.pessimistic {
+            Some(locks) => {
+                let locks = locks.read().unwrap();
+                (
+                    locks.primary.clone(),
+                    locks.keys.clone(),
+                    locks.for_update_ts,
+                )
+            }
+            None => (None, BTreeSet::new(), 0),
+        };
+        for key in locked.iter().chain(&primary) {
+            mutations
+                .entry(key.clone())
+                .or_insert_with(|| Mutation::Lock(key.clone()));
+        }
+        let primary = primary.and_then(|key| mutations.remove(&key));
+        let mutations = primary
+            .into_iter()
+            .chain(mutations.into_iter().map(|(_, m)| m))
+            .map(|m| {
+                let is_locked = locked.contains(m.key());
+                (m, is_locked)
+            })
+            .collect();
+        TwoPhaseCommitter {
+            rpc: txn.snapshot.rpc,
+            mutations,
+            start_version: txn.snapshot.timestamp.timestamp(),
+            for_update_ts,
+        }
     }
 }
 
+impl RequestInner for TwoPhaseCommitter {
+    type Resp = ();
+
+    fn execute(self) -> KvFuture<()> {
+        Box::pin(self.commit())
+    }
+}
+
+impl TwoPhaseCommitter {
+    async fn commit(self) -> Result<()> {
+        if self.mutations.is_empty() {
+            return Ok(());
+        }
+        let TwoPhaseCommitter {
+            rpc,
+            mutations,
+            start_version,
+            for_update_ts,
+        } = self;
+        let keys: Vec<Key> = mutations.iter().map(|(m, _)| m.key().clone()).collect();
+        let primary = keys[0].clone();
+        let secondaries = keys[1..].to_vec();
+        let size: usize = mutations.iter().map(|(m, _)| m.size()).sum();
+        let ttl = lock_ttl(start_version, SystemTime::now().into(), txn_lock_ttl(size));
+
+        if let Err(e) = rpc
+            .kv_prewrite(
+                mutations,
+                primary.clone(),
+                start_version,
+                ttl,
+                for_update_ts,
+            )
+            .await
+        {
+            warn!("prewrite failed, rolling back transaction: {}", e);
+            Self::rollback(&rpc, keys, start_version).await;
+            return Err(e);
+        }
+        let commit_ts = match rpc.get_fresh_timestamp().await {
+            Ok(ts) => ts,
+            Err(e) => {
+                // Nothing is committed yet, so the prewrite locks must not wait for their TTL.
+                warn!(
+                    "failed to get commit timestamp, rolling back transaction: {}",
+                    e
+                );
+                Self::rollback(&rpc, keys, start_version).await;
+                return Err(e);
+            }
+        };
+        rpc.kv_commit(vec![primary], start_version, commit_ts.timestamp())
+            .await?;
+        // The transaction is committed once its primary key is. Locks left on the secondary keys
+        // will be resolved by readers, so failing here is not fatal.
+        if let Err(e) = rpc
+            .kv_commit(secondaries, start_version, commit_ts.timestamp())
+            .await
+        {
+            warn!("failed to commit secondary keys: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Roll back the prewritten `keys` of a transaction which will not be committed.
+    async fn rollback(rpc: &RpcClient, keys: Vec<Key>, start_version: u64) {
+        if let Err(e) = rpc.kv_batch_rollback(keys, start_version).await {
+            warn!("failed to roll back transaction: {}", e);
+        }
+    }
+}
+
+/// The time to live of the locks of a transaction writing `size` bytes, in milliseconds.
+fn txn_lock_ttl(size: usize) -> u64 {
+    let size_mib = size as f64 / (1024.0 * 1024.0);
+    let ttl = (TXN_LOCK_TTL_FACTOR * size_mib.sqrt()) as u64;
+    cmp::min(cmp::max(ttl, TXN_LOCK_TTL), MAX_TXN_LOCK_TTL)
+}
+
+/// The TTL of a lock which should live for `ttl` milliseconds from `now`. TiKV counts the TTL of a
+/// lock from the start version of its transaction, so the time elapsed since is added.
+fn lock_ttl(start_version: u64, now: Timestamp, ttl: u64) -> u64 {
+    let elapsed = now
+        .duration_since(Timestamp::from(start_version))
+        .unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()) + ttl
+}
+
 /// An unresolved [`Transaction::rollback`](struct.Transaction.html#method.rollback) request.
 ///
 /// Once resolved this request will result in the rolling back of the transaction.
 pub struct Rollback {
-    txn: Transaction,
+    state: RequestState<RollbackInner>,
 }
 
 impl Rollback {
     fn new(txn: Transaction) -> Self {
-        Rollback { txn }
+        Rollback {
+            state: RequestState::new(RollbackInner { txn }),
+        }
     }
 }
 
 impl Future for Rollback {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+struct RollbackInner {
+    txn: Transaction,
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _txn = &self.txn;
-        unimplemented!()
+impl RequestInner for RollbackInner {
+    type Resp = ();
+
+    fn execute(self) -> KvFuture<()> {
+        // Nothing but pessimistic locks has been written to TiKV before committing, so dropping
+        // the buffered mutations and releasing these locks is enough.
+        let locks = match self.txn.pessimistic {
+            Some(locks) => locks,
+            None => return Box::pin(future::ok(())),
+        };
+        let (keys, for_update_ts) = {
+            let locks = locks.read().unwrap();
+            (
+                locks.keys.iter().cloned().collect::<Vec<_>>(),
+                locks.for_update_ts,
+            )
+        };
+        if keys.is_empty() {
+            return Box::pin(future::ok(()));
+        }
+        let rpc = self.txn.snapshot.rpc;
+        let start_version = self.txn.snapshot.timestamp.timestamp();
+        Box::pin(async move {
+            rpc.kv_pessimistic_rollback(keys, start_version, for_update_ts)
+                .await
+        })
     }
 }
 
@@ -551,22 +1597,188 @@ impl Future for Rollback {
 ///
 /// Once resolved this request will result in the locking of the given keys.
 pub struct LockKeys {
-    keys: Vec<Key>,
+    state: RequestState<LockKeysInner>,
 }
 
 impl LockKeys {
-    fn new(keys: Vec<Key>) -> Self {
-        LockKeys { keys }
+    fn new(inner: LockKeysInner) -> Self {
+        LockKeys {
+            state: RequestState::new(inner),
+        }
     }
 }
 
 impl Future for LockKeys {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+struct LockKeysInner {
+    snapshot: Snapshot,
+    keys: Vec<Key>,
+    /// The locks of the transaction, if it is pessimistic.
+    locks: Option<Arc<RwLock<PessimisticLocks>>>,
+}
+
+impl LockKeysInner {
+    /// Lock the keys of a pessimistic transaction. Returns the version the keys may be read at
+    /// while they are locked.
+    async fn lock(self) -> Result<u64> {
+        let start_version = self.snapshot.timestamp.timestamp();
+        let locks = match self.locks {
+            Some(locks) => locks,
+            // The keys of an optimistic transaction are locked when it commits.
+            None => return Ok(start_version),
+        };
+        let rpc = self.snapshot.rpc;
+        let for_update_ts = rpc.get_fresh_timestamp().await?.timestamp();
+        if self.keys.is_empty() {
+            return Ok(for_update_ts);
+        }
+        let lock = PessimisticLock {
+            rpc,
+            start_version,
+            for_update_ts,
+            ttl: lock_ttl(
+                start_version,
+                Timestamp::from(for_update_ts),
+                PESSIMISTIC_LOCK_TTL,
+            ),
+        };
+        let primary = locks.read().unwrap().primary.clone();
+        let (primary, keys) = match primary {
+            Some(primary) => (primary, self.keys),
+            None => {
+                // The primary key is locked on its own first, so that no secondary key is ever
+                // locked without it.
+                let primary = self.keys[0].clone();
+                lock.acquire(vec![primary.clone()], &primary, &locks)
+                    .await?;
+                {
+                    let mut locks = locks.write().unwrap();
+                    if locks.primary.is_none() {
+                        locks.primary = Some(primary.clone());
+                    }
+                }
+                let keys = self
+                    .keys
+                    .into_iter()
+                    .filter(|key| *key != primary)
+                    .collect();
+                (primary, keys)
+            }
+        };
+        if !keys.is_empty() {
+            lock.acquire(keys, &primary, &locks).await?;
+        }
+        Ok(for_update_ts)
+    }
+}
+
+/// A pessimistic lock request of a transaction, for some of its keys.
+struct PessimisticLock {
+    rpc: Arc<RpcClient>,
+    start_version: u64,
+    for_update_ts: u64,
+    ttl: u64,
+}
+
+impl PessimisticLock {
+    /// Lock `keys` and add them to `locks`. If that fails, the keys this request locked are
+    /// released again, while keys the transaction locked earlier stay locked.
+    async fn acquire(
+        &self,
+        keys: Vec<Key>,
+        primary: &Key,
+        locks: &RwLock<PessimisticLocks>,
+    ) -> Result<()> {
+        let unlocked: Vec<Key> = {
+            let locks = locks.read().unwrap();
+            keys.iter()
+                .filter(|key| !locks.keys.contains(*key))
+                .cloned()
+                .collect()
+        };
+        if let Err(e) = self
+            .rpc
+            .kv_pessimistic_lock(
+                keys.clone(),
+                primary.clone(),
+                self.start_version,
+                self.ttl,
+                self.for_update_ts,
+            )
+            .await
+        {
+            if let Err(e) = self
+                .rpc
+                .kv_pessimistic_rollback(unlocked, self.start_version, self.for_update_ts)
+                .await
+            {
+                warn!("failed to release pessimistic locks: {}", e);
+            }
+            return Err(e);
+        }
+        let mut locks = locks.write().unwrap();
+        locks.keys.extend(keys);
+        locks.for_update_ts = cmp::max(locks.for_update_ts, self.for_update_ts);
+        Ok(())
+    }
+}
+
+impl RequestInner for LockKeysInner {
+    type Resp = ();
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _keys = &self.keys;
-        unimplemented!()
+    fn execute(self) -> KvFuture<()> {
+        Box::pin(self.lock().map_ok(|_| ()))
+    }
+}
+
+/// An unresolved [`Transaction::get_for_update`](struct.Transaction.html#method.get_for_update)
+/// request.
+///
+/// Once resolved this request will result in the locking of the given key and the fetching of its
+/// value.
+pub struct GetForUpdate {
+    state: RequestState<GetForUpdateInner>,
+}
+
+impl GetForUpdate {
+    fn new(inner: GetForUpdateInner) -> Self {
+        GetForUpdate {
+            state: RequestState::new(inner),
+        }
+    }
+}
+
+impl Future for GetForUpdate {
+    type Output = Result<Option<Value>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.poll(cx)
+    }
+}
+
+struct GetForUpdateInner {
+    lock: LockKeysInner,
+    get: GetInner,
+}
+
+impl RequestInner for GetForUpdateInner {
+    type Resp = Option<Value>;
+
+    fn execute(self) -> KvFuture<Option<Value>> {
+        let GetForUpdateInner { lock, get } = self;
+        Box::pin(async move {
+            let version = lock.lock().await?;
+            if let Some(value) = get.buffered {
+                return Ok(value);
+            }
+            get.snapshot.rpc.kv_get(get.key, version).await
+        })
     }
 }
 
@@ -575,24 +1787,20 @@ impl Future for LockKeys {
 /// Once resolved this request will result in the setting of the value associated with the given
 /// key.
 pub struct Set {
-    key: Key,
-    value: Value,
+    _private: (),
 }
 
 impl Set {
-    fn new(key: Key, value: Value) -> Self {
-        Set { key, value }
+    fn new() -> Self {
+        Set { _private: () }
     }
 }
 
 impl Future for Set {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _key = &self.key;
-        let _value = &self.value;
-        unimplemented!()
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -600,21 +1808,58 @@ impl Future for Set {
 ///
 /// Once resolved this request will result in the deletion of the given key.
 pub struct Delete {
-    key: Key,
+    _private: (),
 }
 
 impl Delete {
-    fn new(key: Key) -> Self {
-        Delete { key }
+    fn new() -> Self {
+        Delete { _private: () }
     }
 }
 
 impl Future for Delete {
-    type Item = ();
-    type Error = Error;
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(951_782_400_000);
+        let timestamp = Timestamp::compose(951_782_400_000, 7);
+        assert_eq!(SystemTime::from(timestamp), time);
+        assert_eq!(
+            Timestamp::from(time),
+            Timestamp::compose(951_782_400_000, 0)
+        );
+        assert!(Timestamp::from(time) < timestamp);
+        assert_eq!(timestamp.to_string(), "2000-02-29T00:00:00.000Z#7");
+
+        let earlier = timestamp.checked_sub(Duration::from_secs(5)).unwrap();
+        assert_eq!(earlier.physical(), 951_782_395_000);
+        assert_eq!(earlier.logical(), 7);
+        assert_eq!(
+            timestamp.duration_since(earlier),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(earlier.duration_since(timestamp), None);
+        assert_eq!(earlier.checked_add(Duration::from_secs(5)), Some(timestamp));
+        assert_eq!(timestamp.checked_sub(Duration::from_secs(1 << 40)), None);
+    }
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let _key = &self.key;
-        unimplemented!()
+    #[test]
+    fn test_txn_lock_ttl() {
+        const MIB: usize = 1024 * 1024;
+        assert_eq!(txn_lock_ttl(0), TXN_LOCK_TTL);
+        assert_eq!(txn_lock_ttl(16 * 1024), TXN_LOCK_TTL);
+        assert_eq!(txn_lock_ttl(MIB), 6000);
+        assert_eq!(txn_lock_ttl(4 * MIB), 12000);
+        assert_eq!(txn_lock_ttl(1024 * MIB), MAX_TXN_LOCK_TTL);
     }
 }
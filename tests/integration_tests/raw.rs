@@ -11,7 +11,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::future::Future;
+use futures::{executor::block_on, prelude::*};
+use std::time::Duration;
 
 const NUM_TEST_KEYS: u32 = 100;
 use crate::integration_tests::pd_addr;
@@ -25,35 +26,35 @@ fn generate_value(id: i32) -> Value {
     format!("testvalue_{}", id).into_bytes().into()
 }
 
-fn wipe_all(client: &Client) {
+async fn wipe_all(client: &Client) {
     let test_key_start = generate_key(0);
     let test_key_end = generate_key(NUM_TEST_KEYS as i32 - 1);
     client
         .delete_range(test_key_start..test_key_end)
-        .wait()
+        .await
         .expect("Could not delete test keys");
 }
 
-fn connect() -> Client {
+async fn connect() -> Client {
     let client = Client::new(Config::new(pd_addr()))
-        .wait()
+        .await
         .expect("Could not connect to tikv");
-    wipe_all(&client);
+    wipe_all(&client).await;
     client
 }
 
-fn test_empty(client: &Client) {
+async fn test_empty(client: &Client) {
     let test_key_start = generate_key(0);
     let test_key_end = generate_key(NUM_TEST_KEYS as i32 - 1);
 
     assert!(client
         .scan(test_key_start..test_key_end, NUM_TEST_KEYS)
-        .wait()
+        .await
         .expect("Could not scan")
         .is_empty());
 }
 
-fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys: Vec<Key>) {
+async fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys: Vec<Key>) {
     let test_key_start = generate_key(0);
     let test_key_end = generate_key(NUM_TEST_KEYS as i32 - 1);
 
@@ -62,7 +63,7 @@ fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys:
         assert_eq!(
             client
                 .get(key)
-                .wait()
+                .await
                 .expect("Could not get value")
                 .expect("key doesn't exist"),
             value.clone(),
@@ -70,7 +71,7 @@ fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys:
     }
 
     for key in not_existing_keys.clone().into_iter() {
-        let r = client.get(key).wait().expect("Cound not get value");
+        let r = client.get(key).await.expect("Cound not get value");
         assert!(r.is_none());
     }
 
@@ -88,7 +89,7 @@ fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys:
     assert_eq!(
         client
             .batch_get(all_keys)
-            .wait()
+            .await
             .expect("Could not get value in batch"),
         existing_pairs,
     );
@@ -96,7 +97,7 @@ fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys:
     assert_eq!(
         client
             .batch_get(not_existing_keys)
-            .wait()
+            .await
             .expect("Could not get value in batch"),
         Vec::new(),
     );
@@ -104,7 +105,7 @@ fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys:
     assert_eq!(
         client
             .scan(test_key_start.clone()..test_key_end.clone(), NUM_TEST_KEYS)
-            .wait()
+            .await
             .expect("Could not scan"),
         existing_pairs,
     );
@@ -113,60 +114,157 @@ fn test_existence(client: &Client, existing_pairs: &[KvPair], not_existing_keys:
         client
             .scan(test_key_start.clone()..test_key_end.clone(), NUM_TEST_KEYS)
             .key_only()
-            .wait()
+            .await
             .expect("Could not scan"),
         existing_key_only_pairs,
     );
+
+    let mut reversed_pairs = existing_pairs.to_vec();
+    reversed_pairs.reverse();
+    assert_eq!(
+        client
+            .scan(test_key_start.clone()..test_key_end.clone(), NUM_TEST_KEYS)
+            .reverse()
+            .await
+            .expect("Could not scan"),
+        reversed_pairs,
+    );
+
+    assert_eq!(
+        client
+            .scan_stream(test_key_start.clone()..test_key_end.clone())
+            .batch_size(3)
+            .try_collect::<Vec<_>>()
+            .await
+            .expect("Could not scan"),
+        existing_pairs,
+    );
 }
 
 #[test]
 fn basic_raw_test() {
-    let client = connect();
+    block_on(async {
+        let client = connect().await;
 
-    test_empty(&client);
+        test_empty(&client).await;
 
-    assert!(client
-        .put(generate_key(0), generate_value(0))
-        .wait()
-        .is_ok());
-    test_existence(
-        &client,
-        &[KvPair::new(generate_key(0), generate_value(0))],
-        vec![generate_key(1), generate_key(2)],
-    );
+        assert!(client.put(generate_key(0), generate_value(0)).await.is_ok());
+        test_existence(
+            &client,
+            &[KvPair::new(generate_key(0), generate_value(0))],
+            vec![generate_key(1), generate_key(2)],
+        )
+        .await;
 
-    let empty_pairs = Vec::new();
-    assert!(client.delete(generate_key(0)).wait().is_ok());
-    test_existence(
-        &client,
-        &empty_pairs,
-        vec![generate_key(0), generate_key(1), generate_key(2)],
-    );
+        let empty_pairs = Vec::new();
+        assert!(client.delete(generate_key(0)).await.is_ok());
+        test_existence(
+            &client,
+            &empty_pairs,
+            vec![generate_key(0), generate_key(1), generate_key(2)],
+        )
+        .await;
 
-    let pairs: Vec<KvPair> = (0..10)
-        .map(|i| KvPair::new(generate_key(i), generate_value(i)))
-        .collect();
-    assert!(client.batch_put(pairs.clone()).wait().is_ok());
-    test_existence(
-        &client,
-        &pairs,
-        vec![generate_key(10), generate_key(11), generate_key(12)],
-    );
+        let pairs: Vec<KvPair> = (0..10)
+            .map(|i| KvPair::new(generate_key(i), generate_value(i)))
+            .collect();
+        assert!(client.batch_put(pairs.clone()).await.is_ok());
+        test_existence(
+            &client,
+            &pairs,
+            vec![generate_key(10), generate_key(11), generate_key(12)],
+        )
+        .await;
 
-    let keys: Vec<Key> = vec![generate_key(8), generate_key(9)];
-    assert!(client.batch_delete(keys).wait().is_ok());
-    let mut pairs = pairs;
-    pairs.truncate(8);
-    test_existence(
-        &client,
-        &pairs,
-        vec![generate_key(8), generate_key(9), generate_key(10)],
-    );
+        let keys: Vec<Key> = vec![generate_key(8), generate_key(9)];
+        assert!(client.batch_delete(keys).await.is_ok());
+        let mut pairs = pairs;
+        pairs.truncate(8);
+        test_existence(
+            &client,
+            &pairs,
+            vec![generate_key(8), generate_key(9), generate_key(10)],
+        )
+        .await;
 
-    wipe_all(&client);
-    test_existence(
-        &client,
-        &empty_pairs,
-        pairs.into_iter().map(|x| x.into_inner().0).collect(),
-    );
+        wipe_all(&client).await;
+        test_existence(
+            &client,
+            &empty_pairs,
+            pairs.into_iter().map(|x| x.into_inner().0).collect(),
+        )
+        .await;
+    });
+}
+
+#[test]
+fn raw_compare_and_swap_test() {
+    block_on(async {
+        let client = connect().await;
+        let key = generate_key(0);
+
+        let (previous, swapped) = client
+            .compare_and_swap(key.clone(), None, generate_value(0))
+            .await
+            .expect("Could not compare and swap");
+        assert_eq!((previous, swapped), (None, true));
+
+        let (previous, swapped) = client
+            .compare_and_swap(key.clone(), None, generate_value(1))
+            .await
+            .expect("Could not compare and swap");
+        assert_eq!((previous, swapped), (Some(generate_value(0)), false));
+
+        let (previous, swapped) = client
+            .compare_and_swap(key.clone(), generate_value(0), generate_value(1))
+            .await
+            .expect("Could not compare and swap");
+        assert_eq!((previous, swapped), (Some(generate_value(0)), true));
+        assert_eq!(
+            client.get(key).await.expect("Could not get value"),
+            Some(generate_value(1))
+        );
+
+        wipe_all(&client).await;
+    });
+}
+
+#[test]
+fn raw_ttl_test() {
+    block_on(async {
+        let client = connect().await;
+
+        client
+            .put(generate_key(0), generate_value(0))
+            .ttl(Duration::from_secs(600))
+            .await
+            .expect("Could not put kv pair");
+        let ttl = client
+            .get_key_ttl(generate_key(0))
+            .await
+            .expect("Could not get ttl")
+            .expect("Key does not exist");
+        assert!(ttl > Duration::from_secs(0) && ttl <= Duration::from_secs(600));
+
+        client
+            .put(generate_key(1), generate_value(1))
+            .await
+            .expect("Could not put kv pair");
+        assert_eq!(
+            client
+                .get_key_ttl(generate_key(1))
+                .await
+                .expect("Could not get ttl"),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(
+            client
+                .get_key_ttl(generate_key(2))
+                .await
+                .expect("Could not get ttl"),
+            None
+        );
+
+        wipe_all(&client).await;
+    });
 }
@@ -0,0 +1,751 @@
+// Copyright 2019 The TiKV Project Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::{executor::block_on, prelude::*};
+use futures_timer::Delay;
+use std::time::Duration;
+use tikv_client::{
+    mock::{MockCluster, RegionError},
+    raw, transaction, ErrorKind, Key, KvPair, Value,
+};
+
+fn start() -> MockCluster {
+    let cluster = MockCluster::start().expect("Could not start mock cluster");
+    cluster.split("k3");
+    cluster.split("k6");
+    assert_eq!(cluster.region_count(), 3);
+    cluster
+}
+
+fn pairs(range: ::std::ops::Range<u32>) -> Vec<KvPair> {
+    range
+        .map(|i| KvPair::new(format!("k{}", i), format!("v{}", i)))
+        .collect()
+}
+
+#[test]
+fn mock_raw_test() {
+    block_on(async {
+        let cluster = start();
+        let client = raw::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        client
+            .batch_put(pairs(0..9))
+            .await
+            .expect("Could not put kv pairs");
+        assert_eq!(
+            client.get("k4").await.expect("Could not get value"),
+            Some(Value::from("v4"))
+        );
+        let all: Vec<Key> = pairs(0..9).into_iter().map(|p| p.key().clone()).collect();
+        assert_eq!(
+            client
+                .batch_get(all.clone())
+                .await
+                .expect("Could not get values"),
+            pairs(0..9)
+        );
+
+        let range = Key::from("k1")..Key::from("k8");
+        assert_eq!(
+            client
+                .scan(range.clone(), 10)
+                .await
+                .expect("Could not scan"),
+            pairs(1..8)
+        );
+        let mut reversed = pairs(1..8);
+        reversed.reverse();
+        assert_eq!(
+            client
+                .scan(range.clone(), 10)
+                .reverse()
+                .await
+                .expect("Could not scan"),
+            reversed
+        );
+        assert_eq!(
+            client
+                .scan_stream(range.clone())
+                .batch_size(2)
+                .try_collect::<Vec<_>>()
+                .await
+                .expect("Could not scan"),
+            pairs(1..8)
+        );
+        assert!(client
+            .scan_stream(range.clone())
+            .batch_size(0)
+            .try_collect::<Vec<_>>()
+            .await
+            .is_err());
+
+        // The mock regions have no followers, so replica reads are served by the leaders.
+        let replica_read = raw::ReplicaRead::new().prefer_label("zone", "local");
+        assert_eq!(
+            client
+                .get("k4")
+                .replica_read(replica_read.clone())
+                .await
+                .expect("Could not get value"),
+            Some(Value::from("v4"))
+        );
+        assert_eq!(
+            client
+                .batch_get(all.clone())
+                .replica_read(replica_read.clone())
+                .await
+                .expect("Could not get values"),
+            pairs(0..9)
+        );
+        assert_eq!(
+            client
+                .scan(range.clone(), 10)
+                .replica_read(replica_read)
+                .await
+                .expect("Could not scan"),
+            pairs(1..8)
+        );
+
+        client
+            .delete_range(Key::from("k2")..Key::from("k7"))
+            .await
+            .expect("Could not delete range");
+        assert_eq!(
+            client.batch_get(all).await.expect("Could not get values"),
+            [pairs(0..2), pairs(7..9)].concat()
+        );
+    });
+}
+
+#[test]
+fn mock_raw_ttl_test() {
+    block_on(async {
+        let cluster = start();
+        let client = raw::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        // A TTL under a second is rounded up, rather than down to "never expire".
+        client
+            .put("k1", "v1")
+            .ttl(Duration::from_millis(500))
+            .await
+            .expect("Could not put kv pair");
+        client
+            .batch_put(pairs(4..8))
+            .ttl(Duration::from_millis(500))
+            .await
+            .expect("Could not put kv pairs");
+        for key in &["k1", "k4", "k7"] {
+            assert_eq!(
+                client.get_key_ttl(*key).await.expect("Could not get ttl"),
+                Some(Duration::from_secs(1))
+            );
+        }
+        Delay::new(Duration::from_millis(1100)).await;
+        assert_eq!(client.get("k1").await.expect("Could not get value"), None);
+        assert_eq!(
+            client
+                .scan(Key::from("k4")..Key::from("k8"), 10)
+                .await
+                .expect("Could not scan"),
+            Vec::new()
+        );
+    });
+}
+
+#[test]
+fn mock_region_error_test() {
+    block_on(async {
+        let cluster = start();
+        let client = raw::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        cluster.inject_error("k1", RegionError::NotLeader);
+        cluster.inject_error("k1", RegionError::EpochNotMatch);
+        cluster.inject_error("k4", RegionError::ServerIsBusy);
+        client
+            .batch_put(pairs(0..6))
+            .await
+            .expect("Could not put kv pairs");
+
+        cluster.split("k1");
+        cluster.inject_error("k7", RegionError::StaleCommand);
+        assert_eq!(
+            client
+                .scan(Key::from("k0")..Key::from("k9"), 10)
+                .await
+                .expect("Could not scan"),
+            pairs(0..6)
+        );
+    });
+}
+
+#[test]
+fn mock_batch_concurrency_test() {
+    block_on(async {
+        let cluster = start();
+        cluster.split("k1");
+        cluster.split("k5");
+        let client = raw::Client::new(cluster.config().batch_concurrency(1))
+            .await
+            .expect("Could not connect to mock cluster");
+
+        // Keys out of order, spanning every region, exercise several rounds of region lookups.
+        let mut shuffled = pairs(0..9);
+        shuffled.reverse();
+        client
+            .batch_put(shuffled)
+            .await
+            .expect("Could not put kv pairs");
+        let keys: Vec<Key> = pairs(0..9)
+            .into_iter()
+            .rev()
+            .map(|p| p.key().clone())
+            .collect();
+        assert_eq!(
+            client
+                .batch_get(keys.clone())
+                .await
+                .expect("Could not get values"),
+            pairs(0..9)
+        );
+        client
+            .batch_delete(keys)
+            .await
+            .expect("Could not delete keys");
+        assert_eq!(
+            client
+                .scan(Key::from("k0")..Key::from("k9"), 10)
+                .await
+                .expect("Could not scan"),
+            Vec::new()
+        );
+    });
+}
+
+#[test]
+fn mock_batch_split_test() {
+    block_on(async {
+        let cluster = start();
+        let client = raw::Client::new(cluster.config().max_batch_size(3, 1024))
+            .await
+            .expect("Could not connect to mock cluster");
+
+        // The first batch sent to each region is rejected, and is split in half.
+        cluster.inject_error("k0", RegionError::RaftEntryTooLarge);
+        cluster.inject_error("k3", RegionError::RaftEntryTooLarge);
+        client
+            .batch_put(pairs(0..9))
+            .await
+            .expect("Could not put kv pairs");
+        let keys: Vec<Key> = pairs(0..9).into_iter().map(|p| p.key().clone()).collect();
+        assert_eq!(
+            client
+                .batch_get(keys.clone())
+                .await
+                .expect("Could not get values"),
+            pairs(0..9)
+        );
+
+        cluster.inject_error("k6", RegionError::RaftEntryTooLarge);
+        client
+            .batch_delete(keys.clone())
+            .await
+            .expect("Could not delete keys");
+        assert_eq!(
+            client.batch_get(keys).await.expect("Could not get values"),
+            Vec::new()
+        );
+    });
+}
+
+#[test]
+fn mock_transaction_test() {
+    block_on(async {
+        let cluster = start();
+        let client = transaction::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        for pair in pairs(0..9) {
+            txn.set(pair.key().clone(), pair.value().clone())
+                .await
+                .expect("Could not set value");
+        }
+        txn.commit().await.expect("Could not commit transaction");
+
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        txn.delete("k4").await.expect("Could not delete value");
+        txn.commit().await.expect("Could not commit transaction");
+
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(snapshot.get("k4").await.expect("Could not get value"), None);
+        assert_eq!(
+            snapshot.get("k5").await.expect("Could not get value"),
+            Some(Value::from("v5"))
+        );
+        assert_eq!(
+            snapshot
+                .scan(Key::from("k2")..Key::from("k7"))
+                .batch_size(2)
+                .try_collect::<Vec<_>>()
+                .await
+                .expect("Could not scan"),
+            [pairs(2..4), pairs(5..7)].concat()
+        );
+        assert!(snapshot
+            .scan(Key::from("k2")..Key::from("k7"))
+            .batch_size(0)
+            .try_collect::<Vec<_>>()
+            .await
+            .is_err());
+    });
+}
+
+#[test]
+fn mock_read_your_writes_test() {
+    block_on(async {
+        let cluster = start();
+        let client = transaction::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        for pair in pairs(0..9) {
+            txn.set(pair.key().clone(), pair.value().clone())
+                .await
+                .expect("Could not set value");
+        }
+        txn.commit().await.expect("Could not commit transaction");
+
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        txn.set("k2", "new").await.expect("Could not set value");
+        txn.set("k45", "v45").await.expect("Could not set value");
+        txn.delete("k5").await.expect("Could not delete value");
+        assert_eq!(
+            txn.get("k2").await.expect("Could not get value"),
+            Some(Value::from("new"))
+        );
+        assert_eq!(txn.get("k5").await.expect("Could not get value"), None);
+        assert_eq!(
+            txn.get("k6").await.expect("Could not get value"),
+            Some(Value::from("v6"))
+        );
+        assert_eq!(
+            txn.batch_get(vec!["k1", "k2", "k45", "k5"])
+                .await
+                .expect("Could not get values"),
+            vec![
+                KvPair::new("k1", "v1"),
+                KvPair::new("k2", "new"),
+                KvPair::new("k45", "v45"),
+            ]
+        );
+
+        let expected = vec![
+            KvPair::new("k1", "v1"),
+            KvPair::new("k2", "new"),
+            KvPair::new("k3", "v3"),
+            KvPair::new("k4", "v4"),
+            KvPair::new("k45", "v45"),
+            KvPair::new("k6", "v6"),
+        ];
+        assert_eq!(
+            txn.scan(Key::from("k1")..Key::from("k7"))
+                .batch_size(2)
+                .try_collect::<Vec<_>>()
+                .await
+                .expect("Could not scan"),
+            expected
+        );
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(
+            txn.scan_reverse(Key::from("k1")..Key::from("k7"))
+                .batch_size(2)
+                .try_collect::<Vec<_>>()
+                .await
+                .expect("Could not scan"),
+            reversed
+        );
+
+        // Nothing was written to TiKV before committing.
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(
+            snapshot.get("k2").await.expect("Could not get value"),
+            Some(Value::from("v2"))
+        );
+        txn.commit().await.expect("Could not commit transaction");
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(snapshot.get("k5").await.expect("Could not get value"), None);
+    });
+}
+
+#[test]
+fn mock_lock_keys_test() {
+    block_on(async {
+        let cluster = start();
+        let client = transaction::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        txn.set("k1", "v1").await.expect("Could not set value");
+        txn.commit().await.expect("Could not commit transaction");
+
+        // Locking a key nobody else writes does not change it.
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        txn.lock_keys(vec!["k1"])
+            .await
+            .expect("Could not lock keys");
+        txn.set("k2", "v2").await.expect("Could not set value");
+        txn.commit().await.expect("Could not commit transaction");
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(
+            snapshot.get("k1").await.expect("Could not get value"),
+            Some(Value::from("v1"))
+        );
+
+        // A locked key written by a concurrent transaction fails the commit.
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        txn.lock_keys(vec!["k1"])
+            .await
+            .expect("Could not lock keys");
+        txn.set("k3", "v3").await.expect("Could not set value");
+        let mut other = client.begin().await.expect("Could not begin transaction");
+        other.set("k1", "new").await.expect("Could not set value");
+        other.commit().await.expect("Could not commit transaction");
+        assert!(txn.commit().await.is_err());
+
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(snapshot.get("k3").await.expect("Could not get value"), None);
+    });
+}
+
+#[test]
+fn mock_pessimistic_test() {
+    block_on(async {
+        let cluster = start();
+        let client =
+            transaction::Client::new(cluster.config().lock_wait_timeout(Duration::from_secs(1)))
+                .await
+                .expect("Could not connect to mock cluster");
+
+        let mut txn = client.begin().await.expect("Could not begin transaction");
+        txn.set("k1", "v1").await.expect("Could not set value");
+        txn.commit().await.expect("Could not commit transaction");
+
+        // A locking read sees what was committed after the transaction started.
+        let mut first = client
+            .begin_pessimistic()
+            .await
+            .expect("Could not begin transaction");
+        assert!(first.is_pessimistic());
+        let mut other = client.begin().await.expect("Could not begin transaction");
+        other.set("k1", "v2").await.expect("Could not set value");
+        other.commit().await.expect("Could not commit transaction");
+        assert_eq!(
+            first
+                .get_for_update("k1")
+                .await
+                .expect("Could not get value"),
+            Some(Value::from("v2"))
+        );
+
+        // Other transactions can neither lock nor write the key until it is released.
+        let mut second = client
+            .begin_pessimistic()
+            .await
+            .expect("Could not begin transaction");
+        match second.lock_keys(vec!["k1"]).await {
+            Err(e) => match e.kind() {
+                ErrorKind::LockWaitTimeout { .. } => {}
+                _ => panic!("Unexpected error: {}", e),
+            },
+            Ok(_) => panic!("Locked a key held by another transaction"),
+        }
+        let mut other = client.begin().await.expect("Could not begin transaction");
+        other.set("k1", "v3").await.expect("Could not set value");
+        assert!(other.commit().await.is_err());
+
+        first.set("k1", "v4").await.expect("Could not set value");
+        first
+            .lock_keys(vec!["k2"])
+            .await
+            .expect("Could not lock keys");
+        first.commit().await.expect("Could not commit transaction");
+        second
+            .lock_keys(vec!["k1"])
+            .await
+            .expect("Could not lock keys");
+        second
+            .rollback()
+            .await
+            .expect("Could not roll back transaction");
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(
+            snapshot.get("k1").await.expect("Could not get value"),
+            Some(Value::from("v4"))
+        );
+        assert_eq!(snapshot.get("k2").await.expect("Could not get value"), None);
+
+        // Two transactions waiting for each other deadlock, until one of them rolls back.
+        let mut a = client
+            .begin_pessimistic()
+            .await
+            .expect("Could not begin transaction");
+        let mut b = client
+            .begin_pessimistic()
+            .await
+            .expect("Could not begin transaction");
+        a.lock_keys(vec!["k4"]).await.expect("Could not lock keys");
+        b.lock_keys(vec!["k7"]).await.expect("Could not lock keys");
+        let waiting = a.lock_keys(vec!["k7"]);
+        let deadlocked = async move {
+            Delay::new(Duration::from_millis(100)).await;
+            let result = b.lock_keys(vec!["k4"]).await;
+            b.rollback().await.expect("Could not roll back transaction");
+            result
+        };
+        let (waited, deadlocked) = future::join(waiting, deadlocked).await;
+        waited.expect("Could not lock keys after the deadlock");
+        match deadlocked {
+            Err(e) => match e.kind() {
+                ErrorKind::Deadlock { .. } => {}
+                _ => panic!("Unexpected error: {}", e),
+            },
+            Ok(_) => panic!("Locked keys in a deadlock"),
+        }
+        a.set("k7", "v7").await.expect("Could not set value");
+        a.commit().await.expect("Could not commit transaction");
+    });
+}
+
+#[test]
+fn mock_resolve_lock_test() {
+    block_on(async {
+        let cluster = start();
+        let client = transaction::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+
+        // The expired lock of a crashed writer is rolled back by the reader running into it.
+        let crashed = cluster.prewrite("k1", "v1", Duration::from_millis(0));
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        assert_eq!(snapshot.get("k1").await.expect("Could not get value"), None);
+        assert_eq!(cluster.commit("k1", crashed), None);
+
+        // A live lock is waited for, and its writer can still commit.
+        let writer = cluster.prewrite("k4", "v4", Duration::from_secs(10));
+        let snapshot = client.snapshot().await.expect("Could not get snapshot");
+        let read = snapshot.get("k4");
+        let commit = async {
+            Delay::new(Duration::from_millis(200)).await;
+            cluster.commit("k4", writer)
+        };
+        let (value, committed) = future::join(read, commit).await;
+        assert!(committed.is_some());
+        assert_eq!(value.expect("Could not get value"), Some(Value::from("v4")));
+    });
+}
+
+#[test]
+fn mock_leader_change_test() {
+    block_on(async {
+        let cluster = start();
+        let first = cluster.store_ids()[0];
+        let second = cluster.add_store().expect("Could not add store");
+        let client = raw::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+        client.put("k1", "v1").await.expect("Could not put kv pair");
+        assert_eq!(cluster.request_count(second), 0);
+
+        // The former leader suggests the new one, which the request is resent to right away.
+        cluster.transfer_leader("k1", second);
+        let sent = cluster.request_count(first);
+        assert_eq!(
+            client.get("k1").await.expect("Could not get value"),
+            Some(Value::from("v1"))
+        );
+        assert_eq!(cluster.request_count(first), sent + 1);
+        assert_eq!(cluster.request_count(second), 1);
+
+        // Later requests go to the new leader only.
+        assert_eq!(
+            client.get("k1").await.expect("Could not get value"),
+            Some(Value::from("v1"))
+        );
+        assert_eq!(cluster.request_count(first), sent + 1);
+        assert_eq!(cluster.request_count(second), 2);
+    });
+}
+
+#[test]
+fn mock_store_down_test() {
+    block_on(async {
+        let cluster = start();
+        let first = cluster.store_ids()[0];
+        let second = cluster.add_store().expect("Could not add store");
+        let client = raw::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+        client.put("k1", "v1").await.expect("Could not put kv pair");
+
+        // The store of the leader goes down, and a peer on another store is elected.
+        cluster.set_store_down(first, true);
+        cluster.transfer_leader("k1", second);
+        let sent = cluster.request_count(first);
+        assert_eq!(
+            client.get("k1").await.expect("Could not get value"),
+            Some(Value::from("v1"))
+        );
+        // The request is retried until the store is considered down, then sent to the other one.
+        assert_eq!(cluster.request_count(first), sent + 3);
+        assert_eq!(cluster.request_count(second), 1);
+
+        client.put("k2", "v2").await.expect("Could not put kv pair");
+        assert_eq!(cluster.request_count(first), sent + 3);
+        assert_eq!(cluster.request_count(second), 2);
+    });
+}
+
+#[test]
+fn mock_tso_prefetch_test() {
+    block_on(async {
+        let cluster = start();
+        let client = transaction::Client::new(cluster.config().tso_prefetch(8))
+            .await
+            .expect("Could not connect to mock cluster");
+
+        let mut last = 0;
+        for _ in 0..50 {
+            let ts = client
+                .current_timestamp()
+                .await
+                .expect("Could not get timestamp")
+                .timestamp();
+            assert!(ts > last);
+            last = ts;
+        }
+        let timestamps = future::try_join_all((0..50).map(|_| client.current_timestamp()))
+            .await
+            .expect("Could not get timestamps");
+        let mut timestamps: Vec<_> = timestamps.into_iter().map(|ts| ts.timestamp()).collect();
+        timestamps.sort();
+        timestamps.dedup();
+        assert_eq!(timestamps.len(), 50);
+        assert!(timestamps[0] > last);
+        last = *timestamps.last().unwrap();
+
+        // Timestamps keep increasing across a change of the PD leader.
+        cluster.change_pd_leader();
+        for _ in 0..50 {
+            let ts = client
+                .current_timestamp()
+                .await
+                .expect("Could not get timestamp after PD leader change")
+                .timestamp();
+            assert!(ts > last);
+            last = ts;
+        }
+    });
+}
+
+#[test]
+fn mock_pd_failure_test() {
+    block_on(async {
+        let cluster = start();
+        let client = transaction::Client::new(cluster.config())
+            .await
+            .expect("Could not connect to mock cluster");
+        let last = client
+            .current_timestamp()
+            .await
+            .expect("Could not get timestamp")
+            .timestamp();
+
+        // Timestamp requests sent while PD is down are resent once it is back.
+        cluster.set_pd_down(true);
+        let recover = async {
+            Delay::new(Duration::from_millis(300)).await;
+            cluster.set_pd_down(false);
+        };
+        let (ts, ()) = future::join(client.current_timestamp(), recover).await;
+        let ts = ts
+            .expect("Could not get timestamp after PD came back")
+            .timestamp();
+        assert!(ts > last);
+
+        // The client reconnects when PD comes back with another leader.
+        cluster.set_pd_down(true);
+        let recover = async {
+            Delay::new(Duration::from_millis(300)).await;
+            cluster.change_pd_leader();
+            cluster.set_pd_down(false);
+        };
+        let (next, ()) = future::join(client.current_timestamp(), recover).await;
+        let next = next
+            .expect("Could not get timestamp after PD leader change")
+            .timestamp();
+        assert!(next > ts);
+    });
+}
+
+#[test]
+fn mock_sync_client_test() {
+    let cluster = start();
+    let client = raw::SyncClient::new(cluster.config()).expect("Could not connect to mock cluster");
+    client
+        .batch_put(pairs(0..9))
+        .expect("Could not put kv pairs");
+    assert_eq!(
+        client.get("k4").expect("Could not get value"),
+        Some(Value::from("v4"))
+    );
+    assert_eq!(
+        client
+            .scan(Key::from("k1")..Key::from("k8"), 10)
+            .expect("Could not scan"),
+        pairs(1..8)
+    );
+    client
+        .delete_range(Key::from("k0")..Key::from("k9"))
+        .expect("Could not delete range");
+
+    let client =
+        transaction::SyncClient::new(cluster.config()).expect("Could not connect to mock cluster");
+    let mut txn = client.begin().expect("Could not begin transaction");
+    for pair in pairs(0..9) {
+        txn.set(pair.key().clone(), pair.value().clone())
+            .expect("Could not set value");
+    }
+    txn.commit().expect("Could not commit transaction");
+
+    let txn = client.begin().expect("Could not begin transaction");
+    assert_eq!(
+        txn.get("k5").expect("Could not get value"),
+        Some(Value::from("v5"))
+    );
+    assert_eq!(
+        txn.scan(Key::from("k2")..Key::from("k7"), 3)
+            .expect("Could not scan"),
+        pairs(2..5)
+    );
+}
@@ -13,3 +13,5 @@
 
 #[cfg(feature = "integration-tests")]
 mod integration_tests;
+#[cfg(feature = "mock")]
+mod mock_tests;